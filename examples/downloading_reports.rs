@@ -47,8 +47,10 @@ async fn main() {
             .await
             .unwrap_or_else(|error| panic!("Failed to request {:?}\n{error:?}", report));
 
-        // Make sure it isn't empty
-        assert!(!data.is_empty(), "{:?} report is empty!", report);
+        // Make sure it's a valid report
+        report
+            .validate(&data)
+            .unwrap_or_else(|error| panic!("{:?} report is invalid\n{error:?}", report));
 
         // Save the file if requested
         if let Some(output) = &args.output {
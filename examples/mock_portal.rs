@@ -0,0 +1,29 @@
+use std::net::SocketAddr;
+
+use clap::Parser;
+use rapportage_downloader::{metrics, mock::MockPortal};
+
+#[derive(Parser)]
+struct Args {
+    #[arg(short, long, default_value = "127.0.0.1:8081")]
+    listen: SocketAddr,
+    #[arg(short, long, default_value = "test@example.com")]
+    mail: String,
+    #[arg(short, long, default_value = "test")]
+    password: String,
+}
+
+#[tokio::main]
+async fn main() {
+    // Parse arguments
+    let args = Args::parse();
+
+    // Serve the fixtures until ctrl-c is pressed
+    let portal = MockPortal::new(&args.mail, &args.password);
+    println!("Serving the mock portal at http://{}", args.listen);
+    metrics::serve(args.listen, portal.router(), async {
+        tokio::signal::ctrl_c().await.ok();
+    })
+    .await
+    .expect("Failed to serve the mock portal");
+}
@@ -0,0 +1,737 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use axum::{
+    body::{boxed, Bytes, Full, StreamBody},
+    extract::{Path, Query, State},
+    http::{header, HeaderValue, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+
+use crate::{
+    config::{self, Filters, ReportConfig, PER_METER_REPORT},
+    convert::Format,
+    ean::{self, Ean},
+    id::Id,
+    login::CookieStore,
+    metrics::Metrics,
+    report::{Polling, Report, TemporaryFile},
+    sink::{Content, Document, Sink},
+    summary::Failure,
+    usage,
+};
+
+/// The number of finished jobs that are kept, the oldest ones are removed first together with their files
+const FINISHED_JOBS: usize = 100;
+
+/// An error response, the message is returned as `{"error": message}`
+#[derive(Debug)]
+pub struct Error {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl Error {
+    fn new(status: StatusCode, message: impl Display) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    fn not_found(message: impl Display) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    fn bad_request(message: impl Display) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    /// The portal returned an error or something unexpected
+    fn portal(message: impl Display) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, message)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.status, self.message)
+    }
+}
+
+impl From<config::Error> for Error {
+    fn from(value: config::Error) -> Self {
+        Self::bad_request(value)
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            Json(serde_json::json!({ "error": self.message })),
+        )
+            .into_response()
+    }
+}
+
+/// The state of a download job
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for another download to finish
+    Queued,
+    Running,
+
+    /// The report can be retrieved at `/jobs/{id}/file`
+    Done,
+    Failed,
+
+    /// The server stopped before the report was downloaded
+    Cancelled,
+}
+
+/// The status of a download job, as it's returned by the job endpoints
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: u64,
+    pub state: JobState,
+    pub report: String,
+    pub ean: Option<String>,
+    pub meter_id: Option<u32>,
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+    pub format: String,
+
+    /// Whether the report is saved to the outputs as well
+    pub save: bool,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+
+    /// The size of the converted report
+    pub bytes: Option<u64>,
+
+    /// The url to retrieve the report from once it's done
+    pub file: Option<String>,
+    pub failure: Option<Failure>,
+}
+
+/// A job and the converted report once it's downloaded.
+/// The report is kept in a temporary file, which is removed once the job is.
+#[derive(Debug)]
+struct Job {
+    status: JobStatus,
+    file: Option<(String, Content)>,
+}
+
+/// The parameters of a download, as json body of `POST /reports/{kind}/download`.
+/// The reports per meter need an ean or id and download the last year unless the range is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadRequest {
+    pub ean: Option<String>,
+    pub id: Option<u32>,
+
+    /// The number of days before the end of the range
+    pub days: Option<u32>,
+    pub start: Option<NaiveDate>,
+    pub end: Option<NaiveDate>,
+
+    /// The format to convert the report to, the format of the outputs if it isn't set
+    pub format: Option<String>,
+
+    /// Save the report to the outputs as well
+    pub save: bool,
+}
+
+/// The date range of `GET /meters/{ean}/usage`, `to` is today if it isn't set
+#[derive(Debug, Clone, Deserialize)]
+pub struct UsageQuery {
+    pub from: NaiveDate,
+    pub to: Option<NaiveDate>,
+}
+
+/// A report type, as it's returned by `GET /reports`
+#[derive(Debug, Clone, Serialize)]
+struct ReportKind {
+    name: &'static str,
+
+    /// Whether the report belongs to a single meter and needs an ean or id
+    per_meter: bool,
+}
+
+/// A connection, as it's returned by `GET /meters`
+#[derive(Debug, Clone, Serialize)]
+struct Meter {
+    ean: Ean,
+    status: Option<String>,
+
+    /// The id, if it has been retrieved before
+    id: Option<u32>,
+}
+
+/// A measurement, as it's returned by `GET /meters/{ean}/usage`
+#[derive(Debug, Clone, Serialize)]
+struct Usage {
+    timestamp: String,
+    value: f64,
+    unit: String,
+
+    /// `consumption` or `feed_in`, a meter can have values for both at the same time
+    direction: String,
+}
+
+/// Settings for the service
+#[derive(Clone)]
+pub struct Options {
+    /// The connections that are returned by `GET /meters`
+    pub filters: Filters,
+    pub polling: Polling,
+
+    /// The format of downloads that don't request a format
+    pub format: Format,
+
+    /// The number of reports that are downloaded at the same time
+    pub concurrency: usize,
+
+    /// The token every request needs as `Authorization: Bearer {token}` header, if it's set
+    pub token: Option<String>,
+}
+
+impl std::fmt::Debug for Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the token
+        f.debug_struct("Options")
+            .field("filters", &self.filters)
+            .field("polling", &self.polling)
+            .field("format", &self.format)
+            .field("concurrency", &self.concurrency)
+            .field("token", &self.token.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// Everything the handlers share
+#[derive(Debug)]
+struct Inner {
+    cookie_store: CookieStore,
+    options: Options,
+    sink: Arc<dyn Sink>,
+    metrics: Metrics,
+    shutdown: CancellationToken,
+
+    /// Limits the number of reports that are downloaded at the same time
+    downloads: Semaphore,
+
+    /// Retrieving an id sets a filter cookie for the whole session, so only one id is retrieved at a time
+    id_lock: tokio::sync::Mutex<()>,
+    ids: Mutex<HashMap<String, Id>>,
+    jobs: Mutex<BTreeMap<u64, Job>>,
+    last_job: AtomicU64,
+}
+
+/// The downloader as a service, see [`router`] for the endpoints.
+/// Cloning is cheap, every clone shares the session and jobs.
+#[derive(Debug, Clone)]
+pub struct Api {
+    inner: Arc<Inner>,
+}
+
+/// Locks the mutex, a panic while it was locked doesn't leave the maps in an invalid state
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+impl Api {
+    /// Creates the service for a logged in session, the jobs stop when the shutdown is cancelled.
+    /// Reports are saved to the sink if a download requests it.
+    #[must_use]
+    pub fn new(
+        cookie_store: CookieStore,
+        options: Options,
+        sink: Arc<dyn Sink>,
+        metrics: Metrics,
+        shutdown: CancellationToken,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                cookie_store,
+                downloads: Semaphore::new(options.concurrency.max(1)),
+                options,
+                sink,
+                metrics,
+                shutdown,
+                id_lock: tokio::sync::Mutex::new(()),
+                ids: Mutex::new(HashMap::new()),
+                jobs: Mutex::new(BTreeMap::new()),
+                last_job: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Runs the request, and once more after logging in again if it failed because the session expired
+    async fn with_relogin<T, E, F>(&self, request: impl Fn() -> F) -> Result<T, E>
+    where
+        F: std::future::Future<Output = Result<T, E>>,
+    {
+        match request().await {
+            Ok(value) => Ok(value),
+            Err(e) => {
+                // Logging in again doesn't help if the session is still logged in, or the portal can't be reached
+                if !matches!(self.inner.cookie_store.is_logged_in().await, Ok(false)) {
+                    return Err(e);
+                }
+
+                // The error of the login isn't Send, so it isn't kept across the next request
+                let relogin = self.inner.cookie_store.redo_login().await.is_ok();
+                self.inner.metrics.login(true, relogin);
+                if relogin {
+                    request().await
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Retrieves the id of the meter and checks it by retrieving the ean of the id
+    async fn resolve_id(&self, ean: &Ean) -> Result<Id, Error> {
+        if let Some(id) = lock(&self.inner.ids).get(ean.value()) {
+            return Ok(*id);
+        }
+        let _lock = self.inner.id_lock.lock().await;
+
+        // Another request might have retrieved the id while this one waited
+        if let Some(id) = lock(&self.inner.ids).get(ean.value()) {
+            return Ok(*id);
+        }
+        let cookie_store = &self.inner.cookie_store;
+        let id = self
+            .with_relogin(|| async {
+                let id = Id::from_ean(cookie_store, ean).await.map_err(|e| {
+                    Error::not_found(format!("Failed to find the meter {ean}: {e}"))
+                })?;
+                let received_ean = Ean::from_id(cookie_store, id)
+                    .await
+                    .map_err(|e| Error::portal(format!("Failed to check the meter {ean}: {e}")))?;
+                if received_ean == *ean {
+                    Ok(id)
+                } else {
+                    Err(Error::portal(format!(
+                        "The id {id} belongs to {received_ean} instead of {ean}"
+                    )))
+                }
+            })
+            .await;
+        self.inner.metrics.id_attempt(id.is_ok());
+        let id = id?;
+        lock(&self.inner.ids).insert(ean.value().to_owned(), id);
+        Ok(id)
+    }
+
+    /// Downloads and validates the latest version of the report, at most `concurrency` at the same time.
+    /// The report is streamed to a temporary file instead of being kept in memory.
+    async fn download(&self, report: &Report) -> Result<(String, Content), Failure> {
+        let _permit = self.inner.downloads.acquire().await;
+        let cookie_store = &self.inner.cookie_store;
+        self.with_relogin(|| async {
+            let (file_name, file, size) = report
+                .download_latest_version_polling_to(
+                    cookie_store,
+                    &self.inner.options.polling,
+                    &std::env::temp_dir(),
+                    |_| {},
+                )
+                .await
+                .map_err(|e| Failure::download(&e))?;
+            report
+                .validate_file(file.path())
+                .map_err(|e| Failure::download(&e))?;
+            Ok((file_name, Content::file(file, size)))
+        })
+        .await
+    }
+
+    /// Updates the status of the job
+    fn update(&self, id: u64, update: impl FnOnce(&mut Job)) {
+        if let Some(job) = lock(&self.inner.jobs).get_mut(&id) {
+            update(job);
+        }
+    }
+
+    /// Downloads the report of the job, converts it and saves it to the outputs if that was requested
+    async fn run_job(&self, id: u64, report: Report, ean: Option<Ean>, format: Format, save: bool) {
+        self.update(id, |job| job.status.state = JobState::Running);
+        let meter_id = match report {
+            Report::EnergieVerbruikPerUur(meter_id, _, _) => Some(meter_id),
+            _ => None,
+        };
+        let result = async {
+            let (file_name, data) = self.download(&report).await?;
+            let document = Document {
+                report: report.clone(),
+                ean,
+                id: meter_id,
+                file_name,
+                data,
+                downloaded_at: Utc::now(),
+            };
+            if save {
+                self.inner
+                    .sink
+                    .save(&document)
+                    .await
+                    .map_err(|e| Failure::save(&e))?;
+            }
+            let file_name = document.output_name(format);
+            let data = document.encode(format).map_err(|e| Failure::save(&e))?;
+            let data = to_file(data, &file_name)
+                .await
+                .map_err(|e| Failure::save(&e.into()))?;
+            Ok::<_, Failure>((file_name, data))
+        };
+        let result = tokio::select! {
+            result = result => Some(result),
+            () = self.inner.shutdown.cancelled() => None,
+        };
+        let outcome = match &result {
+            Some(Ok(_)) => "written",
+            Some(Err(_)) => "failed",
+            None => "cancelled",
+        };
+        self.inner.metrics.report(report.name(), outcome);
+        self.update(id, |job| {
+            job.status.finished_at = Some(Utc::now());
+            match result {
+                Some(Ok((file_name, data))) => {
+                    job.status.state = JobState::Done;
+                    job.status.bytes = Some(data.len());
+                    job.status.file = Some(format!("/jobs/{id}/file"));
+                    job.file = Some((file_name, data));
+                }
+                Some(Err(failure)) => {
+                    tracing::warn!(job = id, error = %failure.message, "Failed to download the report");
+                    job.status.state = JobState::Failed;
+                    job.status.failure = Some(failure);
+                }
+                None => job.status.state = JobState::Cancelled,
+            }
+        });
+    }
+
+    /// Adds a job and removes the oldest finished jobs
+    fn add_job(&self, status: JobStatus) {
+        let mut jobs = lock(&self.inner.jobs);
+        let finished = jobs
+            .values()
+            .filter(|job| job.status.finished_at.is_some())
+            .count();
+        let expired = jobs
+            .iter()
+            .filter(|(_, job)| job.status.finished_at.is_some())
+            .map(|(id, _)| *id)
+            .take((finished + 1).saturating_sub(FINISHED_JOBS))
+            .collect::<Vec<_>>();
+        for id in expired {
+            jobs.remove(&id);
+        }
+        jobs.insert(status.id, Job { status, file: None });
+    }
+}
+
+/// Writes converted data to a temporary file, so finished jobs don't keep their reports in memory
+async fn to_file(data: Content, file_name: &str) -> Result<Content, std::io::Error> {
+    match data {
+        Content::Bytes(data) => {
+            let file = TemporaryFile::new(&std::env::temp_dir().join(file_name));
+            tokio::fs::write(file.path(), &data).await?;
+            Ok(Content::file(file, data.len() as u64))
+        }
+        file @ Content::File { .. } => Ok(file),
+    }
+}
+
+/// `GET /reports`
+async fn reports() -> Json<Vec<ReportKind>> {
+    let mut kinds = Report::GLOBAL
+        .map(|report| ReportKind {
+            name: report.name(),
+            per_meter: false,
+        })
+        .to_vec();
+    kinds.push(ReportKind {
+        name: PER_METER_REPORT,
+        per_meter: true,
+    });
+    Json(kinds)
+}
+
+/// `POST /reports/{kind}/download`, starts a job and returns its status
+async fn download(
+    State(api): State<Api>,
+    Path(kind): Path<String>,
+    body: Bytes,
+) -> Result<impl IntoResponse, Error> {
+    if kind != PER_METER_REPORT && Report::global(&kind).is_none() {
+        return Err(Error::not_found(format!("Unknown report {kind}")));
+    }
+    let request: DownloadRequest = if body.is_empty() {
+        DownloadRequest::default()
+    } else {
+        serde_json::from_slice(&body).map_err(Error::bad_request)?
+    };
+    let report_config = ReportConfig {
+        report: kind,
+        days: request.days,
+        start: request.start,
+        end: request.end,
+        schedule: None,
+    };
+    report_config.validate("request")?;
+    let format = request
+        .format
+        .as_deref()
+        .map_or(Ok(api.inner.options.format), str::parse)
+        .map_err(Error::bad_request)?;
+
+    // Reports per meter need the id of the meter
+    let ean = request.ean.map(Ean::from);
+    let meter_id = match (&ean, request.id) {
+        _ if !report_config.per_meter() => None,
+        (_, Some(id)) => Some(Id::from(id)),
+        (Some(ean), None) => Some(api.resolve_id(ean).await?),
+        (None, None) => {
+            return Err(Error::bad_request(format!(
+                "The report {} needs an ean or id",
+                report_config.report
+            )))
+        }
+    };
+    let today = chrono::Local::now().date_naive();
+    let report = report_config
+        .to_report(meter_id, today)
+        .ok_or_else(|| Error::not_found(format!("Unknown report {}", report_config.report)))?;
+
+    // Start the job
+    let id = api.inner.last_job.fetch_add(1, Ordering::Relaxed) + 1;
+    let (start, end) = report.date_range().unzip();
+    let status = JobStatus {
+        id,
+        state: JobState::Queued,
+        report: report.name().to_owned(),
+        ean: ean.as_ref().map(ToString::to_string),
+        meter_id: meter_id.map(u32::from),
+        start,
+        end,
+        format: format.to_string(),
+        save: request.save,
+        created_at: Utc::now(),
+        finished_at: None,
+        bytes: None,
+        file: None,
+        failure: None,
+    };
+    api.add_job(status.clone());
+    tokio::spawn({
+        let api = api.clone();
+        async move { api.run_job(id, report, ean, format, request.save).await }
+    });
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/jobs/{id}"))],
+        Json(status),
+    ))
+}
+
+/// `GET /jobs`
+async fn jobs(State(api): State<Api>) -> Json<Vec<JobStatus>> {
+    Json(
+        lock(&api.inner.jobs)
+            .values()
+            .map(|job| job.status.clone())
+            .collect(),
+    )
+}
+
+/// `GET /jobs/{id}`
+async fn job(State(api): State<Api>, Path(id): Path<u64>) -> Result<Json<JobStatus>, Error> {
+    lock(&api.inner.jobs)
+        .get(&id)
+        .map(|job| Json(job.status.clone()))
+        .ok_or_else(|| Error::not_found(format!("Unknown job {id}")))
+}
+
+/// `GET /jobs/{id}/file`, streams the report of a finished job
+async fn job_file(State(api): State<Api>, Path(id): Path<u64>) -> Result<Response, Error> {
+    let (file_name, data, format) = {
+        let jobs = lock(&api.inner.jobs);
+        let job = jobs
+            .get(&id)
+            .ok_or_else(|| Error::not_found(format!("Unknown job {id}")))?;
+        let Some((file_name, data)) = &job.file else {
+            return Err(Error::new(
+                StatusCode::CONFLICT,
+                format!("The job {id} is {:?}", job.status.state),
+            ));
+        };
+        let format: Format = job.status.format.parse().unwrap_or_default();
+        (file_name.clone(), data.clone(), format)
+    };
+
+    // The clone of the content keeps the file while it's opened, even if the job is removed in the meantime
+    let body = match &data {
+        Content::Bytes(data) => boxed(Full::from(data.clone())),
+        Content::File { file, .. } => {
+            let file = tokio::fs::File::open(file.path()).await.map_err(|e| {
+                Error::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to read the report of job {id}: {e}"),
+                )
+            })?;
+            boxed(StreamBody::new(ReaderStream::new(file)))
+        }
+    };
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(format.content_type()),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_str(&format!("attachment; filename=\"{file_name}\""))
+                    .unwrap_or(HeaderValue::from_static("attachment")),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// `GET /meters`, the connections that pass the filters
+async fn meters(State(api): State<Api>) -> Result<Json<Vec<Meter>>, Error> {
+    let (_, data) = api
+        .download(&Report::Aansluitinglijst)
+        .await
+        .map_err(|failure| Error::portal(failure.message))?;
+    let connections =
+        ean::read_connections(data.reader().map_err(Error::portal)?).map_err(Error::portal)?;
+    let ids = lock(&api.inner.ids);
+    Ok(Json(
+        connections
+            .into_iter()
+            .filter(|connection| {
+                api.inner
+                    .options
+                    .filters
+                    .matches(&connection.ean, connection.status.as_deref())
+            })
+            .map(|connection| Meter {
+                id: ids.get(connection.ean.value()).map(|id| u32::from(*id)),
+                ean: connection.ean,
+                status: connection.status,
+            })
+            .collect(),
+    ))
+}
+
+/// `GET /meters/{ean}/usage?from&to`, the hourly usage of the meter
+async fn usage(
+    State(api): State<Api>,
+    Path(ean): Path<String>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<Vec<Usage>>, Error> {
+    let to = query
+        .to
+        .unwrap_or_else(|| chrono::Local::now().date_naive());
+    if query.from > to {
+        return Err(Error::bad_request(format!(
+            "from {} is after to {to}",
+            query.from
+        )));
+    }
+    let id = api.resolve_id(&Ean::from(ean)).await?;
+    let report = Report::EnergieVerbruikPerUur(id, query.from, to);
+    let (_, data) = api
+        .download(&report)
+        .await
+        .map_err(|failure| Error::portal(failure.message))?;
+    let records = usage::read(data.reader().map_err(Error::portal)?).map_err(Error::portal)?;
+    Ok(Json(
+        records
+            .into_iter()
+            .map(|record| Usage {
+                timestamp: record.timestamp.to_rfc3339(),
+                value: record.value,
+                unit: record.unit.to_string(),
+                direction: record.direction.to_string(),
+            })
+            .collect(),
+    ))
+}
+
+/// Rejects requests without the token, if the service has one
+async fn authorize<B>(
+    State(api): State<Api>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, Error> {
+    if let Some(token) = &api.inner.options.token {
+        let authorized = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|received| {
+                // Compare every byte, so the time doesn't reveal how much of the token is right
+                received.len() == token.len()
+                    && received
+                        .bytes()
+                        .zip(token.bytes())
+                        .fold(0, |difference, (a, b)| difference | (a ^ b))
+                        == 0
+            });
+        if !authorized {
+            return Err(Error::new(
+                StatusCode::UNAUTHORIZED,
+                "A valid Authorization: Bearer header is required",
+            ));
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// Returns the routes of the service, which need the token of the options if it's set:
+/// - `GET /reports`: the report types
+/// - `POST /reports/{kind}/download`: starts a download job with a [`DownloadRequest`] body
+/// - `GET /jobs` and `GET /jobs/{id}`: the status of the jobs
+/// - `GET /jobs/{id}/file`: the report of a finished job
+/// - `GET /meters`: the connections that pass the filters
+/// - `GET /meters/{ean}/usage?from&to`: the hourly usage of a meter
+pub fn router(api: Api) -> Router {
+    Router::new()
+        .route("/reports", get(reports))
+        .route("/reports/:kind/download", post(download))
+        .route("/jobs", get(jobs))
+        .route("/jobs/:id", get(job))
+        .route("/jobs/:id/file", get(job_file))
+        .route("/meters", get(meters))
+        .route("/meters/:ean/usage", get(usage))
+        .route_layer(middleware::from_fn_with_state(api.clone(), authorize))
+        .with_state(api)
+}
@@ -0,0 +1,682 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{Datelike, Days, NaiveDate};
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    convert::Format,
+    ean::Ean,
+    id::Id,
+    login,
+    report::Report,
+    schedule::Schedule,
+    sink::{self, Auth, HttpOptions, S3Options, Template},
+};
+
+/// The name of the only report that's downloaded per meter
+pub const PER_METER_REPORT: &str = "energie-verbruik-per-uur";
+
+/// The largest number of days of a date range, a hundred years
+pub const MAX_DAYS: u32 = 36_600;
+
+/// The largest maximum age of the last successful run in seconds, the hundred years of [`MAX_DAYS`]
+pub const MAX_READY_MAX_AGE: u64 = 36_600 * 24 * 60 * 60;
+
+/// Errors that can occur while reading a config file
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Io(#[from] std::io::Error),
+
+    /// The file isn't valid toml or a value has the wrong type
+    Toml(#[from] toml::de::Error),
+
+    /// The value of the key isn't valid
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The messages point at the key, so they're shown as is
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Toml(e) => write!(f, "{e}"),
+            Self::Invalid { key, message } => write!(f, "Invalid value for {key}: {message}"),
+        }
+    }
+}
+
+/// Returns an [`Error::Invalid`] for the key
+fn invalid(key: impl Into<String>, message: impl Into<String>) -> Error {
+    Error::Invalid {
+        key: key.into(),
+        message: message.into(),
+    }
+}
+
+/// Deserializes a string with [`FromStr`]
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Deserializes an optional string with [`FromStr`]
+fn from_str_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| value.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// A job definition, like:
+///
+/// ```toml
+/// concurrency = 2
+/// schedule = "0 2 * * *"
+///
+/// [[accounts]]
+/// mail = "energie@example.com"
+/// password_env = "DB_ENERGIE_PASSWORD"
+/// customers = [50]
+///
+/// [[reports]]
+/// report = "energie-verbruik-per-uur"
+/// days = 365
+///
+/// [[reports]]
+/// report = "aansluitinglijst"
+///
+/// [filters]
+/// exclude_eans = ["871687120000000001"]
+///
+/// [[reports]]
+/// report = "datakwaliteit"
+/// schedule = "@monthly"
+///
+/// [sinks]
+/// outputs = ["reports", "sqlite://usage.sqlite"]
+/// format = "csv"
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The accounts to log in with, the reports of every account are downloaded
+    pub accounts: Vec<Account>,
+
+    /// The reports to download
+    pub reports: Vec<ReportConfig>,
+
+    /// The meters to download reports for
+    pub filters: Filters,
+
+    /// Where to save the reports
+    pub sinks: Sinks,
+
+    /// The number of meters whose reports are downloaded at the same time
+    pub concurrency: usize,
+
+    /// When to download the reports that don't have their own schedule in daemon mode
+    #[serde(deserialize_with = "from_str_option")]
+    pub schedule: Option<Schedule>,
+
+    /// The file that contains the time of the last run of every schedule
+    pub state_file: PathBuf,
+
+    /// The json file the summary of the last run is written to
+    pub summary_file: PathBuf,
+
+    /// The maximum number of seconds to wait for the portal to generate a report
+    pub report_deadline: u64,
+
+    /// The maximum number of seconds since the last successful run before the daemon isn't ready
+    pub ready_max_age: u64,
+
+    /// The address of the portal, only changed to test against a mock of the portal
+    pub portal_url: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            accounts: Vec::new(),
+            reports: vec![ReportConfig {
+                report: PER_METER_REPORT.to_owned(),
+                days: None,
+                start: None,
+                end: None,
+                schedule: None,
+            }],
+            filters: Filters::default(),
+            sinks: Sinks::default(),
+            concurrency: 1,
+            schedule: None,
+            state_file: PathBuf::from("rapportage-state.json"),
+            summary_file: PathBuf::from("rapportage-summary.json"),
+            report_deadline: 600,
+            ready_max_age: 25 * 60 * 60,
+            portal_url: login::DEFAULT_BASE_URL.to_owned(),
+        }
+    }
+}
+
+/// The credentials of an account at DB Energie
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Account {
+    pub mail: String,
+
+    /// The password, it's better to use `password_env` in files that are checked in
+    pub password: Option<String>,
+
+    /// The environment variable that contains the password
+    pub password_env: Option<String>,
+
+    /// The customers whose reports are downloaded
+    #[serde(default = "default_customers")]
+    pub customers: Vec<u32>,
+}
+
+fn default_customers() -> Vec<u32> {
+    vec![50]
+}
+
+impl std::fmt::Debug for Account {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the password
+        f.debug_struct("Account")
+            .field("mail", &self.mail)
+            .field("password_env", &self.password_env)
+            .field("customers", &self.customers)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Account {
+    /// Creates an account with a password for the default customer
+    #[must_use]
+    pub fn new(mail: String, password: String) -> Self {
+        Self {
+            mail,
+            password: Some(password),
+            password_env: None,
+            customers: default_customers(),
+        }
+    }
+
+    /// Returns the password or the value of the password environment variable
+    #[must_use]
+    pub fn password(&self) -> Option<String> {
+        self.password.clone().or_else(|| {
+            self.password_env
+                .as_ref()
+                .and_then(|variable| std::env::var(variable).ok())
+        })
+    }
+}
+
+/// A report to download, reports with a date range download the last year unless the range is set
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReportConfig {
+    /// The name of the report, like `energie-verbruik-per-uur` or `aansluitinglijst`
+    pub report: String,
+
+    /// The number of days before the end of the range
+    pub days: Option<u32>,
+
+    /// The first day of the range
+    pub start: Option<NaiveDate>,
+
+    /// The last day of the range, today if it isn't set
+    pub end: Option<NaiveDate>,
+
+    /// When to download the report in daemon mode, instead of the schedule of the config
+    #[serde(default, deserialize_with = "from_str_option")]
+    pub schedule: Option<Schedule>,
+}
+
+impl ReportConfig {
+    /// Checks whether the report is downloaded for every meter
+    #[must_use]
+    pub fn per_meter(&self) -> bool {
+        self.report == PER_METER_REPORT
+    }
+
+    /// Returns the first and last day of the report.
+    /// A range that would start before the first representable date starts at that date.
+    #[must_use]
+    pub fn date_range(&self, today: NaiveDate) -> (NaiveDate, NaiveDate) {
+        let end = self.end.unwrap_or(today);
+        let start = match (self.start, self.days) {
+            (Some(start), _) => Some(start),
+            (None, Some(days)) => end.checked_sub_days(Days::new(u64::from(days))),
+            (None, None) => end
+                .with_year(end.year() - 1)
+                .or_else(|| end.checked_sub_days(Days::new(365))),
+        };
+        (start.unwrap_or(NaiveDate::MIN), end)
+    }
+
+    /// Returns the report for the meter, or the report for every meter if it doesn't need a meter
+    #[must_use]
+    pub fn to_report(&self, id: Option<Id>, today: NaiveDate) -> Option<Report> {
+        match id {
+            Some(id) if self.per_meter() => {
+                let (start, end) = self.date_range(today);
+                Some(Report::EnergieVerbruikPerUur(id, start, end))
+            }
+            _ => Report::global(&self.report),
+        }
+    }
+
+    /// Checks the report and its date range, the key is used in the error
+    ///
+    /// # Errors
+    /// Returns [`Error::Invalid`] if the report is unknown or the date range is invalid
+    pub fn validate(&self, key: &str) -> Result<(), Error> {
+        if !self.per_meter() {
+            if Report::global(&self.report).is_none() {
+                let mut names = Report::GLOBAL.map(|report| report.name()).to_vec();
+                names.push(PER_METER_REPORT);
+                return Err(invalid(
+                    format!("{key}.report"),
+                    format!(
+                        "Unknown report {}, expected one of {}",
+                        self.report,
+                        names.join(", ")
+                    ),
+                ));
+            }
+            for (name, set) in [
+                ("days", self.days.is_some()),
+                ("start", self.start.is_some()),
+                ("end", self.end.is_some()),
+            ] {
+                if set {
+                    return Err(invalid(
+                        format!("{key}.{name}"),
+                        format!("The report {} doesn't have a date range", self.report),
+                    ));
+                }
+            }
+        }
+        if self.start.is_some() && self.days.is_some() {
+            return Err(invalid(
+                format!("{key}.days"),
+                "Only one of start and days can be set",
+            ));
+        }
+        if let Some(days) = self.days.filter(|days| *days > MAX_DAYS) {
+            return Err(invalid(
+                format!("{key}.days"),
+                format!("{days} days is more than the maximum of {MAX_DAYS}"),
+            ));
+        }
+        if let (Some(start), Some(end)) = (self.start, self.end) {
+            if start > end {
+                return Err(invalid(
+                    format!("{key}.start"),
+                    format!("The start {start} is after the end {end}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Which meters reports are downloaded for
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Filters {
+    /// Only download reports for these eans, if there are any
+    pub eans: Vec<String>,
+
+    /// Never download reports for these eans
+    pub exclude_eans: Vec<String>,
+
+    /// Only download reports for connections with one of these statuses
+    pub statuses: Vec<String>,
+}
+
+impl Default for Filters {
+    fn default() -> Self {
+        Self {
+            eans: Vec::new(),
+            exclude_eans: Vec::new(),
+            statuses: vec!["Actief".to_owned()],
+        }
+    }
+}
+
+impl Filters {
+    /// Checks whether reports should be downloaded for the connection, the status is only checked if it's known
+    #[must_use]
+    pub fn matches(&self, ean: &Ean, status: Option<&str>) -> bool {
+        let ean = ean.value().trim();
+        let status = match status {
+            Some(status) => {
+                self.statuses.is_empty()
+                    || self
+                        .statuses
+                        .iter()
+                        .any(|included| included.eq_ignore_ascii_case(status.trim()))
+            }
+            None => true,
+        };
+        status
+            && (self.eans.is_empty() || self.eans.iter().any(|included| included == ean))
+            && !self.exclude_eans.iter().any(|excluded| excluded == ean)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for (name, eans) in [("eans", &self.eans), ("exclude_eans", &self.exclude_eans)] {
+            for (index, ean) in eans.iter().enumerate() {
+                if ean.len() != 18 || !ean.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(invalid(
+                        format!("filters.{name}[{index}]"),
+                        format!("{ean} isn't an ean code of 18 digits"),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where and how reports are saved
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sinks {
+    /// The outputs, see [`sink::from_output`]
+    pub outputs: Vec<String>,
+
+    #[serde(deserialize_with = "from_str")]
+    pub format: Format,
+
+    /// The path of saved files in directories and object storage
+    #[serde(deserialize_with = "from_str_option")]
+    pub template: Option<Template>,
+
+    /// Keep a manifest in directories and skip unchanged reports
+    pub manifest: bool,
+
+    /// Settings for http(s) outputs
+    pub upload: Upload,
+
+    /// Settings for s3 outputs, the credentials are read from the environment
+    pub s3: S3,
+}
+
+impl Default for Sinks {
+    fn default() -> Self {
+        Self {
+            outputs: Vec::new(),
+            format: Format::default(),
+            template: None,
+            manifest: true,
+            upload: Upload::default(),
+            s3: S3::default(),
+        }
+    }
+}
+
+impl Sinks {
+    /// Returns the settings for the sinks, the s3 credentials are left empty
+    #[must_use]
+    pub fn options(&self) -> sink::Options {
+        sink::Options {
+            format: self.format,
+            http: self.upload.options(),
+            s3: self.s3.options(),
+            template: self.template.clone(),
+            manifest: self.manifest,
+        }
+    }
+}
+
+/// Settings for http(s) outputs
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Upload {
+    /// The environment variable that contains the bearer token
+    pub token_env: Option<String>,
+
+    /// The username for basic authentication
+    pub user: Option<String>,
+
+    /// The environment variable that contains the password for basic authentication
+    pub password_env: Option<String>,
+
+    /// The name of the form field that contains the report
+    pub file_field: String,
+
+    /// Extra form fields that are sent with every report
+    pub form_fields: BTreeMap<String, String>,
+
+    /// The number of times a failed upload is retried
+    pub retries: u32,
+}
+
+impl Default for Upload {
+    fn default() -> Self {
+        let options = HttpOptions::default();
+        Self {
+            token_env: None,
+            user: None,
+            password_env: None,
+            file_field: options.field_name,
+            form_fields: BTreeMap::new(),
+            retries: options.retries,
+        }
+    }
+}
+
+impl Upload {
+    /// Returns the settings for http(s) outputs with the credentials from the environment
+    #[must_use]
+    pub fn options(&self) -> HttpOptions {
+        let env = |variable: &Option<String>| {
+            variable
+                .as_ref()
+                .and_then(|variable| std::env::var(variable).ok())
+                .filter(|value| !value.is_empty())
+        };
+        let auth = match (env(&self.token_env), &self.user) {
+            (Some(token), _) => Some(Auth::Bearer(token)),
+            (None, Some(username)) => Some(Auth::Basic {
+                username: username.clone(),
+                password: env(&self.password_env),
+            }),
+            (None, None) => None,
+        };
+        HttpOptions {
+            auth,
+            field_name: self.file_field.clone(),
+            fields: self.form_fields.clone().into_iter().collect(),
+            retries: self.retries,
+            ..HttpOptions::default()
+        }
+    }
+}
+
+/// Settings for s3 outputs
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3 {
+    pub endpoint: String,
+    pub region: String,
+
+    /// Put the bucket in the path instead of the host name
+    pub path_style: bool,
+
+    /// Reports larger than this number of MiB are uploaded in parts
+    pub part_size: usize,
+}
+
+impl Default for S3 {
+    fn default() -> Self {
+        let options = S3Options::default();
+        Self {
+            endpoint: options.endpoint,
+            region: options.region,
+            path_style: options.path_style,
+            part_size: options.part_size / 1024 / 1024,
+        }
+    }
+}
+
+impl S3 {
+    /// Returns the settings for s3 outputs without credentials
+    #[must_use]
+    pub fn options(&self) -> S3Options {
+        S3Options {
+            endpoint: self.endpoint.clone(),
+            region: self.region.clone(),
+            path_style: self.path_style,
+            part_size: self.part_size * 1024 * 1024,
+            ..S3Options::default()
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    /// Parses the toml, [`Config::validate`] checks the values once the arguments are merged into them
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+impl Config {
+    /// Groups the reports by their schedule, for daemon mode
+    ///
+    /// # Errors
+    /// Returns [`Error::Invalid`] if a report doesn't have a schedule and there's no default schedule
+    pub fn jobs(&self) -> Result<Vec<(Schedule, Vec<ReportConfig>)>, Error> {
+        let mut jobs: Vec<(Schedule, Vec<ReportConfig>)> = Vec::new();
+        for (index, report) in self.reports.iter().enumerate() {
+            let schedule = report
+                .schedule
+                .as_ref()
+                .or(self.schedule.as_ref())
+                .ok_or_else(|| {
+                    invalid(
+                        format!("reports[{index}].schedule"),
+                        "Every report needs a schedule in daemon mode, set it or the schedule of the config",
+                    )
+                })?;
+            match jobs.iter_mut().find(|(existing, _)| existing == schedule) {
+                Some((_, reports)) => reports.push(report.clone()),
+                None => jobs.push((schedule.clone(), vec![report.clone()])),
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Reads the config file, without validating its values
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be read or isn't a valid config
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Checks the values that can't be checked while parsing, the error contains the key of the invalid value
+    ///
+    /// # Errors
+    /// Returns [`Error::Invalid`] for the first invalid value
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.accounts.is_empty() {
+            return Err(invalid("accounts", "At least one account is required"));
+        }
+        for (index, account) in self.accounts.iter().enumerate() {
+            let key = format!("accounts[{index}]");
+            if account.mail.trim().is_empty() {
+                return Err(invalid(format!("{key}.mail"), "The mail can't be empty"));
+            }
+            if account.password.is_some() && account.password_env.is_some() {
+                return Err(invalid(
+                    format!("{key}.password_env"),
+                    "Only one of password and password_env can be set",
+                ));
+            }
+            if account.password().is_none() {
+                return Err(match &account.password_env {
+                    Some(variable) => invalid(
+                        format!("{key}.password_env"),
+                        format!("The environment variable {variable} isn't set"),
+                    ),
+                    None => invalid(
+                        format!("{key}.password"),
+                        "A password or password_env is required",
+                    ),
+                });
+            }
+            if account.customers.is_empty() {
+                return Err(invalid(
+                    format!("{key}.customers"),
+                    "At least one customer is required",
+                ));
+            }
+        }
+        if self.reports.is_empty() {
+            return Err(invalid("reports", "At least one report is required"));
+        }
+        for (index, report) in self.reports.iter().enumerate() {
+            report.validate(&format!("reports[{index}]"))?;
+        }
+        self.filters.validate()?;
+        if self.sinks.outputs.is_empty() {
+            return Err(invalid("sinks.outputs", "At least one output is required"));
+        }
+        if self.concurrency == 0 {
+            return Err(invalid("concurrency", "The concurrency must be at least 1"));
+        }
+        if self.ready_max_age == 0 {
+            return Err(invalid(
+                "ready_max_age",
+                "The maximum age must be at least 1 second",
+            ));
+        }
+        if self.ready_max_age > MAX_READY_MAX_AGE {
+            return Err(invalid(
+                "ready_max_age",
+                format!("The maximum age can't be more than {MAX_READY_MAX_AGE} seconds"),
+            ));
+        }
+        if self.report_deadline == 0 {
+            return Err(invalid(
+                "report_deadline",
+                "The deadline must be at least 1 second",
+            ));
+        }
+        match url::Url::parse(&self.portal_url) {
+            Ok(url) if ["http", "https"].contains(&url.scheme()) => {}
+            _ => {
+                return Err(invalid(
+                    "portal_url",
+                    format!("{} isn't a http or https url", self.portal_url),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
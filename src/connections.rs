@@ -0,0 +1,394 @@
+use std::{fmt::Display, num::ParseIntError, str::FromStr, string::FromUtf8Error};
+
+use scraper::error::SelectorErrorKind;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    ean::Ean,
+    id::Id,
+    login::CookieStore,
+    request::{self, ReportRequest},
+};
+
+/// The page of the connection list, it shows the connections that match the `PersonalFilter` cookie
+const LIST_PATH: &str = "/Connections/List/Index";
+
+/// Errors that can occur while searching the connections
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Request(#[from] reqwest::Error),
+    ValueMissing(&'static str),
+    Utf8(#[from] FromUtf8Error),
+    Selector(#[from] SelectorErrorKind<'static>),
+    UrlParse(#[from] url::ParseError),
+    ParseInt(#[from] ParseIntError),
+    Json(#[from] serde_json::Error),
+    InvalidHeaderValue(#[from] reqwest::header::InvalidHeaderValue),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// The direction the connection list is sorted in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderDirection {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// The filter of the connection list, the portal reads it from the url encoded json in the `PersonalFilter` cookie.
+/// An id of 0 or an empty list or string doesn't filter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PersonalFilter {
+    pub main_portal_id: u32,
+    pub portal_id: u32,
+    pub product_id: Vec<u32>,
+    pub status_id: Vec<u32>,
+    pub provider_id: u32,
+    pub grid_id: u32,
+    pub meterreadingcompany_id: u32,
+    pub customer_id: Vec<u32>,
+    pub department_id: Vec<u32>,
+    pub gvkv_id: u32,
+    pub monitoring_types_id: u32,
+    pub characteristic_id: u32,
+    pub consumption_category_id: u32,
+    pub consumption_type_id: Vec<u32>,
+    pub costplace_id: u32,
+    pub energytaxationcluster_id: u32,
+    pub classification_id: u32,
+    pub label_id: u32,
+    #[serde(rename = "ConnectionTypeId")]
+    pub connection_type_id: u32,
+    pub meter_number: String,
+    pub ean_search: String,
+    pub meter_deleted: bool,
+    #[serde(rename = "ListMap")]
+    pub list_map: bool,
+    pub page_size: u32,
+
+    /// The page to show, starting at 1
+    pub page_number: u32,
+    pub order_by: String,
+    pub order_direction: OrderDirection,
+}
+
+impl PersonalFilter {
+    /// Returns the filter that shows the first page of the electricity connections of the customers
+    #[must_use]
+    pub fn new(customers: &[u32]) -> Self {
+        Self {
+            main_portal_id: 1,
+            portal_id: 6,
+            product_id: vec![1],
+            status_id: Vec::new(),
+            provider_id: 0,
+            grid_id: 0,
+            meterreadingcompany_id: 0,
+            customer_id: customers.to_vec(),
+            department_id: Vec::new(),
+            gvkv_id: 0,
+            monitoring_types_id: 0,
+            characteristic_id: 0,
+            consumption_category_id: 0,
+            consumption_type_id: Vec::new(),
+            costplace_id: 0,
+            energytaxationcluster_id: 0,
+            classification_id: 0,
+            label_id: 0,
+            connection_type_id: 0,
+            meter_number: String::new(),
+            ean_search: String::new(),
+            meter_deleted: false,
+            list_map: false,
+            page_size: 15,
+            page_number: 1,
+            order_by: String::new(),
+            order_direction: OrderDirection::Asc,
+        }
+    }
+
+    /// Only shows the connections whose ean contains the search term
+    #[must_use]
+    pub fn with_ean_search(mut self, search: &str) -> Self {
+        search.clone_into(&mut self.ean_search);
+        self
+    }
+
+    /// Only shows the connections with one of the statuses
+    #[must_use]
+    pub fn with_statuses(mut self, statuses: Vec<u32>) -> Self {
+        self.status_id = statuses;
+        self
+    }
+
+    /// Only shows the connections of one of the departments
+    #[must_use]
+    pub fn with_departments(mut self, departments: Vec<u32>) -> Self {
+        self.department_id = departments;
+        self
+    }
+
+    /// Only shows the connections whose meter number contains the search term
+    #[must_use]
+    pub fn with_meter_number(mut self, search: &str) -> Self {
+        search.clone_into(&mut self.meter_number);
+        self
+    }
+
+    /// Only shows the connections with the label
+    #[must_use]
+    pub const fn with_label(mut self, label_id: u32) -> Self {
+        self.label_id = label_id;
+        self
+    }
+
+    /// Only shows the connections with the classification
+    #[must_use]
+    pub const fn with_classification(mut self, classification_id: u32) -> Self {
+        self.classification_id = classification_id;
+        self
+    }
+
+    /// Only shows the connections of the cost place
+    #[must_use]
+    pub const fn with_costplace(mut self, costplace_id: u32) -> Self {
+        self.costplace_id = costplace_id;
+        self
+    }
+
+    /// Only shows the connections in the energy taxation cluster
+    #[must_use]
+    pub const fn with_energytaxationcluster(mut self, energytaxationcluster_id: u32) -> Self {
+        self.energytaxationcluster_id = energytaxationcluster_id;
+        self
+    }
+
+    /// Only shows the connections of the type
+    #[must_use]
+    pub const fn with_connection_type(mut self, connection_type_id: u32) -> Self {
+        self.connection_type_id = connection_type_id;
+        self
+    }
+
+    /// Only shows the connections in the grid
+    #[must_use]
+    pub const fn with_grid(mut self, grid_id: u32) -> Self {
+        self.grid_id = grid_id;
+        self
+    }
+
+    /// Only shows the connections of the provider
+    #[must_use]
+    pub const fn with_provider(mut self, provider_id: u32) -> Self {
+        self.provider_id = provider_id;
+        self
+    }
+
+    /// Also shows the connections whose meter is deleted
+    #[must_use]
+    pub const fn with_deleted(mut self, deleted: bool) -> Self {
+        self.meter_deleted = deleted;
+        self
+    }
+
+    /// Shows the page with the number, of the given size
+    #[must_use]
+    pub const fn with_page(mut self, size: u32, number: u32) -> Self {
+        self.page_size = size;
+        self.page_number = number;
+        self
+    }
+
+    /// Sorts the connections by the column
+    #[must_use]
+    pub fn with_order(mut self, by: &str, direction: OrderDirection) -> Self {
+        by.clone_into(&mut self.order_by);
+        self.order_direction = direction;
+        self
+    }
+
+    /// Returns the value of the `PersonalFilter` cookie
+    ///
+    /// # Errors
+    /// Returns an error if the filter couldn't be serialized
+    pub fn encode(&self) -> Result<String, serde_json::Error> {
+        Ok(request::encode_cookie(&serde_json::to_string(self)?))
+    }
+}
+
+/// A row of the connection list
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Connection {
+    pub id: Id,
+    pub ean: Ean,
+
+    /// The text of the other cells of the row, in the order the portal shows them
+    pub cells: Vec<String>,
+}
+
+/// The connection list of the portal
+#[derive(Debug, Clone, Copy)]
+pub struct Connections<'a> {
+    cookie_store: &'a CookieStore,
+}
+
+impl<'a> Connections<'a> {
+    #[must_use]
+    pub const fn new(cookie_store: &'a CookieStore) -> Self {
+        Self { cookie_store }
+    }
+
+    /// Returns the connections on the page of the list that the filter selects
+    ///
+    /// # Errors
+    /// - If the filter couldn't be encoded
+    /// - If the request failed
+    pub async fn search(&self, filter: &PersonalFilter) -> Result<Vec<Connection>, Error> {
+        Ok(self.search_page(filter).await?.1)
+    }
+
+    /// Returns the number of rows on the page of the list and the connections that could be read from them
+    async fn search_page(
+        &self,
+        filter: &PersonalFilter,
+    ) -> Result<(usize, Vec<Connection>), Error> {
+        // The list only reads the filter from the cookie
+        let cookie_store = self.cookie_store;
+        cookie_store.add_cookie_str(
+            &format!("PersonalFilter={}", filter.encode()?),
+            &Url::from_str(&cookie_store.url(LIST_PATH))?,
+        );
+
+        // Download the page for the filter
+        let content = String::from_utf8(
+            cookie_store
+                .send(
+                    cookie_store
+                        .client()
+                        .get(cookie_store.url(LIST_PATH))
+                        .header("request", ReportRequest::Flag(false).encode()?),
+                )
+                .await?
+                .bytes()
+                .await?
+                .to_vec(),
+        )?;
+        parse_rows(&content)
+    }
+
+    /// Returns the connections on every page of the list that the filter selects, starting at its page
+    ///
+    /// # Errors
+    /// Returns an error if searching one of the pages failed
+    pub async fn search_all(&self, filter: &PersonalFilter) -> Result<Vec<Connection>, Error> {
+        let mut filter = filter.clone();
+        let mut connections = Vec::<Connection>::new();
+        loop {
+            let (rows, page) = self.search_page(&filter).await?;
+            let full = rows >= filter.page_size as usize;
+
+            // Stop when the portal shows a page again, instead of the next one
+            let known = connections.len();
+            for connection in page {
+                if !connections.iter().any(|known| known.id == connection.id) {
+                    connections.push(connection);
+                }
+            }
+            if !full || filter.page_size == 0 || connections.len() == known {
+                return Ok(connections);
+            }
+            filter.page_number += 1;
+        }
+    }
+}
+
+/// Reads the number of rows and the connections from the html of the connection list,
+/// rows without an ean or id are skipped
+fn parse_rows(content: &str) -> Result<(usize, Vec<Connection>), Error> {
+    let page = scraper::Html::parse_document(content);
+    let row_selector = scraper::Selector::parse("a.list-row-visible")?;
+    let cell_selector = scraper::Selector::parse(".row-cell")?;
+    let ean_selector = scraper::Selector::parse(".row-cell.width-140")?;
+
+    let rows = page.select(&row_selector).collect::<Vec<_>>();
+    let connections = rows
+        .iter()
+        .filter_map(|row| match parse_row(*row, &cell_selector, &ean_selector) {
+            Ok(connection) => Some(connection),
+            Err(e) => {
+                tracing::warn!(row = %row.html(), error = %e, "Skipped a connection");
+                None
+            }
+        })
+        .collect();
+    Ok((rows.len(), connections))
+}
+
+/// Reads the connection from a row of the connection list
+fn parse_row(
+    row: scraper::ElementRef,
+    cell_selector: &scraper::Selector,
+    ean_selector: &scraper::Selector,
+) -> Result<Connection, Error> {
+    let id = row
+        .attr("href")
+        .ok_or(Error::ValueMissing("Connection doesn't contain a link"))?
+        .rsplit('/')
+        .next()
+        .ok_or(Error::ValueMissing("Connection url doesn't contain an id"))?
+        .parse::<u32>()?;
+    let ean_cell = row
+        .select(ean_selector)
+        .next()
+        .ok_or(Error::ValueMissing("Connection doesn't contain an ean"))?;
+    let ean = ean_cell.text().collect::<String>().trim().to_owned();
+    let cells = row
+        .select(cell_selector)
+        .filter(|cell| cell.id() != ean_cell.id())
+        .map(|cell| cell.text().collect::<String>().trim().to_owned())
+        .collect();
+    Ok(Connection {
+        id: Id::from(id),
+        ean: Ean::from(ean),
+        cells,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_rows_are_skipped() {
+        let content = "<html><body><div class=\"list\">\
+            <a class=\"list-row-visible\" href=\"/Connections/Edit/Index/1001\">\
+            <div class=\"row-cell width-140\">871687120000000001</div><div class=\"row-cell width-100\">Actief</div></a>\
+            <a class=\"list-row-visible\" href=\"/Connections/Edit/Index/\">\
+            <div class=\"row-cell width-140\">871687120000000002</div></a>\
+            <a class=\"list-row-visible\" href=\"/Connections/Edit/Index/1003\">\
+            <div class=\"row-cell width-100\">Actief</div></a>\
+            <a class=\"list-row-visible\">\
+            <div class=\"row-cell width-140\">871687120000000004</div></a>\
+            <a class=\"list-row-visible\" href=\"/Connections/Edit/Index/1005\">\
+            <div class=\"row-cell width-140\">871687120000000005</div></a>\
+            </div></body></html>";
+        let (rows, connections) = parse_rows(content).expect("Failed to parse the rows");
+        assert_eq!(rows, 5);
+        assert_eq!(
+            connections
+                .iter()
+                .map(|connection| (u32::from(connection.id), connection.ean.value()))
+                .collect::<Vec<_>>(),
+            [(1001, "871687120000000001"), (1005, "871687120000000005")]
+        );
+        assert_eq!(connections[0].cells, ["Actief"]);
+    }
+}
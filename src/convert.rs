@@ -0,0 +1,826 @@
+use std::{
+    fmt::Display,
+    io::{Cursor, Read, Seek},
+    str::FromStr,
+    sync::Arc,
+};
+
+use calamine::{DataType, Reader as _, Xlsx};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+use chrono_tz::Tz;
+use parquet::{
+    basic::{LogicalType, Repetition, Type as PhysicalType},
+    data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::types::Type,
+};
+
+use crate::{report::Report, usage};
+
+/// Errors that can occur while converting a report
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Io(#[from] std::io::Error),
+    Xlsx(#[from] calamine::XlsxError),
+    Usage(#[from] usage::Error),
+    Csv(#[from] csv::Error),
+    Json(#[from] serde_json::Error),
+    Parquet(#[from] parquet::errors::ParquetError),
+    MissingWorksheet,
+    UnknownFormat(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// The available output formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// The workbook as it's returned by the portal
+    #[default]
+    Xlsx,
+
+    /// Comma separated values with a header row
+    Csv,
+
+    /// A json object per row
+    Jsonl,
+
+    /// An Apache Parquet file
+    Parquet,
+}
+
+impl Format {
+    /// Returns the file extension of the format
+    #[must_use]
+    pub const fn extension(&self) -> &'static str {
+        match self {
+            Self::Xlsx => "xlsx",
+            Self::Csv => "csv",
+            Self::Jsonl => "jsonl",
+            Self::Parquet => "parquet",
+        }
+    }
+    /// Returns the media type of files in the format
+    #[must_use]
+    pub const fn content_type(&self) -> &'static str {
+        match self {
+            Self::Xlsx => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            Self::Csv => "text/csv",
+            Self::Jsonl => "application/x-ndjson",
+            Self::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "xlsx" => Ok(Self::Xlsx),
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::Jsonl),
+            "parquet" => Ok(Self::Parquet),
+            _ => Err(Error::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// A single value in a table
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Empty,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+
+    /// A time without a timezone, as it's stored in the workbook
+    DateTime(NaiveDateTime),
+
+    /// A time in Europe/Amsterdam
+    Timestamp(DateTime<Tz>),
+}
+
+impl From<&DataType> for Value {
+    fn from(value: &DataType) -> Self {
+        match value {
+            DataType::Int(value) => Self::Int(*value),
+            DataType::Float(value) => Self::Float(*value),
+            DataType::String(value) if value.trim().is_empty() => Self::Empty,
+            DataType::String(value) => Self::Text(value.trim().to_owned()),
+            DataType::Bool(value) => Self::Bool(*value),
+            DataType::DateTime(_) | DataType::DateTimeIso(_) => value
+                .as_datetime()
+                .map_or_else(|| Self::Text(value.to_string()), Self::DateTime),
+            DataType::Duration(_) | DataType::DurationIso(_) => Self::Text(value.to_string()),
+            DataType::Error(_) | DataType::Empty => Self::Empty,
+        }
+    }
+}
+
+impl Value {
+    /// Returns the value as text, empty values are empty strings
+    fn to_text(&self) -> String {
+        match self {
+            Self::Empty => String::new(),
+            Self::Bool(value) => value.to_string(),
+            Self::Int(value) => value.to_string(),
+            Self::Float(value) => value.to_string(),
+            Self::Text(value) => value.clone(),
+            Self::DateTime(value) => value.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            Self::Timestamp(value) => value.to_rfc3339(),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::Empty => serde_json::Value::Null,
+            Self::Bool(value) => (*value).into(),
+            Self::Int(value) => (*value).into(),
+            Self::Float(value) => serde_json::Number::from_f64(*value)
+                .map_or(serde_json::Value::Null, serde_json::Value::Number),
+            Self::Text(_) | Self::DateTime(_) | Self::Timestamp(_) => self.to_text().into(),
+        }
+    }
+}
+
+/// The type of all values in a column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Bool,
+    Int,
+    Float,
+    Text,
+
+    /// A time without a timezone, text like `2024-01-31` or `31-01-2024 12:00` is parsed
+    DateTime,
+
+    /// A time in Europe/Amsterdam
+    Timestamp,
+}
+
+impl ColumnType {
+    /// Converts the value to this type, returns `None` if it doesn't fit.
+    /// Empty values fit every type and every value can be written as text.
+    fn coerce(self, value: Value) -> Option<Value> {
+        match (self, value) {
+            (_, Value::Empty) => Some(Value::Empty),
+            (Self::Text, Value::Text(text)) => Some(Value::Text(text)),
+            (Self::Text, value) => Some(Value::Text(value.to_text())),
+            (Self::Bool, Value::Bool(value)) => Some(Value::Bool(value)),
+            (Self::Int, Value::Int(value)) => Some(Value::Int(value)),
+            #[allow(clippy::cast_possible_truncation)]
+            (Self::Int, Value::Float(value)) if value.fract() == 0.0 => {
+                Some(Value::Int(value as i64))
+            }
+            (Self::Int, Value::Text(text)) => text.parse().ok().map(Value::Int),
+            (Self::Float, Value::Float(value)) => Some(Value::Float(value)),
+            #[allow(clippy::cast_precision_loss)]
+            (Self::Float, Value::Int(value)) => Some(Value::Float(value as f64)),
+            (Self::Float, Value::Text(text)) => parse_decimal(&text).map(Value::Float),
+            (Self::DateTime, Value::DateTime(value)) => Some(Value::DateTime(value)),
+            (Self::DateTime, Value::Text(text)) => parse_date_time(&text).map(Value::DateTime),
+            (Self::Timestamp, Value::Timestamp(value)) => Some(Value::Timestamp(value)),
+            _ => None,
+        }
+    }
+}
+
+/// Parses a number that's written as text, in Dutch notation like `1.250,5` or with a decimal point like `1250.5`
+pub(crate) fn parse_decimal(text: &str) -> Option<f64> {
+    let text = text.trim();
+    if text.contains(',') {
+        text.replace('.', "").replace(',', ".").parse().ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// Parses a date or time in the formats the portal writes them as text
+fn parse_date_time(text: &str) -> Option<NaiveDateTime> {
+    [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%d-%m-%Y %H:%M:%S",
+        "%d-%m-%Y %H:%M",
+    ]
+    .into_iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+    .or_else(|| {
+        ["%Y-%m-%d", "%d-%m-%Y"]
+            .into_iter()
+            .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+            .map(|date| date.and_time(NaiveTime::MIN))
+    })
+}
+
+/// Returns the type of the converted columns of the report, see [`column_name`].
+/// Only the columns that are known from real exports have a type, other columns are text.
+#[must_use]
+pub const fn column_types(report: &Report) -> &'static [(&'static str, ColumnType)] {
+    use ColumnType::{Float, Text, Timestamp};
+    match report {
+        Report::Aansluitinglijst => &[("ean", Text), ("status", Text)],
+        Report::EnergieVerbruikPerUur(_, _, _) => &[
+            ("ean", Text),
+            ("timestamp", Timestamp),
+            ("value", Float),
+            ("unit", Text),
+            ("direction", Text),
+        ],
+        Report::Belastingcluster
+        | Report::Co2
+        | Report::Datakwaliteit
+        | Report::Gebouwen
+        | Report::MeetEnInfra
+        | Report::Metadata
+        | Report::Meterstanden
+        | Report::Mj
+        | Report::Tussenmeter
+        | Report::Verbruik => &[],
+    }
+}
+
+/// Returns the type of the converted column in the report
+fn column_type(report: &Report, column: &str) -> ColumnType {
+    column_types(report)
+        .iter()
+        .find(|(name, _)| *name == column)
+        .map_or(ColumnType::Text, |(_, column_type)| *column_type)
+}
+
+/// A report converted to named columns and rows
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    pub columns: Vec<String>,
+
+    /// The type of every column, every value in the column has this type or is empty
+    pub types: Vec<ColumnType>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    /// Writes the table as comma separated values with a header row
+    ///
+    /// # Errors
+    /// Returns an error if a row couldn't be written
+    pub fn to_csv(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(&self.columns)?;
+        for row in &self.rows {
+            writer.write_record(row.iter().map(Value::to_text))?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| Error::Csv(e.into_error().into()))
+    }
+
+    /// Writes the table as a json object per line
+    ///
+    /// # Errors
+    /// Returns an error if a row couldn't be serialized
+    pub fn to_jsonl(&self) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        for row in &self.rows {
+            let object = self
+                .columns
+                .iter()
+                .cloned()
+                .zip(row.iter().map(Value::to_json))
+                .collect::<serde_json::Map<_, _>>();
+            serde_json::to_writer(&mut output, &object)?;
+            output.push(b'\n');
+        }
+        Ok(output)
+    }
+
+    /// Writes the table as a parquet file with a single row group, with the type of every column.
+    ///
+    /// # Errors
+    /// Returns an error if the schema couldn't be created or a column couldn't be written
+    pub fn to_parquet(&self) -> Result<Vec<u8>, Error> {
+        // Create the schema, every column is optional
+        let types = &self.types;
+        let fields = self
+            .columns
+            .iter()
+            .zip(types)
+            .map(|(name, column_type)| {
+                let (physical_type, logical_type) = match column_type {
+                    ColumnType::Bool => (PhysicalType::BOOLEAN, None),
+                    ColumnType::Int => (PhysicalType::INT64, None),
+                    ColumnType::Float => (PhysicalType::DOUBLE, None),
+                    ColumnType::Text => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+                    ColumnType::DateTime | ColumnType::Timestamp => (
+                        PhysicalType::INT64,
+                        Some(LogicalType::Timestamp {
+                            is_adjusted_to_u_t_c: *column_type == ColumnType::Timestamp,
+                            unit: parquet::basic::TimeUnit::MILLIS(Default::default()),
+                        }),
+                    ),
+                };
+                Type::primitive_type_builder(name, physical_type)
+                    .with_repetition(Repetition::OPTIONAL)
+                    .with_logical_type(logical_type)
+                    .build()
+                    .map(Arc::new)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let schema = Type::group_type_builder("report")
+            .with_fields(fields)
+            .build()?;
+
+        let mut output = Vec::new();
+        let mut writer = SerializedFileWriter::new(
+            &mut output,
+            Arc::new(schema),
+            Arc::new(WriterProperties::builder().build()),
+        )?;
+        let mut row_group = writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            let values = self
+                .rows
+                .iter()
+                .map(|row| row.get(index).unwrap_or(&Value::Empty));
+
+            // Empty values are only stored in the definition levels
+            let definition_levels = values
+                .clone()
+                .map(|value| i16::from(*value != Value::Empty))
+                .collect::<Vec<_>>();
+            let values = values.filter(|value| **value != Value::Empty);
+            match types.get(index).copied().unwrap_or(ColumnType::Text) {
+                ColumnType::Bool => {
+                    let values = values
+                        .map(|value| matches!(value, Value::Bool(true)))
+                        .collect::<Vec<_>>();
+                    column.typed::<BoolType>().write_batch(
+                        &values,
+                        Some(&definition_levels),
+                        None,
+                    )?;
+                }
+                ColumnType::Int => {
+                    let values = values
+                        .map(|value| match value {
+                            Value::Int(value) => *value,
+                            _ => 0,
+                        })
+                        .collect::<Vec<_>>();
+                    column.typed::<Int64Type>().write_batch(
+                        &values,
+                        Some(&definition_levels),
+                        None,
+                    )?;
+                }
+                ColumnType::Float => {
+                    let values = values
+                        .map(|value| match value {
+                            #[allow(clippy::cast_precision_loss)]
+                            Value::Int(value) => *value as f64,
+                            Value::Float(value) => *value,
+                            _ => 0.0,
+                        })
+                        .collect::<Vec<_>>();
+                    column.typed::<DoubleType>().write_batch(
+                        &values,
+                        Some(&definition_levels),
+                        None,
+                    )?;
+                }
+                ColumnType::Text => {
+                    let values = values
+                        .map(|value| ByteArray::from(value.to_text().into_bytes()))
+                        .collect::<Vec<_>>();
+                    column.typed::<ByteArrayType>().write_batch(
+                        &values,
+                        Some(&definition_levels),
+                        None,
+                    )?;
+                }
+                ColumnType::DateTime | ColumnType::Timestamp => {
+                    let values = values
+                        .map(|value| match value {
+                            Value::DateTime(value) => value.and_utc().timestamp_millis(),
+                            Value::Timestamp(value) => value.timestamp_millis(),
+                            _ => 0,
+                        })
+                        .collect::<Vec<_>>();
+                    column.typed::<Int64Type>().write_batch(
+                        &values,
+                        Some(&definition_levels),
+                        None,
+                    )?;
+                }
+            }
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        writer.close()?;
+        Ok(output)
+    }
+}
+
+/// Converts a column header to the name that's used in every converted report.
+/// Names are lowercase words separated by underscores, and headers that describe the same value share a name.
+#[must_use]
+pub fn column_name(header: &str) -> String {
+    let name = header
+        .trim()
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("_");
+
+    // Use the same name for the same value across reports
+    match name.as_str() {
+        "ean" | "ean_code" | "eancode" | "meter" => "ean".to_owned(),
+        "datum" | "datum_tijd" | "tijdstip" | "timestamp" => "timestamp".to_owned(),
+        "eenheid" | "unit" => "unit".to_owned(),
+        "waarde" | "value" => "value".to_owned(),
+        _ => name,
+    }
+}
+
+/// Returns the types of the columns, a column with a value that doesn't fit its type is written as text instead of failing the report
+fn fit_types(
+    report: &Report,
+    columns: &[String],
+    types: Vec<ColumnType>,
+    rows: &[Vec<Value>],
+) -> Vec<ColumnType> {
+    types
+        .into_iter()
+        .enumerate()
+        .map(|(index, column_type)| {
+            let Some(value) = rows
+                .iter()
+                .map(|row| &row[index])
+                .find(|value| column_type.coerce((*value).clone()).is_none())
+            else {
+                return column_type;
+            };
+            tracing::warn!(
+                report = report.name(),
+                column = columns[index],
+                value = value.to_text(),
+                "Writing the column as text, the value doesn't fit its type"
+            );
+            ColumnType::Text
+        })
+        .collect()
+}
+
+/// Reads the worksheet of a report as a table with converted column names
+fn worksheet_table(report: &Report, reader: impl Read + Seek) -> Result<Table, Error> {
+    // Open the worksheet of the report, or the first worksheet if its layout doesn't name one
+    let mut workbook = Xlsx::new(reader)?;
+    let range = match report.layout().worksheet {
+        Some(worksheet) => workbook.worksheet_range(worksheet)?,
+        None => workbook
+            .worksheet_range_at(0)
+            .ok_or(Error::MissingWorksheet)??,
+    };
+    let mut rows = range.rows();
+
+    // Name the columns, unnamed and duplicate columns get their position as suffix
+    let mut columns: Vec<String> = Vec::new();
+    for (index, header) in rows.next().unwrap_or_default().iter().enumerate() {
+        let mut name = column_name(&header.to_string());
+        if name.is_empty() || columns.contains(&name) {
+            name = format!(
+                "{}_{}",
+                if name.is_empty() { "column" } else { &name },
+                index + 1
+            );
+        }
+        columns.push(name);
+    }
+
+    // Take every row that contains a value
+    let rows = rows
+        .map(|row| {
+            (0..columns.len())
+                .map(|index| row.get(index).map_or(Value::Empty, Value::from))
+                .collect::<Vec<_>>()
+        })
+        .filter(|row| row.iter().any(|value| *value != Value::Empty))
+        .collect::<Vec<_>>();
+
+    // Convert the values to the types of the columns
+    let types = fit_types(
+        report,
+        &columns,
+        columns
+            .iter()
+            .map(|column| column_type(report, column))
+            .collect(),
+        &rows,
+    );
+    let rows = rows
+        .into_iter()
+        .map(|row| {
+            row.into_iter()
+                .zip(&types)
+                .map(|(value, column_type)| column_type.coerce(value).unwrap_or(Value::Empty))
+                .collect()
+        })
+        .collect();
+    Ok(Table {
+        columns,
+        types,
+        rows,
+    })
+}
+
+/// Converts the downloaded data of a report to a table.
+/// The hourly usage report is parsed into a record per meter and interval,
+/// the columns of other reports are taken from the header row of their worksheet.
+///
+/// # Errors
+/// Returns an error if the data couldn't be read as the report
+pub fn table(report: &Report, data: &[u8]) -> Result<Table, Error> {
+    read_table(report, Cursor::new(data))
+}
+
+/// Reads the downloaded report from the reader as a table, see [`table`]
+///
+/// # Errors
+/// Returns an error if the data couldn't be read as the report
+pub fn read_table(report: &Report, reader: impl Read + Seek) -> Result<Table, Error> {
+    match report {
+        Report::EnergieVerbruikPerUur(_, _, _) => Ok(Table {
+            columns: column_types(report)
+                .iter()
+                .map(|(name, _)| (*name).to_owned())
+                .collect(),
+            types: column_types(report)
+                .iter()
+                .map(|(_, column_type)| *column_type)
+                .collect(),
+            rows: usage::read(reader)?
+                .into_iter()
+                .map(|record| {
+                    vec![
+                        Value::Text(record.meter.to_string()),
+                        Value::Timestamp(record.timestamp),
+                        Value::Float(record.value),
+                        Value::Text(record.unit.to_string()),
+                        Value::Text(record.direction.to_string()),
+                    ]
+                })
+                .collect(),
+        }),
+        _ => worksheet_table(report, reader),
+    }
+}
+
+/// Converts the downloaded data of a report to the format.
+/// Xlsx data is returned as is.
+///
+/// # Errors
+/// Returns an error if the data couldn't be read as the report or written in the format
+pub fn convert(report: &Report, data: Vec<u8>, format: Format) -> Result<Vec<u8>, Error> {
+    match format {
+        Format::Xlsx => Ok(data),
+        format => convert_reader(report, Cursor::new(data), format),
+    }
+}
+
+/// Converts the downloaded report from the reader to the format, see [`convert`]
+///
+/// # Errors
+/// Returns an error if the data couldn't be read as the report or written in the format
+pub fn convert_reader(
+    report: &Report,
+    mut reader: impl Read + Seek,
+    format: Format,
+) -> Result<Vec<u8>, Error> {
+    match format {
+        Format::Xlsx => {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            Ok(data)
+        }
+        Format::Csv => read_table(report, reader)?.to_csv(),
+        Format::Jsonl => read_table(report, reader)?.to_jsonl(),
+        Format::Parquet => read_table(report, reader)?.to_parquet(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use parquet::{
+        basic::Type as PhysicalType,
+        file::reader::{FileReader as _, SerializedFileReader},
+        record::Field,
+    };
+
+    use super::*;
+    use crate::id::Id;
+
+    const CO2: &[u8] = include_bytes!("../tests/fixtures/reports/co2.xlsx");
+    const METERSTANDEN: &[u8] = include_bytes!("../tests/fixtures/reports/meterstanden.xlsx");
+    const USAGE: &[u8] = include_bytes!("../tests/fixtures/reports/energie-verbruik-per-uur.xlsx");
+
+    /// Reads the csv back into its header and rows
+    fn read_csv(data: &[u8]) -> (Vec<String>, Vec<Vec<String>>) {
+        let mut reader = csv::Reader::from_reader(data);
+        let header = reader
+            .headers()
+            .expect("No header")
+            .iter()
+            .map(ToOwned::to_owned)
+            .collect();
+        let rows = reader
+            .records()
+            .map(|record| {
+                record
+                    .expect("Invalid record")
+                    .iter()
+                    .map(ToOwned::to_owned)
+                    .collect()
+            })
+            .collect();
+        (header, rows)
+    }
+
+    /// Reads the parquet file back with a parquet reader, returns the physical type of every column and the rows
+    fn read_parquet(data: Vec<u8>) -> (Vec<(String, PhysicalType)>, Vec<Vec<Field>>) {
+        // The reader needs a file to read the footer first
+        let path = std::env::temp_dir().join(format!(
+            "rapportage-convert-{}-{}.parquet",
+            std::process::id(),
+            data.len()
+        ));
+        std::fs::write(&path, data).expect("Failed to write the parquet file");
+        let reader = SerializedFileReader::new(
+            std::fs::File::open(&path).expect("Failed to open the parquet file"),
+        )
+        .expect("Invalid parquet file");
+        let columns = reader
+            .metadata()
+            .file_metadata()
+            .schema_descr()
+            .columns()
+            .iter()
+            .map(|column| (column.name().to_owned(), column.physical_type()))
+            .collect();
+        let rows = reader
+            .get_row_iter(None)
+            .expect("Failed to read the rows")
+            .map(|row| {
+                row.expect("Invalid row")
+                    .get_column_iter()
+                    .map(|(_, field)| field.clone())
+                    .collect()
+            })
+            .collect();
+        std::fs::remove_file(path).ok();
+        (columns, rows)
+    }
+
+    #[test]
+    fn xlsx_to_csv_keeps_the_values() {
+        let csv = convert(&Report::Co2, CO2.to_vec(), Format::Csv).expect("Failed to convert");
+        let (header, rows) = read_csv(&csv);
+        assert_eq!(header, ["jaar", "product", "unit", "value"]);
+        assert_eq!(
+            rows,
+            [
+                ["2023", "Elektriciteit", "kg CO2", "1250.5"],
+                ["2024", "Elektriciteit", "kg CO2", "1180.25"]
+            ]
+        );
+
+        // Columns that aren't known from real exports keep the text of the export
+        let csv = convert(&Report::Meterstanden, METERSTANDEN.to_vec(), Format::Csv)
+            .expect("Failed to convert");
+        let (header, rows) = read_csv(&csv);
+        assert_eq!(header, ["ean", "timestamp", "meterstand"]);
+        assert_eq!(rows[0], ["871687120000000001", "2024-01-01", "10250"]);
+    }
+
+    #[test]
+    fn xlsx_to_parquet_writes_columns_without_a_type_as_text() {
+        let parquet =
+            convert(&Report::Co2, CO2.to_vec(), Format::Parquet).expect("Failed to convert");
+        let (columns, rows) = read_parquet(parquet);
+        assert_eq!(
+            columns,
+            [
+                ("jaar".to_owned(), PhysicalType::BYTE_ARRAY),
+                ("product".to_owned(), PhysicalType::BYTE_ARRAY),
+                ("unit".to_owned(), PhysicalType::BYTE_ARRAY),
+                ("value".to_owned(), PhysicalType::BYTE_ARRAY),
+            ]
+        );
+        assert_eq!(
+            rows[0],
+            [
+                Field::Str("2023".to_owned()),
+                Field::Str("Elektriciteit".to_owned()),
+                Field::Str("kg CO2".to_owned()),
+                Field::Str("1250.5".to_owned()),
+            ]
+        );
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[test]
+    fn usage_to_parquet_has_timestamps() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+        let report = Report::EnergieVerbruikPerUur(Id::from(1001), date, date);
+        let parquet = convert(&report, USAGE.to_vec(), Format::Parquet).expect("Failed to convert");
+        let (columns, rows) = read_parquet(parquet);
+        assert_eq!(
+            columns
+                .iter()
+                .map(|(_, column_type)| *column_type)
+                .collect::<Vec<_>>(),
+            [
+                PhysicalType::BYTE_ARRAY,
+                PhysicalType::INT64,
+                PhysicalType::DOUBLE,
+                PhysicalType::BYTE_ARRAY,
+                PhysicalType::BYTE_ARRAY
+            ]
+        );
+        assert_eq!(rows.len(), 3);
+
+        // 2024-01-31 00:00 in Europe/Amsterdam
+        assert_eq!(rows[0][1], Field::TimestampMillis(1_706_655_600_000));
+        assert_eq!(rows[0][2], Field::Double(1.5));
+        assert_eq!(rows[0][4], Field::Str("consumption".to_owned()));
+    }
+
+    #[test]
+    fn values_are_converted_to_the_type_of_their_column() {
+        assert_eq!(
+            ColumnType::Int.coerce(Value::Float(2024.0)),
+            Some(Value::Int(2024))
+        );
+        assert_eq!(
+            ColumnType::Float.coerce(Value::Text("12,5".to_owned())),
+            Some(Value::Float(12.5))
+        );
+        assert_eq!(
+            ColumnType::Float.coerce(Value::Text("1.250,5".to_owned())),
+            Some(Value::Float(1250.5))
+        );
+        assert_eq!(
+            ColumnType::Float.coerce(Value::Text("1250.5".to_owned())),
+            Some(Value::Float(1250.5))
+        );
+        assert_eq!(
+            ColumnType::Text.coerce(Value::Int(1)),
+            Some(Value::Text("1".to_owned()))
+        );
+        assert_eq!(ColumnType::Int.coerce(Value::Empty), Some(Value::Empty));
+        assert_eq!(ColumnType::Int.coerce(Value::Text("twee".to_owned())), None);
+        assert_eq!(ColumnType::Float.coerce(Value::Bool(true)), None);
+        assert_eq!(column_type(&Report::Co2, "onbekend"), ColumnType::Text);
+    }
+
+    #[test]
+    fn column_with_a_value_that_doesnt_fit_is_text() {
+        let columns = ["value".to_owned(), "unit".to_owned()];
+        let rows = [
+            vec![Value::Float(1.5), Value::Text("kWh".to_owned())],
+            vec![Value::Text("n.v.t.".to_owned()), Value::Empty],
+        ];
+        assert_eq!(
+            fit_types(
+                &Report::Co2,
+                &columns,
+                vec![ColumnType::Float, ColumnType::Text],
+                &rows
+            ),
+            [ColumnType::Text, ColumnType::Text]
+        );
+        assert_eq!(
+            fit_types(
+                &Report::Co2,
+                &columns,
+                vec![ColumnType::Float, ColumnType::Text],
+                &rows[..1]
+            ),
+            [ColumnType::Float, ColumnType::Text]
+        );
+    }
+}
@@ -0,0 +1,434 @@
+use std::{collections::HashSet, fmt::Display, path::Path, sync::Mutex};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection};
+
+use crate::{ean::Ean, id::Id, report::Report, usage::UsageRecord};
+
+/// Errors that can occur while storing data in the database
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Sqlite(#[from] rusqlite::Error),
+    Poisoned,
+    DuplicateRecord {
+        ean: String,
+        timestamp: String,
+        direction: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// The tables of the database, created when they don't exist yet
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS connections (
+    ean TEXT PRIMARY KEY NOT NULL,
+    id INTEGER NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS usage (
+    ean TEXT NOT NULL REFERENCES connections (ean),
+    timestamp TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    value REAL NOT NULL,
+    unit TEXT NOT NULL,
+    PRIMARY KEY (ean, timestamp, direction)
+);
+
+CREATE TABLE IF NOT EXISTS downloads (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    report TEXT NOT NULL,
+    ean TEXT,
+    start_date TEXT,
+    end_date TEXT,
+    file_name TEXT NOT NULL,
+    bytes INTEGER NOT NULL,
+    records INTEGER NOT NULL,
+    downloaded_at TEXT NOT NULL
+);
+";
+
+/// Adds the direction to the key of a usage table that was created without it,
+/// the stored records were all consumption because feed-in used to overwrite them
+const ADD_DIRECTION: &str = "
+ALTER TABLE usage RENAME TO usage_without_direction;
+{SCHEMA}
+INSERT INTO usage (ean, timestamp, direction, value, unit)
+    SELECT ean, timestamp, 'consumption', value, unit FROM usage_without_direction;
+DROP TABLE usage_without_direction;
+";
+
+/// A downloaded report that's added to the download log
+#[derive(Debug, Clone)]
+pub struct Download<'a> {
+    /// The downloaded report
+    pub report: &'a Report,
+
+    /// The meter the report belongs to, if it's for a single meter
+    pub ean: Option<&'a Ean>,
+
+    /// The name of the file on the portal
+    pub file_name: &'a str,
+
+    /// The size of the downloaded file
+    pub bytes: u64,
+
+    /// The number of stored records
+    pub records: usize,
+
+    /// The time the report was downloaded
+    pub downloaded_at: DateTime<Utc>,
+}
+
+/// A SQLite database that stores usage records, connections and a download log
+#[derive(Debug)]
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+
+impl Database {
+    /// Opens or creates the database at the path and creates the missing tables.
+    /// Foreign keys are enforced, so usage records can only be stored for known connections.
+    ///
+    /// # Errors
+    /// Returns an error if the database couldn't be opened or the tables couldn't be created or migrated
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut connection = Connection::open(path)?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA)?;
+
+        // Usage tables of earlier versions are keyed without the direction
+        let has_direction: bool = connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM pragma_table_info('usage') WHERE name = 'direction')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_direction {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(&ADD_DIRECTION.replace("{SCHEMA}", SCHEMA))?;
+            transaction.commit()?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Opens the database of a `sqlite://` url, like `sqlite:///path/db.sqlite`.
+    /// Returns `None` if the url doesn't use the sqlite scheme.
+    pub fn open_url(url: &str) -> Option<Result<Self, Error>> {
+        url.strip_prefix("sqlite://").map(Self::open)
+    }
+
+    fn connection(&self) -> Result<std::sync::MutexGuard<'_, Connection>, Error> {
+        self.connection.lock().map_err(|_| Error::Poisoned)
+    }
+
+    /// Checks whether the database can be written to, by taking and releasing the write lock
+    ///
+    /// # Errors
+    /// Returns an error if the database is read only or locked
+    pub fn check(&self) -> Result<(), Error> {
+        self.connection()?
+            .execute_batch("BEGIN IMMEDIATE; ROLLBACK;")?;
+        Ok(())
+    }
+
+    /// Stores the id of the connection with the ean
+    ///
+    /// # Errors
+    /// Returns an error if the connection couldn't be stored
+    pub fn upsert_connection(&self, ean: &Ean, id: Id) -> Result<(), Error> {
+        self.connection()?.execute(
+            "INSERT INTO connections (ean, id, updated_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT (ean) DO UPDATE SET id = excluded.id, updated_at = excluded.updated_at",
+            params![ean.value(), u32::from(id), timestamp(&Utc::now())],
+        )?;
+        Ok(())
+    }
+
+    /// Stores the usage records, existing records with the same ean, timestamp and direction are replaced.
+    /// Returns the number of stored records.
+    ///
+    /// # Errors
+    /// - If two records have the same ean, timestamp and direction, one would overwrite the other
+    /// - If a record couldn't be stored
+    ///
+    /// No records are stored in both cases
+    pub fn upsert_usage(&self, records: &[UsageRecord]) -> Result<usize, Error> {
+        // Every record needs its own row
+        let mut keys = HashSet::new();
+        for record in records {
+            let key = (
+                record.meter.value(),
+                timestamp(&record.timestamp.with_timezone(&Utc)),
+                record.direction.to_string(),
+            );
+            if keys.contains(&key) {
+                let (ean, timestamp, direction) = key;
+                return Err(Error::DuplicateRecord {
+                    ean: ean.to_owned(),
+                    timestamp,
+                    direction,
+                });
+            }
+            keys.insert(key);
+        }
+
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction.prepare(
+                "INSERT INTO usage (ean, timestamp, direction, value, unit) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (ean, timestamp, direction) DO UPDATE SET value = excluded.value, unit = excluded.unit",
+            )?;
+            for record in records {
+                statement.execute(params![
+                    record.meter.value(),
+                    timestamp(&record.timestamp.with_timezone(&Utc)),
+                    record.direction.to_string(),
+                    record.value,
+                    record.unit.to_string(),
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(records.len())
+    }
+
+    /// Adds the download to the download log
+    ///
+    /// # Errors
+    /// Returns an error if the download couldn't be stored
+    pub fn log_download(&self, download: &Download) -> Result<(), Error> {
+        let date_range = download.report.date_range();
+        self.connection()?.execute(
+            "INSERT INTO downloads (report, ean, start_date, end_date, file_name, bytes, records, downloaded_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                download.report.name(),
+                download.ean.map(Ean::value),
+                date_range.map(|(start_date, _)| start_date.to_string()),
+                date_range.map(|(_, end_date)| end_date.to_string()),
+                download.file_name,
+                download.bytes,
+                download.records,
+                timestamp(&download.downloaded_at),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Formats the time the same way for every row, so it can be used as key
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone as _;
+    use chrono_tz::Europe::Amsterdam;
+
+    use super::*;
+    use crate::usage::{Direction, Unit};
+
+    fn ean(value: &str) -> Ean {
+        Ean::from(value.to_owned())
+    }
+
+    fn record(meter: &str, hour: u32, value: f64) -> UsageRecord {
+        UsageRecord {
+            meter: ean(meter),
+            timestamp: Amsterdam
+                .with_ymd_and_hms(2024, 1, 31, hour, 0, 0)
+                .single()
+                .expect("Invalid time"),
+            value,
+            unit: Unit::Kwh,
+            direction: Direction::Consumption,
+        }
+    }
+
+    /// Returns the stored usage as ean, timestamp and value
+    fn usage(database: &Database) -> Vec<(String, String, f64)> {
+        let connection = database.connection().expect("Poisoned");
+        let mut statement = connection
+            .prepare("SELECT ean, timestamp, value FROM usage ORDER BY ean, timestamp, direction")
+            .expect("Invalid query");
+        statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .expect("Failed to query")
+            .collect::<Result<_, _>>()
+            .expect("Invalid row")
+    }
+
+    #[test]
+    fn foreign_keys_are_enforced() {
+        let database = Database::open(":memory:").expect("Failed to open");
+        let enabled: bool = database
+            .connection()
+            .expect("Poisoned")
+            .query_row("PRAGMA foreign_keys", [], |row| row.get(0))
+            .expect("Failed to query");
+        assert!(enabled);
+
+        // Usage of an unknown connection isn't stored, not even the records of known connections
+        database
+            .upsert_connection(&ean("871687120000000001"), Id::from(1001))
+            .expect("Failed to store the connection");
+        let records = [
+            record("871687120000000001", 0, 1.0),
+            record("871687120000000009", 0, 1.0),
+        ];
+        assert!(database.upsert_usage(&records).is_err());
+        assert!(usage(&database).is_empty());
+    }
+
+    #[test]
+    fn upserting_the_same_records_replaces_them() {
+        let database = Database::open(":memory:").expect("Failed to open");
+        let meter = ean("871687120000000001");
+        database
+            .upsert_connection(&meter, Id::from(1001))
+            .expect("Failed to store the connection");
+        let records = [
+            record("871687120000000001", 0, 1.0),
+            record("871687120000000001", 1, 2.0),
+        ];
+        assert_eq!(database.upsert_usage(&records).expect("Failed to store"), 2);
+
+        // Downloading the same period again replaces the values instead of adding rows
+        let records = [
+            record("871687120000000001", 1, 2.5),
+            record("871687120000000001", 2, 3.0),
+        ];
+        assert_eq!(database.upsert_usage(&records).expect("Failed to store"), 2);
+        assert_eq!(
+            usage(&database),
+            [
+                (
+                    meter.value().to_owned(),
+                    "2024-01-30T23:00:00Z".to_owned(),
+                    1.0
+                ),
+                (
+                    meter.value().to_owned(),
+                    "2024-01-31T00:00:00Z".to_owned(),
+                    2.5
+                ),
+                (
+                    meter.value().to_owned(),
+                    "2024-01-31T01:00:00Z".to_owned(),
+                    3.0
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn consumption_and_feed_in_are_stored_separately() {
+        let database = Database::open(":memory:").expect("Failed to open");
+        let meter = ean("871687120000000001");
+        database
+            .upsert_connection(&meter, Id::from(1001))
+            .expect("Failed to store the connection");
+        let feed_in = UsageRecord {
+            direction: Direction::FeedIn,
+            ..record("871687120000000001", 0, 3.0)
+        };
+        let records = [record("871687120000000001", 0, 1.0), feed_in.clone()];
+        assert_eq!(database.upsert_usage(&records).expect("Failed to store"), 2);
+        let time = "2024-01-30T23:00:00Z".to_owned();
+        assert_eq!(
+            usage(&database),
+            [
+                (meter.value().to_owned(), time.clone(), 1.0),
+                (meter.value().to_owned(), time.clone(), 3.0),
+            ]
+        );
+
+        // Records that would overwrite each other aren't stored at all
+        let records = [
+            feed_in.clone(),
+            UsageRecord {
+                value: 4.0,
+                ..feed_in
+            },
+        ];
+        assert!(matches!(
+            database.upsert_usage(&records),
+            Err(Error::DuplicateRecord { direction, .. }) if direction == "feed_in"
+        ));
+        assert_eq!(usage(&database)[1], (meter.value().to_owned(), time, 3.0));
+    }
+
+    #[test]
+    fn usage_without_direction_is_migrated() {
+        let path =
+            std::env::temp_dir().join(format!("rapportage-migrate-{}.sqlite", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        {
+            let connection = Connection::open(&path).expect("Failed to open");
+            connection
+                .execute_batch(
+                    "CREATE TABLE connections (ean TEXT PRIMARY KEY NOT NULL, id INTEGER NOT NULL, updated_at TEXT NOT NULL);
+                    CREATE TABLE usage (
+                        ean TEXT NOT NULL REFERENCES connections (ean),
+                        timestamp TEXT NOT NULL,
+                        value REAL NOT NULL,
+                        unit TEXT NOT NULL,
+                        PRIMARY KEY (ean, timestamp)
+                    );
+                    INSERT INTO connections VALUES ('871687120000000001', 1001, '2024-01-31T00:00:00Z');
+                    INSERT INTO usage VALUES ('871687120000000001', '2024-01-30T23:00:00Z', 1.0, 'kWh');",
+                )
+                .expect("Failed to create the old tables");
+        }
+
+        let database = Database::open(&path).expect("Failed to migrate");
+        let feed_in = UsageRecord {
+            direction: Direction::FeedIn,
+            ..record("871687120000000001", 0, 3.0)
+        };
+        assert_eq!(
+            database.upsert_usage(&[feed_in]).expect("Failed to store"),
+            1
+        );
+        assert_eq!(
+            usage(&database)
+                .into_iter()
+                .map(|(_, _, value)| value)
+                .collect::<Vec<_>>(),
+            [1.0, 3.0]
+        );
+        drop(database);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn upserting_a_connection_updates_its_id() {
+        let database = Database::open(":memory:").expect("Failed to open");
+        let meter = ean("871687120000000001");
+        database
+            .upsert_connection(&meter, Id::from(1001))
+            .expect("Failed to store the connection");
+        database
+            .upsert_connection(&meter, Id::from(2002))
+            .expect("Failed to store the connection");
+        let ids: Vec<u32> = database
+            .connection()
+            .expect("Poisoned")
+            .prepare("SELECT id FROM connections")
+            .expect("Invalid query")
+            .query_map([], |row| row.get(0))
+            .expect("Failed to query")
+            .collect::<Result<_, _>>()
+            .expect("Invalid row");
+        assert_eq!(ids, [2002]);
+    }
+}
@@ -1,6 +1,13 @@
-use std::{fmt::Display, num::ParseIntError, string::FromUtf8Error};
+use std::{
+    fmt::Display,
+    io::{Read, Seek},
+    num::ParseIntError,
+    string::FromUtf8Error,
+};
 
+use calamine::{Reader as _, Xlsx};
 use scraper::error::SelectorErrorKind;
+use serde::Serialize;
 
 use crate::{id::Id, login::CookieStore};
 
@@ -13,6 +20,7 @@ pub enum Error {
     Selector(#[from] SelectorErrorKind<'static>),
     UrlParse(#[from] url::ParseError),
     ParseInt(#[from] ParseIntError),
+    Xlsx(#[from] calamine::XlsxError),
 }
 
 impl Display for Error {
@@ -21,7 +29,7 @@ impl Display for Error {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Ean(String);
 
 impl From<String> for Ean {
@@ -46,11 +54,11 @@ impl Ean {
     pub async fn from_id(cookie_store: &CookieStore, id: Id) -> Result<Ean, Error> {
         // Download the page for the id
         let page = cookie_store
-            .client()
-            .get(format!(
-                "https://www.dbenergie.nl/Connections/Edit/Index/{id}"
-            ))
-            .send()
+            .send(
+                cookie_store
+                    .client()
+                    .get(cookie_store.url(&format!("/Connections/Edit/Index/{id}"))),
+            )
             .await?
             .bytes()
             .await?
@@ -80,3 +88,57 @@ impl Ean {
         &self.0
     }
 }
+
+/// A connection in the aansluitinglijst
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connection {
+    pub ean: Ean,
+
+    /// The status of the connection, like Actief, if the list contains it
+    pub status: Option<String>,
+}
+
+/// Reads the connections from a downloaded aansluitinglijst, rows without an ean are left out
+///
+/// # Errors
+/// Returns an error if the workbook or its worksheet couldn't be read, or if the worksheet is empty
+pub fn read_connections<R: Read + Seek>(workbook: R) -> Result<Vec<Connection>, Error> {
+    // Open the list
+    let mut workbook = Xlsx::new(workbook)?;
+    let range = workbook.worksheet_range("Lijst_Export")?;
+    let mut rows = range.rows();
+
+    // Take the first row, only store the ean code and status
+    let columns = rows
+        .next()
+        .ok_or(Error::ValueMissing("Empty worksheet"))?
+        .iter()
+        .enumerate()
+        .filter_map(|(index, column)| {
+            let column = column.to_string();
+            if ["EAN code", "Status"].contains(&column.trim()) {
+                Some((index, column))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Take the ean code and status of every row
+    Ok(rows
+        .filter_map(|row| {
+            let mut ean = None;
+            let mut status = None;
+            for (index, column) in &columns {
+                let value = &row[*index];
+                match column.trim() {
+                    "EAN code" => ean = Some(Ean::from(value.to_string().trim().to_owned())),
+                    "Status" => status = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            ean.filter(|ean| !ean.value().is_empty())
+                .map(|ean| Connection { ean, status })
+        })
+        .collect())
+}
@@ -0,0 +1,314 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use axum::http::header;
+use base64::Engine;
+use chrono::{Datelike as _, Local};
+use hyper::http;
+use reqwest::{cookie::CookieStore as _, header::HeaderValue, Client, ResponseBuilderExt as _};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "mock")]
+mod replay;
+
+#[cfg(feature = "mock")]
+pub use replay::Replay;
+
+/// The value that replaces credentials, tokens and cookies in the fixtures
+const SCRUBBED: &str = "scrubbed";
+
+/// Errors that can occur while saving or loading fixtures
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Io(#[from] std::io::Error),
+    Json(#[from] serde_json::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// A request to the portal and its response, without credentials
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exchange {
+    pub method: String,
+
+    /// The path and query of the request, relative to the portal
+    pub path: String,
+
+    /// The decoded `request` header, the parameters of a report
+    pub payload: Option<String>,
+
+    /// The year the exchange was recorded in, the years in report requests are replayed relative to it
+    #[serde(default)]
+    pub year: Option<i32>,
+
+    /// The decoded `PersonalFilter` cookie, the filter of the connection list
+    pub filter: Option<String>,
+
+    /// The names of the fields of a form, their values are scrubbed
+    pub form: Vec<String>,
+    pub status: u16,
+
+    /// The path and query the request was redirected to, if it was
+    pub redirected_to: Option<String>,
+    pub content_type: Option<String>,
+
+    /// The names of the cookies the response set, their values are scrubbed
+    pub set_cookies: Vec<String>,
+
+    /// The file next to the exchange that contains the body of the response, like `0001.body.html`
+    pub body_file: String,
+}
+
+impl Exchange {
+    /// Returns the extension of the body file for the content type
+    fn extension(content_type: Option<&str>, body: &[u8]) -> &'static str {
+        match content_type.unwrap_or_default() {
+            content_type if content_type.contains("html") => "html",
+            content_type if content_type.contains("json") => "json",
+            content_type if content_type.starts_with("text/") => "txt",
+            _ if body.starts_with(b"PK\x03\x04") => "xlsx",
+            _ => "bin",
+        }
+    }
+}
+
+/// Locks the mutex, a panic while it was locked doesn't leave the state invalid
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Checks whether the file is part of a recording, their names start with the number of the exchange
+fn numbered(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split_once('.'))
+        .is_some_and(|(number, _)| number.len() == 4 && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Returns the path and query of the url
+fn path_and_query(url: &reqwest::Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_owned(),
+    }
+}
+
+/// Returns the decoded value of the cookie in the cookie header
+fn cookie(cookies: &str, name: &str) -> Option<String> {
+    cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(found, _)| *found == name)
+        .and_then(|(_, value)| {
+            percent_encoding::percent_decode_str(value)
+                .decode_utf8()
+                .ok()
+                .map(|value| value.into_owned())
+        })
+}
+
+/// Returns the decoded base64 `request` header
+fn payload(value: Option<&HeaderValue>) -> Option<String> {
+    let payload = base64::engine::general_purpose::STANDARD
+        .decode(value?.as_bytes())
+        .ok()?;
+    Some(String::from_utf8_lossy(&payload).into_owned())
+}
+
+#[derive(Debug)]
+struct Recording {
+    directory: PathBuf,
+    exchanges: Mutex<Vec<(Exchange, Vec<u8>)>>,
+
+    /// The credentials, tokens and cookie values that are removed from the fixtures
+    secrets: Mutex<BTreeSet<String>>,
+}
+
+/// Records the requests of a [`crate::login::CookieStore`] and their responses, to replay them with `Replay` of the `mock` feature.
+/// Cloning is cheap, every clone records into the same fixtures.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    inner: Arc<Recording>,
+}
+
+impl Recorder {
+    /// Creates a recorder that saves the fixtures into the directory
+    #[must_use]
+    pub fn new(directory: PathBuf) -> Self {
+        Self {
+            inner: Arc::new(Recording {
+                directory,
+                exchanges: Mutex::new(Vec::new()),
+                secrets: Mutex::new(BTreeSet::new()),
+            }),
+        }
+    }
+
+    /// Returns the directory the fixtures are saved into
+    #[must_use]
+    pub fn directory(&self) -> &Path {
+        &self.inner.directory
+    }
+
+    /// Adds a value that should never be part of the fixtures
+    pub fn add_secret(&self, secret: &str) {
+        if !secret.is_empty() {
+            lock(&self.inner.secrets).insert(secret.to_owned());
+        }
+    }
+
+    /// Sends the request and records it with its response.
+    /// The body of the response is read completely, so it can be recorded before it's returned.
+    ///
+    /// # Errors
+    /// Returns an error if the request failed or the body couldn't be read
+    pub(crate) async fn execute(
+        &self,
+        client: &Client,
+        jar: &reqwest::cookie::Jar,
+        request: reqwest::Request,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        // Describe the request before it's consumed, the cookies are only added by the client
+        let filter = jar
+            .cookies(request.url())
+            .and_then(|cookies| cookie(cookies.to_str().ok()?, "PersonalFilter"));
+        let form = request
+            .body()
+            .and_then(reqwest::Body::as_bytes)
+            .map(|body| {
+                url::form_urlencoded::parse(body)
+                    .map(|(name, value)| {
+                        self.add_secret(&value);
+                        name.into_owned()
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let method = request.method().to_string();
+        let path = path_and_query(request.url());
+        let payload = payload(request.headers().get("request"));
+
+        // Read the whole response
+        let response = client.execute(request).await?;
+        let status = response.status();
+        let url = response.url().clone();
+        let headers = response.headers().clone();
+        let set_cookies = response
+            .cookies()
+            .map(|cookie| {
+                self.add_secret(cookie.value());
+                cookie.name().to_owned()
+            })
+            .collect();
+        let body = response.bytes().await?;
+
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(ToOwned::to_owned);
+        let redirected_to = Some(path_and_query(&url)).filter(|redirected| *redirected != path);
+        let mut exchanges = lock(&self.inner.exchanges);
+        let body_file = format!(
+            "{:04}.body.{}",
+            exchanges.len() + 1,
+            Exchange::extension(content_type.as_deref(), &body)
+        );
+        exchanges.push((
+            Exchange {
+                method,
+                path,
+                payload,
+                year: Some(Local::now().year()),
+                filter,
+                form,
+                status: status.as_u16(),
+                redirected_to,
+                content_type,
+                set_cookies,
+                body_file,
+            },
+            body.to_vec(),
+        ));
+        drop(exchanges);
+
+        // Return the same response to the caller
+        let mut builder = http::Response::builder().status(status).url(url);
+        for (name, value) in &headers {
+            if name != header::CONTENT_ENCODING && name != header::CONTENT_LENGTH {
+                builder = builder.header(name, value);
+            }
+        }
+        let response = builder
+            .body(body)
+            .unwrap_or_else(|_| http::Response::new(hyper::body::Bytes::new()));
+        Ok(response.into())
+    }
+
+    /// Removes every secret from the text
+    fn scrub(&self, text: &str) -> String {
+        // Longer secrets first, a secret can contain a shorter one
+        let mut secrets = lock(&self.inner.secrets)
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        secrets.iter().fold(text.to_owned(), |text, secret| {
+            text.replace(secret, SCRUBBED)
+        })
+    }
+
+    /// Writes every recorded exchange to a numbered json file in the directory, next to a file with its body.
+    /// The fixtures of an earlier recording are replaced, so a new recording can be compared with git diff.
+    /// Returns the number of exchanges that were saved.
+    ///
+    /// # Errors
+    /// Returns an error if the directory couldn't be created or a file couldn't be written
+    pub fn save(&self) -> Result<usize, Error> {
+        let directory = &self.inner.directory;
+        std::fs::create_dir_all(directory)?;
+        for entry in std::fs::read_dir(directory)? {
+            let path = entry?.path();
+            if numbered(&path) {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        let exchanges = lock(&self.inner.exchanges).clone();
+        for (exchange, body) in &exchanges {
+            let exchange = Exchange {
+                path: self.scrub(&exchange.path),
+                payload: exchange
+                    .payload
+                    .as_deref()
+                    .map(|payload| self.scrub(payload)),
+                filter: exchange.filter.as_deref().map(|filter| self.scrub(filter)),
+                redirected_to: exchange
+                    .redirected_to
+                    .as_deref()
+                    .map(|path| self.scrub(path)),
+                ..exchange.clone()
+            };
+            let body = match std::str::from_utf8(body) {
+                Ok(text) if !exchange.body_file.ends_with(".xlsx") => self.scrub(text).into_bytes(),
+                _ => body.clone(),
+            };
+            let number = exchange.body_file.split('.').next().unwrap_or_default();
+            std::fs::write(
+                directory.join(format!("{number}.json")),
+                serde_json::to_vec_pretty(&exchange)?,
+            )?;
+            std::fs::write(directory.join(&exchange.body_file), body)?;
+        }
+        Ok(exchanges.len())
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use chrono::{Datelike as _, Local};
+use hyper::http;
+
+use super::{cookie, lock, numbered, payload, Error, Exchange, SCRUBBED};
+use crate::{mock::Server, request::ReportRequest};
+
+/// Returns the payload with the years of a report request relative to the year,
+/// so requests for the current year match the exchanges that were recorded in an earlier year
+fn relative_years(payload: &str, year: i32) -> String {
+    let Ok(request) = serde_json::from_str::<ReportRequest>(payload) else {
+        return payload.to_owned();
+    };
+    let request = match request {
+        ReportRequest::Year(requested) => ReportRequest::Year(requested - year),
+        ReportRequest::DataQuality(mut request) => {
+            request.year -= year;
+            ReportRequest::DataQuality(request)
+        }
+        ReportRequest::Consumption(mut request) => {
+            request.year_from -= year;
+            request.year_till -= year;
+            ReportRequest::Consumption(request)
+        }
+        ReportRequest::Unit(mut request) => {
+            request.year_from -= year;
+            request.year_till -= year;
+            ReportRequest::Unit(request)
+        }
+        request => request,
+    };
+    request.to_string()
+}
+
+#[derive(Debug)]
+struct Fixtures {
+    exchanges: Vec<(Exchange, Vec<u8>)>,
+
+    /// The number of times every exchange was replayed
+    replayed: Mutex<Vec<usize>>,
+
+    /// The exchanges that are replayed at the paths they were redirected to
+    redirects: Mutex<HashMap<String, usize>>,
+}
+
+/// Serves recorded fixtures back like the portal did.
+/// A request is answered with the recorded exchange with the same method, path, payload and filter,
+/// the years in report requests are compared relative to the year of the recording.
+/// Exchanges are replayed in the order they were recorded, the last one is repeated once every one was used.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    inner: Arc<Fixtures>,
+}
+
+impl Replay {
+    /// Loads the fixtures that a [`super::Recorder`] saved into the directory
+    ///
+    /// # Errors
+    /// Returns an error if a file couldn't be read or a fixture isn't valid
+    pub fn load(directory: &Path) -> Result<Self, Error> {
+        let mut paths = std::fs::read_dir(directory)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| {
+            numbered(path)
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension == "json")
+                && !path.to_string_lossy().contains(".body.")
+        });
+        paths.sort();
+
+        let mut exchanges = Vec::new();
+        for path in paths {
+            let exchange: Exchange = serde_json::from_slice(&std::fs::read(&path)?)?;
+            let body = std::fs::read(directory.join(&exchange.body_file))?;
+            exchanges.push((exchange, body));
+        }
+        Ok(Self {
+            inner: Arc::new(Fixtures {
+                replayed: Mutex::new(vec![0; exchanges.len()]),
+                exchanges,
+                redirects: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    /// Returns the number of loaded exchanges
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.inner.exchanges.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.inner.exchanges.is_empty()
+    }
+
+    /// Returns the routes that replay the fixtures
+    pub fn router(&self) -> Router {
+        let replay = self.clone();
+        Router::new().fallback(move |method: Method, uri: Uri, headers: HeaderMap| {
+            let replay = replay.clone();
+            async move { replay.respond(&method, &uri, &headers) }
+        })
+    }
+
+    /// Serves the fixtures on a free port of localhost until the returned server is dropped
+    ///
+    /// # Errors
+    /// Returns an error if no port could be bound
+    pub fn start(&self) -> Result<Server, hyper::Error> {
+        Server::start(self.router())
+    }
+
+    /// Returns the index of the exchange to replay for the request
+    fn find(&self, method: &Method, path: &str, headers: &HeaderMap) -> Option<usize> {
+        // A redirect is followed by a request for the path it redirected to
+        if let Some(index) = lock(&self.inner.redirects).remove(path) {
+            return Some(index);
+        }
+        let year = Local::now().year();
+        let payload = payload(headers.get("request")).map(|payload| relative_years(&payload, year));
+        let filter = headers
+            .get(header::COOKIE)
+            .and_then(|cookies| cookie(cookies.to_str().ok()?, "PersonalFilter"));
+        let candidates = self
+            .inner
+            .exchanges
+            .iter()
+            .enumerate()
+            .filter(|(_, (exchange, _))| {
+                exchange.method == method.as_str()
+                    && exchange.path == path
+                    && exchange
+                        .payload
+                        .as_deref()
+                        .map(|payload| relative_years(payload, exchange.year.unwrap_or(year)))
+                        == payload
+                    && exchange.filter == filter
+            })
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+
+        let mut replayed = lock(&self.inner.replayed);
+        let index = candidates
+            .iter()
+            .find(|index| replayed[**index] == 0)
+            .or_else(|| candidates.last())
+            .copied()?;
+        replayed[index] += 1;
+        Some(index)
+    }
+
+    fn respond(&self, method: &Method, uri: &Uri, headers: &HeaderMap) -> Response {
+        let path = uri
+            .path_and_query()
+            .map_or_else(|| uri.path().to_owned(), ToString::to_string);
+        let Some(index) = self.find(method, &path, headers) else {
+            return (
+                StatusCode::NOT_FOUND,
+                format!("No fixture for {method} {path}"),
+            )
+                .into_response();
+        };
+        let (exchange, body) = &self.inner.exchanges[index];
+
+        // Redirect first, the body is replayed at the path the request was redirected to
+        if let Some(redirected_to) = &exchange.redirected_to {
+            if *redirected_to != path {
+                lock(&self.inner.redirects).insert(redirected_to.clone(), index);
+                return (
+                    StatusCode::FOUND,
+                    [(header::LOCATION, redirected_to.clone())],
+                )
+                    .into_response();
+            }
+        }
+
+        let mut response = http::Response::builder()
+            .status(StatusCode::from_u16(exchange.status).unwrap_or(StatusCode::OK));
+        if let Some(content_type) = &exchange.content_type {
+            response = response.header(header::CONTENT_TYPE, content_type);
+        }
+        for name in &exchange.set_cookies {
+            response = response.header(header::SET_COOKIE, format!("{name}={SCRUBBED}; Path=/"));
+        }
+        response
+            .body(axum::body::boxed(axum::body::Full::from(body.clone())))
+            .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+    }
+}
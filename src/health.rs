@@ -0,0 +1,245 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    login::CookieStore,
+    sink::Sink,
+    summary::{Status, Summary},
+};
+
+/// How long the result of checking the session is reused, so probes don't hit the portal every time
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The result of a single readiness check
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+
+    /// Why the check failed, or what was checked
+    pub message: String,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<String, String>) -> Self {
+        let ok = result.is_ok();
+        Self {
+            name,
+            ok,
+            message: result.unwrap_or_else(|message| message),
+        }
+    }
+}
+
+/// The response of `/readyz`
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+/// The last run and the last run that saved reports
+#[derive(Debug, Clone, Copy, Default)]
+struct Runs {
+    last: Option<(DateTime<Utc>, Status)>,
+    last_success: Option<DateTime<Utc>>,
+}
+
+/// The latest session and whether it has been logged in
+#[derive(Debug, Clone)]
+struct Session {
+    cookie_store: CookieStore,
+
+    /// The number of the session, every session that's set gets the next number
+    number: u64,
+    logged_in: bool,
+}
+
+/// The result of checking a session, it's reused until it's too old or the session is replaced
+#[derive(Debug, Clone)]
+struct SessionCheck {
+    checked_at: Instant,
+    session: u64,
+    result: Result<String, String>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    started_at: DateTime<Utc>,
+    max_age: Option<chrono::Duration>,
+    sink: Arc<dyn Sink>,
+    session: Mutex<Option<Session>>,
+
+    /// The number of sessions that have been set
+    sessions: AtomicU64,
+    session_check: tokio::sync::Mutex<Option<SessionCheck>>,
+    runs: Mutex<Runs>,
+}
+
+/// The state that determines whether the application is ready, shared by the runs and the probes.
+/// Cloning is cheap, every clone shares the same state.
+#[derive(Debug, Clone)]
+pub struct Health {
+    inner: Arc<Inner>,
+}
+
+/// Locks the mutex, a panic while it was locked doesn't leave the state invalid
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+impl Health {
+    /// Creates the state for the sink.
+    /// If `max_age` is set, the last successful run, or the start if nothing ran yet, can't be older than it.
+    #[must_use]
+    pub fn new(sink: Arc<dyn Sink>, max_age: Option<chrono::Duration>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                started_at: Utc::now(),
+                max_age,
+                sink,
+                session: Mutex::new(None),
+                sessions: AtomicU64::new(0),
+                session_check: tokio::sync::Mutex::new(None),
+                runs: Mutex::new(Runs::default()),
+            }),
+        }
+    }
+
+    /// Sets the session that's checked, the latest session is used
+    pub fn set_session(&self, cookie_store: CookieStore) {
+        *lock(&self.inner.session) = Some(Session {
+            cookie_store,
+            number: self.inner.sessions.fetch_add(1, Ordering::Relaxed) + 1,
+            logged_in: false,
+        });
+    }
+
+    /// Records a finished run, a run counts as successful if it didn't fail completely
+    pub fn record_run(&self, summary: &Summary) {
+        let mut runs = lock(&self.inner.runs);
+        runs.last = Some((summary.finished_at, summary.status));
+        if matches!(summary.status, Status::Ok | Status::Partial) {
+            runs.last_success = Some(summary.finished_at);
+        }
+    }
+
+    /// Checks whether the latest session has been logged in, without logging in again.
+    /// A session that expired after it was logged in is fine, it logs in again before the next request.
+    async fn check_session(&self) -> Result<String, String> {
+        let mut check = self.inner.session_check.lock().await;
+        let Some(session) = lock(&self.inner.session).clone() else {
+            return Err("Not logged in yet".to_owned());
+        };
+        if let Some(check) = &*check {
+            if check.session == session.number
+                && check.checked_at.elapsed() < SESSION_CHECK_INTERVAL
+            {
+                return check.result.clone();
+            }
+        }
+
+        // The errors are converted to strings because they aren't Send
+        let result = match session.cookie_store.is_logged_in().await {
+            Err(e) => Err(format!("Failed to reach the portal: {e}")),
+            Ok(true) => {
+                if let Some(latest) = lock(&self.inner.session)
+                    .as_mut()
+                    .filter(|latest| latest.number == session.number)
+                {
+                    latest.logged_in = true;
+                }
+                Ok("Logged in".to_owned())
+            }
+            Ok(false) if session.logged_in => {
+                Ok("The session expired, it logs in again before the next request".to_owned())
+            }
+            Ok(false) => Err("Logging in didn't work".to_owned()),
+        };
+        *check = Some(SessionCheck {
+            checked_at: Instant::now(),
+            session: session.number,
+            result: result.clone(),
+        });
+        result
+    }
+
+    /// Checks whether the last successful run is recent enough
+    fn check_runs(&self, max_age: chrono::Duration) -> Result<String, String> {
+        let runs = *lock(&self.inner.runs);
+        let since = runs.last_success.unwrap_or(self.inner.started_at);
+        let last = runs.last.map_or_else(
+            || "nothing ran yet".to_owned(),
+            |(finished_at, status)| {
+                format!(
+                    "the last run finished at {finished_at} with {}",
+                    status.name()
+                )
+            },
+        );
+        if Utc::now() - since <= max_age {
+            Ok(format!("The last success was at {since}, {last}"))
+        } else {
+            Err(format!(
+                "Nothing succeeded since {since}, longer than {} minutes ago, {last}",
+                max_age.num_minutes()
+            ))
+        }
+    }
+
+    /// Checks the session, the last run and the sink
+    pub async fn readiness(&self) -> Readiness {
+        let mut checks = vec![Check::new("session", self.check_session().await)];
+        if let Some(max_age) = self.inner.max_age {
+            checks.push(Check::new("last_run", self.check_runs(max_age)));
+        }
+        checks.push(Check::new(
+            "sink",
+            self.inner
+                .sink
+                .check()
+                .await
+                .map(|()| "Writable".to_owned())
+                .map_err(|e| e.to_string()),
+        ));
+        Readiness {
+            ready: checks.iter().all(|check| check.ok),
+            checks,
+        }
+    }
+}
+
+/// `/healthz`, the process is alive as long as it responds
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// `/readyz`, responds with 503 if one of the checks failed
+async fn readyz(State(health): State<Health>) -> (StatusCode, Json<Readiness>) {
+    let readiness = health.readiness().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+/// Returns the routes of the probes at `/healthz` and `/readyz`
+pub fn router(health: Health) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(health)
+}
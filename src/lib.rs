@@ -1,6 +1,21 @@
 #![warn(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 
+pub mod api;
+pub mod config;
+pub mod connections;
+pub mod convert;
+pub mod database;
 pub mod ean;
+pub mod fixtures;
+pub mod health;
 pub mod id;
 pub mod login;
+pub mod metrics;
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod report;
+pub mod request;
+pub mod schedule;
+pub mod sink;
+pub mod summary;
+pub mod usage;
@@ -1,8 +1,10 @@
 use std::{str::Utf8Error, sync::Arc};
 
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder, Response};
 use scraper::error::SelectorErrorKind;
 
+use crate::fixtures::Recorder;
+
 /// Errors that can happen during log in
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -19,21 +21,44 @@ impl std::fmt::Display for Error {
     }
 }
 
+impl Error {
+    /// Returns the name of the variant, to group errors by their kind
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::FailedRequest(_) => "FailedRequest",
+            Self::MissingVerificationToken => "MissingVerificationToken",
+            Self::TokenHasNoValue => "TokenHasNoValue",
+            Self::Utf8(_) => "Utf8",
+            Self::InvalidTokenSelector(_) => "InvalidTokenSelector",
+        }
+    }
+}
+
+/// The address of the DB Energie portal
+pub const DEFAULT_BASE_URL: &str = "https://www.dbenergie.nl";
+
 #[derive(Debug, Clone)]
 pub struct CookieStore {
     client: Client,
+
+    /// The address of the portal without a trailing slash, like [`DEFAULT_BASE_URL`]
+    base_url: String,
     jar: Arc<reqwest::cookie::Jar>,
     mail: String,
     password: String,
+    customers: Vec<u32>,
+
+    /// Records every request and response as fixtures, if set
+    recorder: Option<Recorder>,
 }
 
 impl CookieStore {
     /// Retrieve the verification token from the website
-    async fn get_verification_token(client: &Client) -> Result<String, Error> {
+    async fn get_verification_token(&self) -> Result<String, Error> {
         // Get the login page
-        let response = client
-            .get("https://www.dbenergie.nl/Authorization/Login/Default")
-            .send()
+        let response = self
+            .send(self.client.get(self.url("/Authorization/Login/Default")))
             .await?;
 
         // Read the body
@@ -64,14 +89,43 @@ impl CookieStore {
     /// # Errors
     /// Returns an error if the verification token couldn't be retrieved or the login form couldn't be send.
     pub async fn login(mail: String, password: String) -> Result<Self, Error> {
+        Self::login_at(DEFAULT_BASE_URL, mail, password).await
+    }
+
+    /// Logs in to the portal at the base url, like a mock of the portal in tests.
+    ///
+    /// # Errors
+    /// Returns an error if the verification token couldn't be retrieved or the login form couldn't be send.
+    pub async fn login_at(base_url: &str, mail: String, password: String) -> Result<Self, Error> {
+        Self::login_with(base_url, mail, password, None).await
+    }
+
+    /// Logs in to the portal at the base url and records every request with the recorder, if it's set.
+    /// The credentials are kept out of the recorded fixtures.
+    ///
+    /// # Errors
+    /// Returns an error if the verification token couldn't be retrieved or the login form couldn't be send.
+    pub async fn login_with(
+        base_url: &str,
+        mail: String,
+        password: String,
+        recorder: Option<Recorder>,
+    ) -> Result<Self, Error> {
+        if let Some(recorder) = &recorder {
+            recorder.add_secret(&mail);
+            recorder.add_secret(&password);
+        }
         let jar = Arc::new(reqwest::cookie::Jar::default());
         let client = Client::builder().cookie_provider(jar.clone()).build()?;
 
         let client = Self {
             client,
+            base_url: base_url.trim_end_matches('/').to_owned(),
             jar,
             mail,
             password,
+            customers: vec![50],
+            recorder,
         };
         client.inner_login().await?;
         Ok(client)
@@ -84,23 +138,71 @@ impl CookieStore {
             ("user[passWord]", &self.password),
             (
                 "__RequestVerificationToken",
-                &Self::get_verification_token(&self.client).await?,
+                &self.get_verification_token().await?,
             ),
         ];
 
         // Send it to the server to retrieve the cookies
-        self.client
-            .post("https://www.dbenergie.nl/Home/Login")
-            .form(&login_data)
-            .send()
+        self.send(self.client.post(self.url("/Home/Login")).form(&login_data))
             .await?;
         Ok(())
     }
 
+    /// Checks whether the session is still logged in.
+    /// The portal redirects to the login page when it isn't.
+    ///
+    /// # Errors
+    /// Returns an error if the portal couldn't be reached
+    pub async fn is_logged_in(&self) -> Result<bool, Error> {
+        let response = self
+            .send(self.client.get(self.url("/Connections/List/Index")))
+            .await?
+            .error_for_status()?;
+        Ok(!response.url().path().starts_with("/Authorization/Login"))
+    }
+
     pub async fn redo_login(&self) -> Result<(), Error> {
         self.inner_login().await
     }
 
+    /// Sets the customers whose reports are requested, the default is customer 50
+    #[must_use]
+    pub fn with_customers(mut self, customers: Vec<u32>) -> Self {
+        self.customers = customers;
+        self
+    }
+
+    /// Returns the customers whose reports are requested
+    #[must_use]
+    pub fn customers(&self) -> &[u32] {
+        &self.customers
+    }
+
+    /// Returns the url of the path on the portal, the path starts with a slash
+    #[must_use]
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base_url)
+    }
+
+    /// Sends the request, every request to the portal should be sent with this or [`Self::send`] so it can be recorded
+    ///
+    /// # Errors
+    /// Returns an error if the request failed
+    pub async fn execute(&self, request: reqwest::Request) -> Result<Response, reqwest::Error> {
+        match &self.recorder {
+            Some(recorder) => recorder.execute(&self.client, &self.jar, request).await,
+            None => self.client.execute(request).await,
+        }
+    }
+
+    /// Builds and sends the request, see [`Self::execute`]
+    ///
+    /// # Errors
+    /// Returns an error if the request couldn't be built or failed
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, reqwest::Error> {
+        self.execute(request.build()?).await
+    }
+
     #[allow(clippy::must_use_candidate)]
     pub const fn client(&self) -> &Client {
         &self.client
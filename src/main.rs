@@ -1,37 +1,518 @@
-use std::{fmt::Display, io, path::PathBuf, str::FromStr as _, time::Duration};
+use std::{
+    fmt::Display,
+    io::{self, IsTerminal},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use calamine::{Reader, Xlsx};
-use chrono::Datelike;
-use clap::Parser;
+use chrono::{DateTime, Local, Utc};
+use clap::{
+    parser::ValueSource, ArgMatches, Args as ClapArgs, CommandFactory, FromArgMatches, Parser,
+    Subcommand, ValueEnum,
+};
+use futures_util::{stream, Stream, StreamExt};
 use rapportage_downloader::{
-    ean::Ean,
+    api::{self, Api},
+    config::{Account, Config, Filters, ReportConfig},
+    connections::{Connections, PersonalFilter},
+    convert::Format,
+    ean::{self, Ean},
+    fixtures::Recorder,
+    health::{self, Health},
     id::Id,
     login::CookieStore,
-    report::{self, Report},
-};
-use reqwest::Client;
-use tokio::{
-    fs::{self, File},
-    io::AsyncWriteExt as _,
-    join,
-    sync::mpsc,
+    metrics::{self, Metrics, Operation},
+    report::{self, Polling, Progress, Report},
+    request::{self, ReportRequest},
+    schedule::{Schedule, State},
+    sink::{self, Auth, Content, Document, Manifest, Problem, Saved, Sink, Template},
+    summary::{AccountSummary, Counts, Failure, Outcome, ReportSummary, Stage, Status, Summary},
 };
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
 
 const MINIMUM_DURATION: Duration = Duration::from_millis(1);
 
+/// The number of times a report is requested before it counts as failed
+const ATTEMPTS: u32 = 5;
+
+/// The delay before requesting a report again, it doubles for every next attempt
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay before retrieving an id again
+const MAXIMUM_ID_DELAY: Duration = Duration::from_secs(60);
+
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
-    /// The email to use to login at DB Energie
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// A toml file that describes the accounts, reports, filters and outputs.
+    /// The other arguments override the values in the file.
     #[arg(short, long)]
-    mail: String,
+    config: Option<PathBuf>,
+
+    /// The email to use to login at DB Energie
+    #[arg(short, long, required_unless_present = "config", requires = "password")]
+    mail: Option<String>,
 
     /// The password to use to login at DB Energie
-    #[arg(short, long)]
-    password: String,
+    #[arg(short, long, required_unless_present = "config", requires = "mail")]
+    password: Option<String>,
+
+    /// The customer to download the reports of, can be passed multiple times
+    #[arg(long)]
+    customer: Vec<u32>,
+
+    /// Where to save the reports: a directory path or file:// url, a http(s):// url to send them to,
+    /// a sqlite:// url of a database to store the usage records in or - for the standard output.
+    /// Can be passed multiple times to save every report to all outputs.
+    #[arg(short, long, required_unless_present = "config")]
+    output: Vec<String>,
+
+    /// Don't keep a manifest.jsonl with the hashes of saved files in directory outputs.
+    /// Without a manifest, reports are written again even if their content didn't change.
+    #[arg(long)]
+    no_manifest: bool,
+
+    /// The maximum number of seconds to wait for the portal to generate a report
+    #[arg(long, default_value_t = 600)]
+    report_deadline: u64,
+
+    /// The address of the portal, like the address of the mock_portal example to run without the real portal
+    #[arg(long, env = "PORTAL_URL")]
+    portal_url: Option<String>,
+
+    /// The number of meters whose reports are downloaded at the same time
+    #[arg(long, default_value_t = 1)]
+    concurrency: usize,
+
+    /// Keep running and download the reports on their schedules, instead of downloading them once
+    #[arg(long)]
+    daemon: bool,
+
+    /// The cron expression of reports without their own schedule, like "0 2 * * *" or @daily
+    #[arg(long)]
+    schedule: Option<Schedule>,
+
+    /// The file that contains the time of the last run of every schedule, to catch up on missed runs
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// The json file the summary of the last run is written to
+    #[arg(long)]
+    summary_file: Option<PathBuf>,
+
+    /// Record every request to the portal and its response as fixtures in the directory, without credentials
+    #[arg(long, conflicts_with_all = ["daemon", "serve"])]
+    record: Option<PathBuf>,
+
+    /// Print every request a run would send and where the reports would be saved, without contacting the portal
+    #[arg(long, conflicts_with_all = ["daemon", "serve", "record"])]
+    dry_run: bool,
+
+    /// The format to convert the reports to before saving them: xlsx, csv, jsonl or parquet
+    #[arg(short, long, default_value_t = Format::Xlsx)]
+    format: Format,
+
+    /// The path of saved reports in directories and object storage, like {report}/{year}/{month}/{ean}_{start}_{end}.{ext}.
+    /// Available placeholders: {report}, {ean}, {id}, {start}, {end}, {year}, {month}, {day}, {downloaded}, {filename} and {ext}
+    #[arg(long)]
+    output_template: Option<Template>,
+
+    /// The bearer token to send reports to a http(s) output with
+    #[arg(long)]
+    upload_token: Option<String>,
+
+    /// The username and optional password to send reports to a http(s) output with, as USER[:PASSWORD]
+    #[arg(long, conflicts_with = "upload_token")]
+    upload_user: Option<String>,
+
+    /// The name of the form field that contains the report
+    #[arg(long, default_value = "file")]
+    upload_file_field: String,
+
+    /// An extra form field to send with every report, as NAME=VALUE
+    #[arg(long, value_parser = parse_form_field)]
+    upload_form_field: Vec<(String, String)>,
+
+    /// The number of times a failed upload is retried
+    #[arg(long, default_value_t = 3)]
+    upload_retries: u32,
+
+    /// The url of the S3-compatible object storage for s3:// outputs
+    #[arg(long, env = "S3_ENDPOINT", default_value = "https://s3.amazonaws.com")]
+    s3_endpoint: String,
+
+    /// The region of the object storage
+    #[arg(long, env = "AWS_REGION", default_value = "us-east-1")]
+    s3_region: String,
+
+    /// The access key of the object storage
+    #[arg(
+        long,
+        env = "AWS_ACCESS_KEY_ID",
+        default_value = "",
+        hide_env_values = true
+    )]
+    s3_access_key: String,
+
+    /// The secret key of the object storage
+    #[arg(
+        long,
+        env = "AWS_SECRET_ACCESS_KEY",
+        default_value = "",
+        hide_env_values = true
+    )]
+    s3_secret_key: String,
+
+    /// The session token of temporary credentials for the object storage
+    #[arg(long, env = "AWS_SESSION_TOKEN", hide_env_values = true)]
+    s3_session_token: Option<String>,
+
+    /// Put the bucket in the path instead of the host name, which most self-hosted object storage requires
+    #[arg(long)]
+    s3_path_style: bool,
+
+    /// Reports larger than this number of MiB are uploaded to the object storage in parts
+    #[arg(long, default_value_t = 8)]
+    s3_part_size: usize,
+
+    /// The address to serve the Prometheus metrics at /metrics on, like 0.0.0.0:9090.
+    /// With --serve, the http api is served at this address as well, and with --daemon or --serve the probes at /healthz and /readyz.
+    #[arg(long, env = "LISTEN")]
+    listen: Option<SocketAddr>,
+
+    /// Keep running and download reports when they're requested through the http api, instead of downloading them once
+    #[arg(long, requires = "listen", conflicts_with = "daemon")]
+    serve: bool,
+
+    /// The token every request to the http api needs as Authorization: Bearer header
+    #[arg(long, env = "API_TOKEN", requires = "serve", hide_env_values = true)]
+    api_token: Option<String>,
+
+    /// In daemon mode, the maximum number of seconds since the last successful run before /readyz reports failure
+    #[arg(long, default_value_t = 90000)]
+    ready_max_age: u64,
+
+    /// How the log lines on the standard error are written
+    #[arg(long, value_enum, env = "LOG_FORMAT", default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// The minimum level of the logs, like info or debug, or filter directives like rapportage_downloader=debug,reqwest=info
+    #[arg(long, env = "LOG_LEVEL", default_value = "info")]
+    log_level: String,
+}
+
+/// The format of the log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+
+    /// A json object per line, with the fields of the event and its spans
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks the files in a directory against its manifest.jsonl
+    Verify {
+        /// The directory the reports were saved in
+        directory: PathBuf,
+    },
+
+    /// Decodes the base64 `request` header of the portal, or an url encoded cookie, and prints its json
+    Decode {
+        /// The value of the header or cookie
+        value: String,
 
-    /// The directory path or url to write the received message to
+        /// The value is an url encoded cookie, like PersonalFilter
+        #[arg(long)]
+        cookie: bool,
+    },
+
+    /// Encodes the json of a report request as the base64 `request` header of the portal, or as a cookie
+    Encode {
+        /// The json of the request or cookie
+        json: String,
+
+        /// Url encode the json as a cookie, like PersonalFilter
+        #[arg(long)]
+        cookie: bool,
+    },
+
+    /// Searches the connection list of the portal and prints the id and ean of every connection found
+    Search(Box<SearchArgs>),
+}
+
+/// The account to search with and the filters of the connection list
+#[derive(ClapArgs)]
+struct SearchArgs {
+    /// A toml file with the accounts to search the connections of
     #[arg(short, long)]
-    output: String,
+    config: Option<PathBuf>,
+
+    /// The email to use to login at DB Energie
+    #[arg(short, long, required_unless_present = "config", requires = "password")]
+    mail: Option<String>,
+
+    /// The password to use to login at DB Energie
+    #[arg(short, long, required_unless_present = "config", requires = "mail")]
+    password: Option<String>,
+
+    /// The customer to search the connections of, can be passed multiple times
+    #[arg(long)]
+    customer: Vec<u32>,
+
+    /// The address of the portal
+    #[arg(long, env = "PORTAL_URL")]
+    portal_url: Option<String>,
+
+    /// Part of the ean
+    #[arg(long)]
+    ean: Option<String>,
+
+    /// Part of the meter number
+    #[arg(long)]
+    meter_number: Option<String>,
+
+    /// The id of the label
+    #[arg(long)]
+    label: Option<u32>,
+
+    /// The id of the classification
+    #[arg(long)]
+    classification: Option<u32>,
+
+    /// The id of the cost place
+    #[arg(long)]
+    costplace: Option<u32>,
+
+    /// The id of the energy taxation cluster
+    #[arg(long)]
+    tax_cluster: Option<u32>,
+
+    /// The id of the connection type
+    #[arg(long)]
+    connection_type: Option<u32>,
+
+    /// The id of a status, can be passed multiple times
+    #[arg(long)]
+    status: Vec<u32>,
+
+    /// The id of a department, can be passed multiple times
+    #[arg(long)]
+    department: Vec<u32>,
+
+    /// Also find the connections whose meter is deleted
+    #[arg(long)]
+    deleted: bool,
+
+    /// Print a json object per connection instead of tab separated columns
+    #[arg(long)]
+    json: bool,
+}
+
+impl SearchArgs {
+    /// Returns the filter of the connection list for the customers
+    fn filter(&self, customers: &[u32]) -> PersonalFilter {
+        let mut filter = PersonalFilter::new(customers)
+            .with_statuses(self.status.clone())
+            .with_departments(self.department.clone())
+            .with_deleted(self.deleted);
+        if let Some(ean) = &self.ean {
+            filter = filter.with_ean_search(ean);
+        }
+        if let Some(meter_number) = &self.meter_number {
+            filter = filter.with_meter_number(meter_number);
+        }
+        filter
+            .with_label(self.label.unwrap_or_default())
+            .with_classification(self.classification.unwrap_or_default())
+            .with_costplace(self.costplace.unwrap_or_default())
+            .with_energytaxationcluster(self.tax_cluster.unwrap_or_default())
+            .with_connection_type(self.connection_type.unwrap_or_default())
+    }
+
+    /// Returns the accounts of the config file, or the account of the arguments
+    fn accounts(&self) -> Result<(Vec<Account>, String), rapportage_downloader::config::Error> {
+        let config = self
+            .config
+            .as_deref()
+            .map_or_else(|| Ok(Config::default()), Config::load)?;
+        let mut accounts = config.accounts;
+        if let (Some(mail), Some(password)) = (&self.mail, &self.password) {
+            accounts = vec![Account::new(mail.clone(), password.clone())];
+        }
+        if !self.customer.is_empty() {
+            for account in &mut accounts {
+                account.customers = self.customer.clone();
+            }
+        }
+        let portal_url = self.portal_url.clone().unwrap_or(config.portal_url);
+        Ok((accounts, portal_url))
+    }
+}
+
+/// Parses a form field passed as NAME=VALUE
+fn parse_form_field(field: &str) -> Result<(String, String), String> {
+    field
+        .split_once('=')
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .ok_or_else(|| format!("Expected NAME=VALUE, received {field}"))
+}
+
+/// Writes the logs to the standard error, the standard output is kept free for reports
+fn init_logging(format: LogFormat, level: &str) -> Result<(), String> {
+    let filter =
+        EnvFilter::try_new(level).map_err(|e| format!("Invalid log level {level}: {e}"))?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .init(),
+    }
+    Ok(())
+}
+
+/// Checks whether the argument was passed on the command line or in the environment, instead of using its default
+fn explicit(matches: &ArgMatches, id: &str) -> bool {
+    matches
+        .value_source(id)
+        .is_some_and(|source| source != ValueSource::DefaultValue)
+}
+
+impl Args {
+    /// Reads the config file, replaces its values by the arguments that were passed and validates the result
+    fn load_config(
+        &self,
+        matches: &ArgMatches,
+    ) -> Result<Config, rapportage_downloader::config::Error> {
+        let mut config = self
+            .config
+            .as_deref()
+            .map_or_else(|| Ok(Config::default()), Config::load)?;
+        self.override_config(matches, &mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Replaces the values in the config by the arguments that were passed
+    fn override_config(&self, matches: &ArgMatches, config: &mut Config) {
+        if let (Some(mail), Some(password)) = (&self.mail, &self.password) {
+            let customers = config
+                .accounts
+                .first()
+                .map(|account| account.customers.clone());
+            let mut account = Account::new(mail.clone(), password.clone());
+            account.customers = customers.unwrap_or(account.customers);
+            config.accounts = vec![account];
+        }
+        if !self.customer.is_empty() {
+            for account in &mut config.accounts {
+                account.customers = self.customer.clone();
+            }
+        }
+        if !self.output.is_empty() {
+            config.sinks.outputs = self.output.clone();
+        }
+        if self.no_manifest {
+            config.sinks.manifest = false;
+        }
+        if explicit(matches, "report_deadline") {
+            config.report_deadline = self.report_deadline;
+        }
+        if explicit(matches, "concurrency") {
+            config.concurrency = self.concurrency;
+        }
+        if explicit(matches, "ready_max_age") {
+            config.ready_max_age = self.ready_max_age;
+        }
+        if let Some(schedule) = &self.schedule {
+            config.schedule = Some(schedule.clone());
+        }
+        if let Some(state_file) = &self.state_file {
+            config.state_file = state_file.clone();
+        }
+        if let Some(portal_url) = &self.portal_url {
+            config.portal_url = portal_url.clone();
+        }
+        if let Some(summary_file) = &self.summary_file {
+            config.summary_file = summary_file.clone();
+        }
+        if explicit(matches, "format") {
+            config.sinks.format = self.format;
+        }
+        if let Some(template) = &self.output_template {
+            config.sinks.template = Some(template.clone());
+        }
+
+        // Settings for http(s) outputs
+        let upload = &mut config.sinks.upload;
+        if explicit(matches, "upload_file_field") {
+            upload.file_field = self.upload_file_field.clone();
+        }
+        if !self.upload_form_field.is_empty() {
+            upload.form_fields = self.upload_form_field.iter().cloned().collect();
+        }
+        if explicit(matches, "upload_retries") {
+            upload.retries = self.upload_retries;
+        }
+
+        // Settings for s3 outputs
+        let s3 = &mut config.sinks.s3;
+        if explicit(matches, "s3_endpoint") {
+            s3.endpoint = self.s3_endpoint.clone();
+        }
+        if explicit(matches, "s3_region") {
+            s3.region = self.s3_region.clone();
+        }
+        if self.s3_path_style {
+            s3.path_style = true;
+        }
+        if explicit(matches, "s3_part_size") {
+            s3.part_size = self.s3_part_size;
+        }
+    }
+
+    /// Adds the credentials of the outputs, which aren't part of the config file
+    fn override_options(&self, options: &mut sink::Options) {
+        // Empty credentials are ignored, so they can be left empty in the environment
+        match (&self.upload_token, &self.upload_user) {
+            (Some(token), _) if !token.is_empty() => {
+                options.http.auth = Some(Auth::Bearer(token.clone()));
+            }
+            (_, Some(user)) if !user.is_empty() => {
+                options.http.auth = Some(match user.split_once(':') {
+                    Some((username, password)) => Auth::Basic {
+                        username: username.to_owned(),
+                        password: Some(password.to_owned()),
+                    },
+                    None => Auth::Basic {
+                        username: user.clone(),
+                        password: None,
+                    },
+                });
+            }
+            _ => {}
+        }
+        options.s3.access_key = self.s3_access_key.clone();
+        options.s3.secret_key = self.s3_secret_key.clone();
+        options.s3.session_token = self.s3_session_token.clone();
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -39,9 +520,7 @@ enum MainError {
     Request(#[from] reqwest::Error),
     Io(#[from] io::Error),
     Report(#[from] report::Error),
-    Xlsx(#[from] calamine::XlsxError),
-    #[allow(dead_code)]
-    ValueMissing(&'static str),
+    Ean(#[from] ean::Error),
 }
 
 impl Display for MainError {
@@ -50,257 +529,1375 @@ impl Display for MainError {
     }
 }
 
-/// Saves the data to a server or file
-async fn save(
-    client: &Client,
-    output: &str,
-    data: Vec<u8>,
-    filename: String,
-) -> Result<(), MainError> {
-    if let Ok(url) = url::Url::from_str(output) {
-        // Store the report in a form
-        let form = reqwest::multipart::Form::new().part(
-            "file",
-            reqwest::multipart::Part::bytes(data).file_name(filename),
-        );
-
-        // Send the file to the requested url
-        client.post(url).multipart(form).send().await?;
-    } else if let Ok(directory) = PathBuf::from_str(output) {
-        // Create the requested directory
-        fs::create_dir_all(&directory).await?;
-
-        // Create the file
-        let mut file = File::create(directory.join(filename)).await?;
-
-        // Write the report to the file
-        file.write_all(&data).await?;
-    } else {
-        // The output should be a url or filepath, so panic if it is neither
-        panic!("Passed output isn't a valid url or filepath");
+impl MainError {
+    /// Returns the name of the variant, or the kind of the report error
+    const fn kind(&self) -> &'static str {
+        match self {
+            Self::Request(_) => "Request",
+            Self::Io(_) => "Io",
+            Self::Report(e) => e.kind(),
+            Self::Ean(_) => "Ean",
+        }
     }
-    Ok(())
 }
 
-async fn read_eans(cookie_store: &CookieStore) -> Result<Vec<Ean>, MainError> {
-    // Download the latest aansluitingen report
-    let (file_name, aansluitingen) = Report::Aansluitinglijst
-        .download_latest_version(cookie_store)
+/// Reads the eans of the connections, returns the eans that pass the filters and the eans that don't
+async fn read_eans(
+    cookie_store: &CookieStore,
+    polling: &Polling,
+    filters: &Filters,
+) -> Result<(Vec<Ean>, Vec<Ean>), MainError> {
+    // Stream the latest aansluitingen report to a temporary file, it's removed once the eans are read
+    let (_, temporary_file, _) = Report::Aansluitinglijst
+        .download_latest_version_polling_to(cookie_store, polling, &std::env::temp_dir(), |_| {})
         .await?;
 
-    // Save the report
-    let mut file = File::create(&file_name).await?;
-    file.write_all(&aansluitingen).await?;
-    file.flush().await?;
-
-    // Open the list
-    let mut workbook: Xlsx<_> = calamine::open_workbook(file_name)?;
-    let range = workbook.worksheet_range("Lijst_Export")?;
-    let mut rows = range.rows();
-
-    // Take the first row, only store the ean code and status
-    let columns = rows
-        .next()
-        .ok_or(MainError::ValueMissing("Empty worksheet"))?
-        .iter()
-        .enumerate()
-        .filter_map(|(index, column)| {
-            let column = column.to_string();
-            if ["EAN code", "Status"].contains(&column.trim()) {
-                Some((index, column))
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
-
-    // Take the ean code of every active row
-    Ok(rows
-        .filter_map(|row| {
-            let mut ean = None;
-            for (index, column) in &columns {
-                let value = &row[*index];
-                match column.trim() {
-                    "EAN code" => ean = Some(Ean::from(value.to_string())),
-                    "Status" if value.to_string().trim() != "Actief" => return None,
-                    _ => {}
-                }
-            }
-            ean.filter(|ean| !ean.value().trim().is_empty())
-        })
-        .collect())
+    // Read the connections and split them by whether they pass the filters
+    let file = std::fs::File::open(temporary_file.path())?;
+    let (eans, skipped) = ean::read_connections(io::BufReader::new(file))?
+        .into_iter()
+        .partition::<Vec<_>, _>(|connection| {
+            filters.matches(&connection.ean, connection.status.as_deref())
+        });
+    Ok((
+        eans.into_iter().map(|connection| connection.ean).collect(),
+        skipped
+            .into_iter()
+            .map(|connection| connection.ean)
+            .collect(),
+    ))
 }
 
 async fn load_ids<I: IntoIterator<Item = Ean> + std::marker::Send>(
     eans: I,
-    id_tx: mpsc::Sender<(Ean, Id)>,
+    id_tx: mpsc::Sender<(Ean, Result<Id, Failure>)>,
     cookie_store: CookieStore,
+    metrics: Metrics,
 ) {
     // Set an initial delay
     let mut sleep_time = MINIMUM_DURATION;
     for ean in eans {
-        loop {
-            // Wait before downloading
-            tokio::time::sleep(sleep_time).await;
-            eprintln!("Id download delay: {}", sleep_time.as_secs_f64());
-            sleep_time *= 2;
+        let meter_id = load_id(&cookie_store, &metrics, &ean, &mut sleep_time).await;
+
+        // Send the id for it to be downloaded, nothing is downloaded anymore once the receiver is gone
+        if id_tx.send((ean, meter_id)).await.is_err() {
+            return;
+        }
+
+        // Decrease delay
+        sleep_time = (sleep_time / 4).max(MINIMUM_DURATION);
+    }
+}
+
+/// Retrieves the id of the meter and checks it by retrieving the ean of the id.
+/// The delay before every attempt doubles up to [`MAXIMUM_ID_DELAY`].
+/// Returns the failure of the last attempt if the id wasn't retrieved after [`ATTEMPTS`] attempts.
+#[tracing::instrument(skip_all, fields(%ean))]
+async fn load_id(
+    cookie_store: &CookieStore,
+    metrics: &Metrics,
+    ean: &Ean,
+    sleep_time: &mut Duration,
+) -> Result<Id, Failure> {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        // Wait before downloading
+        tracing::debug!(
+            attempt,
+            delay_secs = sleep_time.as_secs_f64(),
+            "Waiting before loading the id"
+        );
+        metrics.backoff_delay(Operation::ResolveId, *sleep_time);
+        tokio::time::sleep(*sleep_time).await;
+        *sleep_time = (*sleep_time * 2).min(MAXIMUM_ID_DELAY);
 
+        let started_at = Instant::now();
+        let meter_id = async {
             // Retrieve the id of the meter
-            let meter_id = match Id::from_ean(&cookie_store, &ean).await {
-                Ok(id) => id,
-                Err(e) => {
-                    eprintln!("Failed to receive id.\n{e}\n");
-                    continue;
-                }
-            };
+            let meter_id = Id::from_ean(cookie_store, ean).await.map_err(|e| {
+                tracing::warn!(attempt, error = %e, "Failed to receive the id");
+                Failure::new(Stage::ResolveId, "Id", &e)
+            })?;
 
             // Retrieve the ean corresponding to received ID
-            let received_ean = match Ean::from_id(&cookie_store, meter_id).await {
-                Ok(ean) => ean,
-                Err(e) => {
-                    eprintln!("Failed to check ean and date range\n{e}\n");
-                    continue;
-                }
-            };
+            let received_ean = Ean::from_id(cookie_store, meter_id).await.map_err(|e| {
+                tracing::warn!(attempt, id = %meter_id, error = %e, "Failed to check the ean and date range");
+                Failure::new(Stage::ResolveId, "Ean", &e)
+            })?;
 
-            // If the received ean is not the same as the current ean, print an error and try again
-            if received_ean != ean {
-                eprintln!(
-                    "Received ean is not the same as requested ean!\nExpected: {ean}\nReceived: {received_ean}\nId: {meter_id}\n"
+            // If the received ean is not the same as the current ean, log an error and try again
+            if received_ean != *ean {
+                tracing::warn!(
+                    attempt,
+                    id = %meter_id,
+                    %received_ean,
+                    "Received ean is not the same as the requested ean"
                 );
-                continue;
+                return Err(Failure::new(
+                    Stage::ResolveId,
+                    "EanMismatch",
+                    &format!("The id {meter_id} belongs to {received_ean}"),
+                ));
             }
+            Ok(meter_id)
+        }
+        .await;
 
-            // Print the ean and id match
-            println!("{ean}: {meter_id}");
+        match meter_id {
+            Ok(meter_id) => {
+                // Log the ean and id match
+                metrics.id_attempt(true);
+                metrics.duration(Operation::ResolveId, started_at.elapsed());
+                tracing::info!(
+                    attempt,
+                    id = %meter_id,
+                    duration_secs = started_at.elapsed().as_secs_f64(),
+                    "Loaded the id"
+                );
+                return Ok(meter_id);
+            }
+            Err(failure) => {
+                metrics.id_attempt(false);
+                if attempt >= ATTEMPTS {
+                    tracing::error!(attempt, error = %failure.message, "Failed to load the id, skipping the meter");
+                    return Err(failure);
+                }
+            }
+        }
+    }
+}
+
+/// Everything that's shared by the downloads of all accounts
+struct Job<'a> {
+    config: &'a Config,
+    sink: &'a dyn Sink,
+    polling: Polling,
+    metrics: Metrics,
+
+    /// Whether the session, the last run and the sink are ready, for the probes
+    health: Health,
+
+    /// Records the requests of every account as fixtures, if they should be recorded
+    recorder: Option<Recorder>,
+
+    /// Cancelled when the application should stop, downloads in progress are aborted and no new ones are started
+    shutdown: CancellationToken,
+}
 
-            // Send the id for it to be downloaded
-            while id_tx.send((ean.clone(), meter_id)).await.is_err() {
-                tokio::time::sleep(sleep_time).await;
+impl Job<'_> {
+    /// Runs the future until it's done or the shutdown is requested
+    async fn until_shutdown<T>(&self, future: impl std::future::Future<Output = T>) -> Option<T> {
+        tokio::select! {
+            output = future => Some(output),
+            () = self.shutdown.cancelled() => None,
+        }
+    }
+}
+
+/// Downloads, validates and saves a report.
+/// A failed download is retried after logging in again, until it failed [`ATTEMPTS`] times.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        report = requested.name(),
+        ean = ean.map(tracing::field::display),
+        id = id.map(tracing::field::display),
+    )
+)]
+async fn download_report(
+    job: &Job<'_>,
+    cookie_store: &CookieStore,
+    requested: Report,
+    ean: Option<&Ean>,
+    id: Option<Id>,
+) -> ReportSummary {
+    let report = requested.clone();
+    let mut summary = ReportSummary::new(&report, ean, id);
+    let started_at = Instant::now();
+    let outcome = download_and_save(job, cookie_store, requested, ean, id, &mut summary).await;
+    summary.outcome = outcome;
+    summary.duration_secs = started_at.elapsed().as_secs_f64();
+    job.metrics.report(report.name(), summary.outcome.name());
+    if let (Outcome::Written | Outcome::Unchanged | Outcome::Skipped, Some(ean)) =
+        (summary.outcome, ean)
+    {
+        job.metrics.success(ean);
+    }
+    summary
+}
+
+/// Downloads and saves the report, the attempts, size and failure are added to the summary
+async fn download_and_save(
+    job: &Job<'_>,
+    cookie_store: &CookieStore,
+    requested: Report,
+    ean: Option<&Ean>,
+    id: Option<Id>,
+    summary: &mut ReportSummary,
+) -> Outcome {
+    // Download the report, make sure the portal returned a report instead of an error page
+    let started_at = Instant::now();
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    let download = async {
+        loop {
+            // The report is streamed to a temporary file instead of being kept in memory
+            let download = requested
+                .download_latest_version_polling_to(
+                    cookie_store,
+                    &job.polling,
+                    &std::env::temp_dir(),
+                    |progress| {
+                        if let Progress::Generating {
+                            attempt: poll,
+                            elapsed,
+                        } = progress
+                        {
+                            tracing::info!(
+                                attempt,
+                                poll,
+                                elapsed_secs = elapsed.as_secs(),
+                                "Waiting for the report to be generated"
+                            );
+                        }
+                    },
+                )
+                .await
+                .and_then(|(file_name, file, size)| {
+                    requested.validate_file(file.path())?;
+                    Ok((file_name, Content::file(file, size)))
+                });
+            match download {
+                Ok(download) => {
+                    job.metrics
+                        .backoff_delay(Operation::Download, Duration::ZERO);
+                    break Ok(download);
+                }
+                Err(e) if attempt >= ATTEMPTS => {
+                    tracing::error!(attempt, error = %e, "Failed to download the report");
+                    break Err(e);
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        attempt,
+                        retry_in_secs = delay.as_secs(),
+                        error = %e,
+                        "Failed to download the report, retrying"
+                    );
+                    let relogin_started_at = Instant::now();
+                    let relogin = cookie_store.redo_login().await;
+                    job.metrics.login(true, relogin.is_ok());
+                    job.metrics
+                        .duration(Operation::Relogin, relogin_started_at.elapsed());
+                    job.metrics.backoff_delay(Operation::Download, delay);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
             }
+        }
+    };
+
+    // Abort the download when the application stops, a download that's complete is still saved
+    let download = job.until_shutdown(download).await;
+    summary.attempts = attempt;
+    let (file_name, data) = match download {
+        Some(Ok(download)) => download,
+        Some(Err(e)) => {
+            summary.failure = Some(Failure::download(&e));
+            return Outcome::Failed;
+        }
+        None => {
+            tracing::warn!(attempt, "Cancelled the download");
+            return Outcome::Cancelled;
+        }
+    };
+    let size = data.len();
+    summary.bytes = size;
+    job.metrics
+        .duration(Operation::Download, started_at.elapsed());
+
+    // Save the report
+    let document = Document {
+        report: requested,
+        ean: ean.cloned(),
+        id,
+        file_name,
+        data,
+        downloaded_at: chrono::Utc::now(),
+    };
+    let saving_started_at = Instant::now();
+    let saved = job.sink.save(&document).await;
+    job.metrics
+        .duration(Operation::Save, saving_started_at.elapsed());
+    let duration_secs = started_at.elapsed().as_secs_f64();
+    match saved {
+        Ok(Saved::Written) => {
+            job.metrics.saved(&document.report, size);
+            tracing::info!(attempt, size, duration_secs, "Saved the report");
+            Outcome::Written
+        }
+        Ok(Saved::Unchanged) => {
+            tracing::info!(
+                attempt,
+                size,
+                duration_secs,
+                "Skipped the report, it didn't change"
+            );
+            Outcome::Unchanged
+        }
+        Ok(Saved::Skipped) => {
+            tracing::info!(
+                attempt,
+                size,
+                duration_secs,
+                "Skipped the report, none of the outputs stores it"
+            );
+            Outcome::Skipped
+        }
+        Err(e) => {
+            tracing::error!(attempt, error = %e, "Failed to save the report");
+            summary.failure = Some(Failure::save(&e));
+            Outcome::Failed
+        }
+    }
+}
+
+/// Downloads the reports without a meter once and the reports of every meter in the stream.
+/// The reports of [`Config::concurrency`] meters are downloaded at the same time.
+async fn download_round(
+    job: &Job<'_>,
+    cookie_store: &CookieStore,
+    reports: &[ReportConfig],
+    meters: impl Stream<Item = (Ean, Result<Id, Failure>)>,
+) -> Vec<ReportSummary> {
+    let today = chrono::Local::now().date_naive();
+    let mut summaries = Vec::new();
 
-            // Decrease delay
-            sleep_time = (sleep_time / 4).max(MINIMUM_DURATION);
-            break;
+    // Download the reports that cover every meter
+    for report in reports
+        .iter()
+        .filter(|report| !report.per_meter())
+        .filter_map(|report| report.to_report(None, today))
+    {
+        if job.shutdown.is_cancelled() {
+            let mut summary = ReportSummary::new(&report, None, None);
+            summary.outcome = Outcome::Cancelled;
+            summaries.push(summary);
+            continue;
         }
+        summaries.push(download_report(job, cookie_store, report, None, None).await);
+    }
+
+    // Download the reports of the meters, no new meters are started after the shutdown is requested.
+    // The reports of a meter whose id couldn't be retrieved fail with the failure of retrieving it.
+    let outcomes = meters
+        .take_until(job.shutdown.cancelled())
+        .flat_map(|(ean, id)| {
+            stream::iter(
+                reports
+                    .iter()
+                    .filter(|report| report.per_meter())
+                    .filter_map(move |report| match &id {
+                        Ok(id) => report
+                            .to_report(Some(*id), today)
+                            .map(|report| Ok((*id, report))),
+                        Err(failure) => Some(Err((report.report.clone(), failure.clone()))),
+                    })
+                    .map(move |report| (ean.clone(), report)),
+            )
+        })
+        .map(|(ean, report)| async move {
+            match report {
+                Ok((id, report)) => {
+                    download_report(job, cookie_store, report, Some(&ean), Some(id)).await
+                }
+                Err((report, failure)) => {
+                    job.metrics.report(&report, Outcome::Failed.name());
+                    ReportSummary::unresolved(&report, &ean, failure)
+                }
+            }
+        })
+        .buffer_unordered(job.config.concurrency);
+    let mut outcomes = std::pin::pin!(outcomes);
+    let mut counts = Counts::default();
+    for summary in &summaries {
+        counts.add(summary.outcome);
     }
+    while let Some(summary) = outcomes.next().await {
+        counts.add(summary.outcome);
+        tracing::debug!(%counts, "Progress");
+        summaries.push(summary);
+    }
+    summaries
 }
 
-fn receive_id<T>(rx: &mut mpsc::Receiver<T>, sender_closed: &mut bool) -> Option<T> {
-    // We're done receiving ids, if the sender is closed
-    if *sender_closed {
-        return None;
+/// Logs in to the account and downloads the reports once
+#[tracing::instrument(skip_all, fields(account = %account.mail))]
+async fn run_account(job: &Job<'_>, account: &Account, reports: &[ReportConfig]) -> AccountSummary {
+    let mut summary = AccountSummary::new(&account.mail);
+    let started_at = Instant::now();
+
+    // Log in to receive a cookie
+    tracing::info!("Logging in");
+    let login = CookieStore::login_with(
+        &job.config.portal_url,
+        account.mail.clone(),
+        account.password().unwrap_or_default(),
+        job.recorder.clone(),
+    );
+    let login = job.until_shutdown(login).await;
+    if let Some(login) = &login {
+        job.metrics.login(false, login.is_ok());
+        job.metrics.duration(Operation::Login, started_at.elapsed());
     }
+    let cookie_store = match login {
+        Some(Ok(cookie_store)) => cookie_store.with_customers(account.customers.clone()),
+        Some(Err(e)) => {
+            tracing::error!(error = %e, "Failed to log in");
+            summary
+                .failures
+                .push(Failure::new(Stage::Login, e.kind(), &e));
+            summary.duration_secs = started_at.elapsed().as_secs_f64();
+            return summary;
+        }
+        None => {
+            summary.cancelled = true;
+            return summary;
+        }
+    };
 
-    // Try to receive the next ID
-    match rx.try_recv() {
-        Ok(value) => Some(value),
-        Err(e) => match e {
-            mpsc::error::TryRecvError::Empty => None,
+    job.health.set_session(cookie_store.clone());
 
-            // Store that the sender has disconnected
-            mpsc::error::TryRecvError::Disconnected => {
-                *sender_closed = true;
-                None
+    // Read the eans, they're only needed for reports per meter
+    let eans = if reports.iter().any(ReportConfig::per_meter) {
+        tracing::info!("Reading eans");
+        match job
+            .until_shutdown(read_eans(&cookie_store, &job.polling, &job.config.filters))
+            .await
+        {
+            Some(Ok((eans, skipped))) => {
+                tracing::info!(eans = eans.len(), skipped = skipped.len(), "Read eans");
+                summary.eans = eans.len();
+                summary.skipped_meters = skipped.iter().map(ToString::to_string).collect();
+                eans
             }
-        },
+            Some(Err(e)) => {
+                tracing::error!(error = %e, "Failed to read the ean codes");
+                summary
+                    .failures
+                    .push(Failure::new(Stage::ReadEans, e.kind(), &e));
+                summary.duration_secs = started_at.elapsed().as_secs_f64();
+                return summary;
+            }
+            None => {
+                summary.cancelled = true;
+                return summary;
+            }
+        }
+    } else {
+        Vec::new()
+    };
+
+    // Download the ids and reports, the reports are downloaded while the ids are loaded.
+    // Loading the ids stops when the application stops, which ends the stream of meters.
+    tracing::info!("Loading ids and reports");
+    let (tx, rx) = mpsc::channel(10);
+    let id_loader = tokio::spawn(
+        {
+            let shutdown = job.shutdown.clone();
+            let cookie_store = cookie_store.clone();
+            let metrics = job.metrics.clone();
+            async move {
+                tokio::select! {
+                    () = load_ids(eans, tx, cookie_store, metrics) => {}
+                    () = shutdown.cancelled() => {}
+                }
+            }
+        }
+        .in_current_span(),
+    );
+    let mut ids_resolved = 0;
+    let meters = stream::unfold(rx, |mut rx| async {
+        rx.recv().await.map(|meter| (meter, rx))
+    })
+    .inspect(|(_, id)| ids_resolved += usize::from(id.is_ok()));
+    summary.reports = download_round(job, &cookie_store, reports, meters).await;
+    summary.ids_resolved = ids_resolved;
+    if let Err(e) = id_loader.await {
+        tracing::error!(error = %e, "Failed to load the ids");
     }
+    summary.duration_secs = started_at.elapsed().as_secs_f64();
+    summary
 }
 
-async fn download_reports(
-    mut ids: Vec<(Ean, Id)>,
-    cookie_store: &CookieStore,
-    output: &str,
-    mut rx: mpsc::Receiver<(Ean, Id)>,
-) {
-    // Initialize an index
-    let mut i = 0;
+/// Downloads the reports of every account once and writes the summary of the run to the summary file
+async fn run(job: &Job<'_>, reports: &[ReportConfig]) -> Summary {
+    let start_time = Utc::now();
+    let started_at = Instant::now();
+    let accounts = futures_util::future::join_all(
+        job.config
+            .accounts
+            .iter()
+            .map(|account| run_account(job, account, reports)),
+    )
+    .await;
+    let summary = Summary::new(start_time, started_at.elapsed(), accounts);
+    tracing::info!(
+        status = summary.status.name(),
+        written = summary.counts.written,
+        unchanged = summary.counts.unchanged,
+        skipped = summary.counts.skipped,
+        failed = summary.counts.failed,
+        cancelled = summary.counts.cancelled,
+        bytes = summary.bytes,
+        duration_secs = summary.duration_secs,
+        "Finished the run"
+    );
+    job.health.record_run(&summary);
+    if let Err(e) = summary.store(&job.config.summary_file) {
+        tracing::error!(
+            path = %job.config.summary_file.display(),
+            error = %e,
+            "Failed to store the summary"
+        );
+    }
+    if let Some(recorder) = &job.recorder {
+        let directory = recorder.directory().display();
+        match recorder.save() {
+            Ok(exchanges) => tracing::info!(%directory, exchanges, "Saved the fixtures"),
+            Err(e) => tracing::error!(%directory, error = %e, "Failed to save the fixtures"),
+        }
+    }
+    summary
+}
 
-    // a boolean for checking whether the sender has disconnected
-    let mut sender_closed = false;
+/// Logs in to the account to check the session, without downloading reports
+#[tracing::instrument(skip_all, fields(account = %account.mail))]
+async fn check_login(job: &Job<'_>, account: &Account) {
+    tracing::info!("Logging in to check the session");
+    let started_at = Instant::now();
+    let login = CookieStore::login_with(
+        &job.config.portal_url,
+        account.mail.clone(),
+        account.password().unwrap_or_default(),
+        job.recorder.clone(),
+    );
+    let Some(login) = job.until_shutdown(login).await else {
+        return;
+    };
+    job.metrics.login(false, login.is_ok());
+    job.metrics.duration(Operation::Login, started_at.elapsed());
+    match login {
+        Ok(cookie_store) => job
+            .health
+            .set_session(cookie_store.with_customers(account.customers.clone())),
+        Err(e) => tracing::error!(error = %e, "Failed to log in"),
+    }
+}
 
-    // And an initial delay
-    let mut sleep_time = MINIMUM_DURATION;
+/// Runs the reports on their schedules until the shutdown is requested.
+/// Runs never overlap, a run that's due while another run is busy starts after it.
+/// Schedules that were missed since their last run in the state, or never ran, run right away.
+/// If the first run isn't due yet, the first account logs in right away so the session is ready before it.
+///
+/// Returns whether a run was interrupted by the shutdown.
+async fn daemon(job: &Job<'_>, jobs: &[(Schedule, Vec<ReportConfig>)], mut state: State) -> bool {
+    let mut started = false;
     loop {
-        // Wait before sending a request
-        tokio::time::sleep(sleep_time).await;
+        // Find the schedule that's due first
+        let now = Utc::now();
+        let next = jobs
+            .iter()
+            .filter_map(|(schedule, reports)| {
+                let due = match state.last_runs.get(&schedule.to_string()) {
+                    Some(last_run) => schedule.next_after(*last_run)?,
+                    None => now,
+                };
+                Some((due, schedule, reports))
+            })
+            .min_by_key(|(due, _, _)| *due);
+        let Some((due, schedule, reports)) = next else {
+            tracing::warn!("None of the schedules run again");
+            return false;
+        };
 
-        // Load available ids
-        while let Some(id) = receive_id(&mut rx, &mut sender_closed) {
-            ids.push(id);
+        // Wait until it's due
+        if due > now {
+            if !started {
+                if let Some(account) = job.config.accounts.first() {
+                    check_login(job, account).await;
+                }
+            }
+            tracing::info!(
+                %schedule,
+                next_run = %due.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+                "Waiting for the next run"
+            );
+            tokio::select! {
+                () = tokio::time::sleep((due - Utc::now()).to_std().unwrap_or_default()) => {}
+                () = job.shutdown.cancelled() => return false,
+            }
         }
 
-        // Increase delay
-        eprintln!("Report download delay: {}", sleep_time.as_secs_f64());
-        sleep_time *= 2;
+        // Run it and remember when it started, runs that were missed in the meantime are combined into the next run
+        started = true;
+        let started_at: DateTime<Utc> = Utc::now();
+        let span = tracing::info_span!("scheduled_run", %schedule);
+        tracing::info!(
+            parent: &span,
+            reports = %reports
+                .iter()
+                .map(|report| report.report.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            "Running the schedule"
+        );
+        let summary = run(job, reports).instrument(span).await;
 
-        // Make sure there is an id available for download.
-        // Reset the index to 0, if the last ID has been reached and the sender has disconnected
-        if i >= ids.len() && !sender_closed {
-            continue;
-        } else if i >= ids.len() {
-            i = 0;
+        // An interrupted run isn't recorded, so it runs again after a restart
+        if summary.status == Status::Cancelled {
+            return true;
+        }
+        state.last_runs.insert(schedule.to_string(), started_at);
+        if let Err(e) = state.store() {
+            tracing::error!(
+                path = %state.path.display(),
+                error = %e,
+                "Failed to store the state"
+            );
+        }
+        if job.shutdown.is_cancelled() {
+            return false;
+        }
+    }
+}
+
+/// The exit code after SIGINT
+const SIGINT_EXIT_CODE: i32 = 130;
+
+/// The exit code after SIGTERM
+const SIGTERM_EXIT_CODE: i32 = 143;
+
+/// Waits for SIGINT or SIGTERM and returns the exit code that belongs to it
+async fn shutdown_signal() -> i32 {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => SIGINT_EXIT_CODE,
+                    _ = terminate.recv() => SIGTERM_EXIT_CODE,
+                }
+            }
+            Err(_) => {
+                tokio::signal::ctrl_c().await.ok();
+                SIGINT_EXIT_CODE
+            }
         }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.ok();
+        SIGINT_EXIT_CODE
+    }
+}
 
-        // Download the latest energy usage report for the last year
-        let today = chrono::Local::now().date_naive();
-        let (file_name, report) = match Report::EnergieVerbruikPerUur(
-            ids[i].1,
-            today.with_year(today.year() - 1).unwrap(),
-            today,
-        )
-        .download_latest_version(cookie_store)
+/// Cancels the shutdown token on the first SIGINT or SIGTERM and exits right away on the second one.
+/// Returns a receiver for the exit code of the first signal.
+fn cancel_on_signal(shutdown: CancellationToken) -> oneshot::Receiver<i32> {
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let code = shutdown_signal().await;
+        tracing::warn!(
+            code,
+            "Stopping, downloads in progress are cancelled. Send the signal again to stop immediately"
+        );
+        tx.send(code).ok();
+        shutdown.cancel();
+        shutdown_signal().await;
+        std::process::exit(code);
+    });
+    rx
+}
+
+/// Logs in with the first account and serves the api, the probes and the metrics until the shutdown is requested.
+/// Exits with 2 without a token on an address other than loopback, and with 1 if logging in or serving fails.
+#[tracing::instrument(skip_all, fields(account = %account.mail))]
+async fn serve(
+    job: &Job<'_>,
+    account: &Account,
+    address: SocketAddr,
+    token: Option<String>,
+    sink: Arc<dyn Sink>,
+) {
+    if job.config.accounts.len() > 1 {
+        tracing::warn!("Only the first account is used by the api");
+    }
+    // An empty API_TOKEN, like the default of a container, counts as no token
+    let token = token.filter(|token| !token.is_empty());
+    if token.is_none() && !address.ip().is_loopback() {
+        exit_invalid(format_args!(
+            "The api on {address} can be used by anyone who can reach it, set --api-token or listen on 127.0.0.1"
+        ));
+    }
+
+    // Log in to receive a cookie
+    let started_at = Instant::now();
+    let login = CookieStore::login_at(
+        &job.config.portal_url,
+        account.mail.clone(),
+        account.password().unwrap_or_default(),
+    );
+    let Some(login) = job.until_shutdown(login).await else {
+        return;
+    };
+    job.metrics.login(false, login.is_ok());
+    job.metrics.duration(Operation::Login, started_at.elapsed());
+    let cookie_store = match login {
+        Ok(cookie_store) => cookie_store.with_customers(account.customers.clone()),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to log in");
+            std::process::exit(1);
+        }
+    };
+    job.health.set_session(cookie_store.clone());
+
+    // Serve the api next to the probes and the metrics
+    let options = api::Options {
+        filters: job.config.filters.clone(),
+        polling: job.polling,
+        format: job.config.sinks.format,
+        concurrency: job.config.concurrency,
+        token,
+    };
+    let api = Api::new(
+        cookie_store,
+        options,
+        sink,
+        job.metrics.clone(),
+        job.shutdown.clone(),
+    );
+    let router = metrics::router(job.metrics.clone())
+        .merge(health::router(job.health.clone()))
+        .merge(api::router(api));
+    tracing::info!(%address, "Serving the api, the probes and the metrics");
+    if let Err(e) = metrics::serve(address, router, job.shutdown.clone().cancelled_owned()).await {
+        tracing::error!(%address, error = %e, "Failed to serve the api");
+        std::process::exit(1);
+    }
+}
+
+/// Prints every request a run would send and where the reports would be saved, without contacting the portal.
+/// The eans and ids are only known while running, without ean filters and for the ids placeholders are printed.
+fn dry_run(config: &Config, sink: &dyn Sink) {
+    let url = |path: &str| format!("{}{path}", config.portal_url.trim_end_matches('/'));
+    let today = Local::now().date_naive();
+    let eans = if config.filters.eans.is_empty() {
+        vec![Ean::from("{ean}".to_owned())]
+    } else {
+        config.filters.eans.iter().cloned().map(Ean::from).collect()
+    };
+    println!("The id of a meter is only known once it's looked up, 0 takes its place");
+    println!("{{fileName}} is the name the portal gives the generated report");
+
+    for account in &config.accounts {
+        println!();
+        println!(
+            "Account {}, customers {:?}",
+            account.mail, account.customers
+        );
+        println!("  GET {}", url("/Authorization/Login/Default"));
+        println!(
+            "  POST {}, form: user[emailAddress], user[passWord], __RequestVerificationToken",
+            url("/Home/Login")
+        );
+
+        // The eans of the reports per meter are read from the aansluitinglijst, and their ids from the connection list
+        if config.reports.iter().any(ReportConfig::per_meter) {
+            let report = Report::Aansluitinglijst;
+            println!("  Read the eans from {}", report.name());
+            println!("    GET {}", url(report.path()));
+            println!("      request: {}", report.request(&account.customers));
+            println!("    GET {}", url("/Global/Download?fileName={fileName}"));
+            for ean in &eans {
+                let filter = PersonalFilter::new(&account.customers).with_ean_search(ean.value());
+                println!("  Look up the id of {ean}");
+                println!("    GET {}", url("/Connections/List/Index"));
+                println!("      request: {}", ReportRequest::Flag(false));
+                println!(
+                    "      cookie PersonalFilter: {}",
+                    serde_json::to_string(&filter).unwrap_or_default()
+                );
+            }
+        }
+
+        for report_config in &config.reports {
+            let meters = if report_config.per_meter() {
+                eans.iter().map(Some).collect()
+            } else {
+                vec![None]
+            };
+            for ean in meters {
+                let id = ean.map(|_| Id::from(0));
+                let Some(report) = report_config.to_report(id, today) else {
+                    continue;
+                };
+                match ean {
+                    Some(ean) => println!("  Download {} of {ean}", report.name()),
+                    None => println!("  Download {}", report.name()),
+                }
+                println!("    GET {}", url(report.path()));
+                println!("      request: {}", report.request(&account.customers));
+                println!("    GET {}", url("/Global/Download?fileName={fileName}"));
+                let document = Document {
+                    report,
+                    ean: ean.cloned(),
+                    id,
+                    file_name: "{fileName}.xlsx".to_owned(),
+                    data: Vec::new().into(),
+                    downloaded_at: Utc::now(),
+                };
+                for destination in sink.destinations(&document) {
+                    println!("    Save to {destination}");
+                }
+            }
+        }
+    }
+}
+
+/// Checks the files in the directory against its manifest and exits with 1 if any file is missing or changed,
+/// or with 2 if the manifest or a file couldn't be read
+async fn verify(directory: &Path) {
+    let manifest = Manifest::load(directory)
         .await
-        {
-            Ok((file_name, report)) => (file_name, report),
+        .unwrap_or_else(|e| exit_invalid(format_args!("Failed to read the manifest: {e}")));
+    let problems = manifest
+        .verify(directory)
+        .await
+        .unwrap_or_else(|e| exit_invalid(format_args!("Failed to read the files: {e}")));
+    for problem in &problems {
+        match problem {
+            Problem::Missing(path) => eprintln!("Missing: {path}"),
+            Problem::SizeMismatch {
+                path,
+                expected,
+                found,
+            } => eprintln!("Changed: {path} ({found} bytes instead of {expected})"),
+            Problem::HashMismatch(path) => eprintln!("Changed: {path} (different hash)"),
+        }
+    }
+    eprintln!(
+        "Checked files: {}, problems: {}",
+        manifest.entries.len(),
+        problems.len()
+    );
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Prints the connections of every account that match the filters
+async fn search(args: &SearchArgs) {
+    let (accounts, portal_url) = args.accounts().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(2);
+    });
+
+    // Every account is searched, a failing account only makes the exit code non-zero at the end
+    let mut failed = Vec::new();
+    for account in accounts {
+        let connections = async {
+            let cookie_store = CookieStore::login_at(
+                &portal_url,
+                account.mail.clone(),
+                account.password().unwrap_or_default(),
+            )
+            .await
+            .map_err(|e| format!("Failed to log in: {e}"))?
+            .with_customers(account.customers.clone());
+            if !cookie_store
+                .is_logged_in()
+                .await
+                .map_err(|e| format!("Failed to log in: {e}"))?
+            {
+                return Err("Failed to log in, check the mail and password".to_owned());
+            }
+            Connections::new(&cookie_store)
+                .search_all(&args.filter(&account.customers))
+                .await
+                .map_err(|e| format!("Failed to search: {e}"))
+        }
+        .await;
+        let connections = match connections {
+            Ok(connections) => connections,
             Err(e) => {
-                eprintln!("Failed to download report for ean {}\n{e}\n", ids[i].0);
-                cookie_store.redo_login().await.ok();
+                eprintln!("{}: {e}", account.mail);
+                failed.push(account.mail);
                 continue;
             }
         };
+        for connection in &connections {
+            if args.json {
+                println!("{}", serde_json::to_string(connection).unwrap_or_default());
+            } else {
+                println!(
+                    "{}\t{}\t{}",
+                    connection.id,
+                    connection.ean,
+                    connection.cells.join("\t")
+                );
+            }
+        }
+        eprintln!("{}: {} connections", account.mail, connections.len());
+    }
+    if !failed.is_empty() {
+        eprintln!("Failed to search for {}", failed.join(", "));
+        std::process::exit(1);
+    }
+}
 
-        // Save the file
-        save(
-            cookie_store.client(),
-            output,
-            report,
-            format!("{}_{file_name}", ids[i].0),
-        )
-        .await
-        .ok();
+/// Prints the json of an encoded request header or cookie
+fn decode(value: &str, cookie: bool) {
+    let json = if cookie {
+        request::decode_cookie(value).and_then(|json| Ok(serde_json::to_string_pretty(&json)?))
+    } else {
+        ReportRequest::decode(value).and_then(|request| {
+            eprintln!("Request: {}", request.kind());
+            Ok(serde_json::to_string_pretty(&request)?)
+        })
+    };
+    match json {
+        Ok(json) => println!("{json}"),
+        Err(e) => {
+            eprintln!("Failed to decode: {e}");
+            std::process::exit(1);
+        }
+    }
+}
 
-        eprintln!("Saved the report for ean {}", ids[i].0);
-        i += 1;
-        sleep_time = (sleep_time / 4).max(MINIMUM_DURATION);
+/// Prints the encoded request header or cookie of the json
+fn encode(json: &str, cookie: bool) {
+    let encoded = if cookie {
+        serde_json::from_str::<serde_json::Value>(json)
+            .map(|json| request::encode_cookie(&json.to_string()))
+    } else {
+        serde_json::from_str::<ReportRequest>(json).and_then(|request| request.encode())
+    };
+    match encoded {
+        Ok(encoded) => println!("{encoded}"),
+        Err(e) => {
+            eprintln!("Failed to encode: {e}");
+            std::process::exit(1);
+        }
     }
 }
 
+/// Prints the error and exits with 2, the exit code of invalid arguments and settings
+fn exit_invalid(error: impl Display) -> ! {
+    eprintln!("{error}");
+    std::process::exit(2);
+}
+
 #[tokio::main]
 async fn main() {
     // Parse the arguments
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Err(e) = init_logging(args.log_format, &args.log_level) {
+        exit_invalid(e);
+    }
+    match &args.command {
+        Some(Command::Verify { directory }) => {
+            verify(directory).await;
+            return;
+        }
+        Some(Command::Decode { value, cookie }) => {
+            decode(value, *cookie);
+            return;
+        }
+        Some(Command::Encode { json, cookie }) => {
+            encode(json, *cookie);
+            return;
+        }
+        Some(Command::Search(search_args)) => {
+            search(search_args).await;
+            return;
+        }
+        None => {}
+    }
 
-    // Log in to receive a cookie
-    eprintln!("Logging in");
-    let cookie_store = CookieStore::login(args.mail, args.password)
+    // Read the config file, the arguments override its values
+    let config = args
+        .load_config(&matches)
+        .unwrap_or_else(|e| exit_invalid(e));
+
+    // Create the sink for the outputs
+    let mut options = config.sinks.options();
+    args.override_options(&mut options);
+    let sink: Arc<dyn Sink> =
+        sink::from_outputs(&config.sinks.outputs, &reqwest::Client::new(), &options)
+            .unwrap_or_else(|e| exit_invalid(format_args!("Invalid output: {e}")))
+            .into();
+    if args.dry_run {
+        dry_run(&config, sink.as_ref());
+        return;
+    }
+
+    // Check the schedules before anything is downloaded
+    let jobs = if args.daemon {
+        match config.jobs() {
+            Ok(jobs) => Some(jobs),
+            Err(e) => exit_invalid(e),
+        }
+    } else {
+        None
+    };
+
+    // Stop cleanly when the container is stopped or ctrl-c is pressed
+    let shutdown = CancellationToken::new();
+    let mut signal_code = cancel_on_signal(shutdown.clone());
+
+    // Serve the metrics while the reports are downloaded, and the probes in daemon and serve mode
+    let metrics = Metrics::new()
+        .unwrap_or_else(|e| exit_invalid(format_args!("Failed to register the metrics: {e}")));
+    let max_age = jobs.as_ref().map(|_| {
+        i64::try_from(config.ready_max_age)
+            .ok()
+            .and_then(chrono::TimeDelta::try_seconds)
+            .unwrap_or_else(|| {
+                exit_invalid(format_args!(
+                    "{} seconds is too long for ready_max_age",
+                    config.ready_max_age
+                ))
+            })
+    });
+    let job = Job {
+        config: &config,
+        sink: sink.as_ref(),
+        polling: Polling {
+            deadline: Duration::from_secs(config.report_deadline),
+            ..Polling::default()
+        },
+        metrics,
+        health: Health::new(sink.clone(), max_age),
+        recorder: args.record.clone().map(Recorder::new),
+        shutdown,
+    };
+    if let (true, Some(address), Some(account)) = (args.serve, args.listen, config.accounts.first())
+    {
+        serve(&job, account, address, args.api_token.clone(), sink.clone()).await;
+        return;
+    }
+    if let Some(address) = args.listen {
+        let mut router = metrics::router(job.metrics.clone());
+        if args.daemon {
+            router = router.merge(health::router(job.health.clone()));
+        }
+        let shutdown = job.shutdown.clone();
+        tokio::spawn(async move {
+            tracing::info!(%address, "Serving the metrics");
+            if let Err(e) = metrics::serve(address, router, shutdown.cancelled_owned()).await {
+                tracing::error!(%address, error = %e, "Failed to serve the metrics");
+            }
+        });
+    }
+
+    // Download the reports of every account
+    let status = match jobs {
+        Some(jobs) => {
+            let state = State::load(&config.state_file)
+                .unwrap_or_else(|e| exit_invalid(format_args!("Failed to read the state: {e}")));
+            if daemon(&job, &jobs, state).await {
+                Status::Cancelled
+            } else {
+                Status::Ok
+            }
+        }
+        None => {
+            // The table is left out of json logs, the summary file contains the same information
+            let summary = run(&job, &config.reports).await;
+            if args.log_format == LogFormat::Text {
+                eprintln!("{summary}");
+            }
+            summary.status
+        }
+    };
+    match status {
+        Status::Ok => {}
+        Status::Cancelled => {
+            std::process::exit(signal_code.try_recv().unwrap_or(SIGINT_EXIT_CODE));
+        }
+        status => std::process::exit(status.exit_code()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rapportage_downloader::config;
+
+    use super::*;
+
+    /// Parses the arguments, after the name of the binary
+    fn parse(arguments: &[&str]) -> (Args, ArgMatches) {
+        let matches = Args::command()
+            .try_get_matches_from(
+                std::iter::once("rapportage_downloader").chain(arguments.iter().copied()),
+            )
+            .expect("Invalid arguments");
+        let args = Args::from_arg_matches(&matches).expect("Invalid arguments");
+        (args, matches)
+    }
+
+    /// Writes the config file of the test and returns its path
+    fn config_file(test: &str, toml: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "rapportage-config-{test}-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, toml).expect("Failed to write the config");
+        path.display().to_string()
+    }
+
+    const CONFIG: &str = r#"
+        concurrency = 4
+
+        [[accounts]]
+        mail = "energie@example.com"
+        password = "geheim"
+        customers = [50]
+
+        [[reports]]
+        report = "aansluitinglijst"
+
+        [sinks]
+        outputs = ["reports"]
+    "#;
+
+    #[test]
+    fn config_from_the_file_only() {
+        let path = config_file("file", CONFIG);
+        let (args, matches) = parse(&["--config", &path]);
+        let config = args.load_config(&matches).expect("Invalid config");
+        assert_eq!(config.accounts.len(), 1);
+        assert_eq!(config.accounts[0].mail, "energie@example.com");
+        assert_eq!(config.accounts[0].customers, [50]);
+        assert_eq!(config.reports[0].report, "aansluitinglijst");
+        assert_eq!(config.sinks.outputs, ["reports"]);
+        assert_eq!(config.concurrency, 4);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn config_from_the_arguments_only() {
+        let (args, matches) = parse(&[
+            "--mail",
+            "energie@example.com",
+            "--password",
+            "geheim",
+            "--customer",
+            "51",
+            "--output",
+            "reports",
+            "--concurrency",
+            "2",
+        ]);
+        let config = args.load_config(&matches).expect("Invalid config");
+        assert_eq!(config.accounts[0].mail, "energie@example.com");
+        assert_eq!(config.accounts[0].customers, [51]);
+        assert_eq!(config.reports[0].report, config::PER_METER_REPORT);
+        assert_eq!(config.sinks.outputs, ["reports"]);
+        assert_eq!(config.concurrency, 2);
+    }
+
+    #[test]
+    fn arguments_complete_and_override_the_file() {
+        // The password variable isn't set and there's no output, which the arguments fix
+        let path = config_file(
+            "override",
+            r#"
+                concurrency = 4
+
+                [[accounts]]
+                mail = "energie@example.com"
+                password_env = "RAPPORTAGE_CONFIG_TEST_UNSET"
+                customers = [50]
+
+                [[reports]]
+                report = "aansluitinglijst"
+            "#,
+        );
+        let (args, matches) = parse(&[
+            "--config",
+            &path,
+            "--mail",
+            "ander@example.com",
+            "--password",
+            "geheim",
+            "--output",
+            "-",
+            "--format",
+            "csv",
+        ]);
+        let config = args.load_config(&matches).expect("Invalid config");
+        assert_eq!(config.accounts[0].mail, "ander@example.com");
+        assert_eq!(config.accounts[0].customers, [50]);
+        assert_eq!(config.sinks.outputs, ["-"]);
+        assert_eq!(config.sinks.format, Format::Csv);
+
+        // Defaults of the arguments don't override the file
+        assert_eq!(config.concurrency, 4);
+
+        // Without the arguments the file isn't complete
+        let (args, matches) = parse(&["--config", &path]);
+        assert!(matches!(
+            args.load_config(&matches),
+            Err(config::Error::Invalid { key, .. }) if key == "accounts[0].password_env"
+        ));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn merged_config_is_validated() {
+        let path = config_file(
+            "invalid",
+            &CONFIG.replace("concurrency = 4", "concurrency = 0"),
+        );
+        let (args, matches) = parse(&["--config", &path]);
+        assert!(matches!(
+            args.load_config(&matches),
+            Err(config::Error::Invalid { key, .. }) if key == "concurrency"
+        ));
+
+        let (args, matches) = parse(&["--config", &path, "--concurrency", "2"]);
+        assert_eq!(
+            args.load_config(&matches)
+                .expect("Invalid config")
+                .concurrency,
+            2
+        );
+
+        // A maximum age that doesn't fit in a duration is rejected instead of panicking later
+        let (args, matches) = parse(&[
+            "--config",
+            &path,
+            "--concurrency",
+            "2",
+            "--ready-max-age",
+            "18446744073709551615",
+        ]);
+        assert!(matches!(
+            args.load_config(&matches),
+            Err(config::Error::Invalid { key, .. }) if key == "ready_max_age"
+        ));
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn date_ranges_are_bounded() {
+        let path = config_file(
+            "days",
+            &CONFIG.replace(
+                r#"report = "aansluitinglijst""#,
+                r#"report = "energie-verbruik-per-uur"
+                days = 4000000000"#,
+            ),
+        );
+        let (args, matches) = parse(&["--config", &path]);
+        assert!(matches!(
+            args.load_config(&matches),
+            Err(config::Error::Invalid { key, .. }) if key == "reports[0].days"
+        ));
+        std::fs::remove_file(path).ok();
+
+        // A range that would start before the first date starts at it instead of panicking
+        let report = config::ReportConfig {
+            report: config::PER_METER_REPORT.to_owned(),
+            days: Some(config::MAX_DAYS),
+            start: None,
+            end: None,
+            schedule: None,
+        };
+        let first = chrono::NaiveDate::MIN;
+        assert_eq!(report.date_range(first), (first, first));
+    }
+
+    #[tokio::test]
+    async fn unknown_meters_are_skipped_after_the_attempts() {
+        let portal = rapportage_downloader::mock::MockPortal::new("test@example.com", "test");
+        let server = portal.start().expect("Failed to start the mock portal");
+        let cookie_store = CookieStore::login_at(
+            &server.url(),
+            "test@example.com".to_owned(),
+            "test".to_owned(),
+        )
         .await
-        .expect("Login failed");
+        .expect("Failed to log in");
+        let metrics = Metrics::new().expect("Failed to create the metrics");
+        let mut sleep_time = MINIMUM_DURATION;
 
-    // Read the eans
-    eprintln!("Reading eans");
-    let eans = read_eans(&cookie_store)
+        let failure = load_id(
+            &cookie_store,
+            &metrics,
+            &Ean::from("871687120000000009".to_owned()),
+            &mut sleep_time,
+        )
         .await
-        .expect("Failed to read ean codes");
+        .expect_err("The meter doesn't exist");
+        assert_eq!(failure.stage, Stage::ResolveId);
+        assert_eq!(failure.kind, "Id");
+        assert_eq!(sleep_time, MINIMUM_DURATION * 2u32.pow(ATTEMPTS));
+    }
 
-    // Create a channel and vector for eans and ids
-    let (tx, rx) = mpsc::channel(10);
-    let ids = Vec::with_capacity(eans.len());
-
-    // Download the ids amd reports
-    eprintln!("Loading ids and reports");
-    let id_loader = tokio::spawn(load_ids(eans, tx, cookie_store.clone()));
-    let report_loader = download_reports(ids, &cookie_store, &args.output, rx);
-    let (join_result, ()) = join!(id_loader, report_loader);
-    join_result.unwrap();
+    #[tokio::test]
+    async fn daemon_logs_in_before_the_first_run() {
+        let portal = rapportage_downloader::mock::MockPortal::new("test@example.com", "test");
+        let server = portal.start().expect("Failed to start the mock portal");
+        let config = Config {
+            accounts: vec![Account {
+                mail: "test@example.com".to_owned(),
+                password: Some("test".to_owned()),
+                password_env: None,
+                customers: vec![50],
+            }],
+            portal_url: server.url(),
+            ..Config::default()
+        };
+        let sink: Arc<dyn Sink> = Arc::new(sink::TeeSink { sinks: Vec::new() });
+        let job = Job {
+            config: &config,
+            sink: sink.as_ref(),
+            polling: Polling::default(),
+            metrics: Metrics::new().expect("Failed to create the metrics"),
+            health: Health::new(sink.clone(), None),
+            recorder: None,
+            shutdown: CancellationToken::new(),
+        };
+
+        // The schedule ran just before the restart, so the next run is tomorrow
+        let schedule: Schedule = "0 2 * * *".parse().expect("Invalid schedule");
+        let state = State {
+            last_runs: [(schedule.to_string(), Utc::now())].into(),
+            path: std::env::temp_dir()
+                .join(format!("rapportage-state-{}.json", std::process::id())),
+        };
+        let jobs = [(schedule, config.reports.clone())];
+        let ready = async {
+            for _ in 0..100 {
+                if job.health.readiness().await.ready {
+                    return true;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            false
+        };
+        let ready = tokio::select! {
+            _ = daemon(&job, &jobs, state) => panic!("The daemon stopped"),
+            ready = ready => ready,
+        };
+        assert!(ready, "{:?}", job.health.readiness().await);
+    }
 }
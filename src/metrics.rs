@@ -0,0 +1,224 @@
+use std::{fmt::Display, net::SocketAddr, time::Duration};
+
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use prometheus::{
+    exponential_buckets, Encoder as _, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::{ean::Ean, report::Report};
+
+/// Errors that can occur while registering or serving the metrics
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Prometheus(#[from] prometheus::Error),
+    Server(#[from] hyper::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// The operations whose duration is measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Login,
+    Relogin,
+
+    /// Retrieving the id of an ean and checking it
+    ResolveId,
+
+    /// Requesting, generating and downloading a report
+    Download,
+
+    /// Saving a report to the outputs
+    Save,
+}
+
+impl Operation {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::Relogin => "relogin",
+            Self::ResolveId => "resolve_id",
+            Self::Download => "download",
+            Self::Save => "save",
+        }
+    }
+}
+
+/// The counters, gauges and histograms of the downloads, exposed in the Prometheus text format.
+/// Cloning is cheap, every clone updates the same metrics.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    reports: IntCounterVec,
+    id_attempts: IntCounterVec,
+    logins: IntCounterVec,
+    saved_bytes: IntCounterVec,
+    durations: HistogramVec,
+    backoff_delay: GaugeVec,
+    last_success: GaugeVec,
+}
+
+impl Metrics {
+    /// Creates and registers the metrics
+    ///
+    /// # Errors
+    /// Returns an error if a metric couldn't be registered
+    pub fn new() -> Result<Self, Error> {
+        let registry = Registry::new_custom(Some("rapportage".to_owned()), None)?;
+        let reports = IntCounterVec::new(
+            Opts::new("reports_total", "The requested reports per outcome"),
+            &["report", "outcome"],
+        )?;
+        let id_attempts = IntCounterVec::new(
+            Opts::new(
+                "id_resolution_attempts_total",
+                "The attempts to retrieve the id of an ean",
+            ),
+            &["result"],
+        )?;
+        let logins = IntCounterVec::new(
+            Opts::new("logins_total", "The logins at the portal"),
+            &["kind", "result"],
+        )?;
+        let saved_bytes = IntCounterVec::new(
+            Opts::new("saved_bytes_total", "The size of the written reports"),
+            &["report"],
+        )?;
+        let durations = HistogramVec::new(
+            HistogramOpts::new("operation_duration_seconds", "The duration of the requests")
+                .buckets(exponential_buckets(0.05, 2.0, 15)?),
+            &["operation"],
+        )?;
+        let backoff_delay = GaugeVec::new(
+            Opts::new(
+                "backoff_delay_seconds",
+                "The current delay before the next attempt",
+            ),
+            &["operation"],
+        )?;
+        let last_success = GaugeVec::new(
+            Opts::new(
+                "last_success_timestamp_seconds",
+                "The unix time of the last saved report of a meter",
+            ),
+            &["ean"],
+        )?;
+        registry.register(Box::new(reports.clone()))?;
+        registry.register(Box::new(id_attempts.clone()))?;
+        registry.register(Box::new(logins.clone()))?;
+        registry.register(Box::new(saved_bytes.clone()))?;
+        registry.register(Box::new(durations.clone()))?;
+        registry.register(Box::new(backoff_delay.clone()))?;
+        registry.register(Box::new(last_success.clone()))?;
+        Ok(Self {
+            registry,
+            reports,
+            id_attempts,
+            logins,
+            saved_bytes,
+            durations,
+            backoff_delay,
+            last_success,
+        })
+    }
+
+    /// Counts a requested report, the outcome is written, unchanged, skipped, failed or cancelled
+    pub fn report(&self, report: &str, outcome: &str) {
+        self.reports.with_label_values(&[report, outcome]).inc();
+    }
+
+    /// Counts an attempt to retrieve the id of an ean
+    pub fn id_attempt(&self, success: bool) {
+        self.id_attempts.with_label_values(&[result(success)]).inc();
+    }
+
+    /// Counts a login, or a login after a failed download if `relogin` is set
+    pub fn login(&self, relogin: bool, success: bool) {
+        let kind = if relogin { "relogin" } else { "login" };
+        self.logins
+            .with_label_values(&[kind, result(success)])
+            .inc();
+    }
+
+    /// Adds the size of a written report
+    pub fn saved(&self, report: &Report, bytes: u64) {
+        self.saved_bytes
+            .with_label_values(&[report.name()])
+            .inc_by(bytes);
+    }
+
+    /// Records the duration of an operation
+    pub fn duration(&self, operation: Operation, duration: Duration) {
+        self.durations
+            .with_label_values(&[operation.name()])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Sets the delay before the next attempt of an operation
+    pub fn backoff_delay(&self, operation: Operation, delay: Duration) {
+        self.backoff_delay
+            .with_label_values(&[operation.name()])
+            .set(delay.as_secs_f64());
+    }
+
+    /// Records that a report of the meter was saved now
+    #[allow(clippy::cast_precision_loss)]
+    pub fn success(&self, ean: &Ean) {
+        self.last_success
+            .with_label_values(&[ean.value()])
+            .set(chrono::Utc::now().timestamp() as f64);
+    }
+
+    /// Returns the metrics in the Prometheus text format
+    ///
+    /// # Errors
+    /// Returns an error if the metrics couldn't be encoded
+    pub fn encode(&self) -> Result<String, Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+const fn result(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+async fn metrics(State(metrics): State<Metrics>) -> Result<String, StatusCode> {
+    metrics
+        .encode()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Returns the routes that expose the metrics at `/metrics`
+pub fn router(metrics: Metrics) -> Router {
+    Router::new()
+        .route("/metrics", get(self::metrics))
+        .with_state(metrics)
+}
+
+/// Serves the router at the address until the shutdown future completes
+///
+/// # Errors
+/// Returns an error if the address couldn't be bound or the server failed
+pub async fn serve(
+    address: SocketAddr,
+    router: Router,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), Error> {
+    axum::Server::try_bind(&address)?
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,580 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
+    Form, Json, Router,
+};
+use base64::Engine;
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use crate::{id::Id, report::Report};
+
+/// The aansluitinglijst that's served by default, it contains the eans of [`Connection::defaults`]
+pub const AANSLUITINGLIJST: &[u8] = include_bytes!("../tests/fixtures/aansluitinglijst.xlsx");
+
+/// Returns the workbook that's served by default for the report, it matches the [`Report::layout`] of the report
+#[must_use]
+pub const fn fixture(report: &Report) -> &'static [u8] {
+    match report {
+        Report::Aansluitinglijst => AANSLUITINGLIJST,
+        Report::Belastingcluster => {
+            include_bytes!("../tests/fixtures/reports/belastingcluster.xlsx")
+        }
+        Report::Co2 | Report::Mj => include_bytes!("../tests/fixtures/reports/co2.xlsx"),
+        Report::Datakwaliteit => include_bytes!("../tests/fixtures/reports/datakwaliteit.xlsx"),
+        Report::EnergieVerbruikPerUur(_, _, _) => {
+            include_bytes!("../tests/fixtures/reports/energie-verbruik-per-uur.xlsx")
+        }
+        Report::Gebouwen => include_bytes!("../tests/fixtures/reports/gebouwen.xlsx"),
+        Report::MeetEnInfra => include_bytes!("../tests/fixtures/reports/meet-en-infra.xlsx"),
+        Report::Metadata => include_bytes!("../tests/fixtures/reports/metadata.xlsx"),
+        Report::Meterstanden => include_bytes!("../tests/fixtures/reports/meterstanden.xlsx"),
+        Report::Tussenmeter => include_bytes!("../tests/fixtures/reports/tussenmeter.xlsx"),
+        Report::Verbruik => include_bytes!("../tests/fixtures/reports/verbruik.xlsx"),
+    }
+}
+
+/// The path of the login page, the portal redirects to it when the session isn't logged in
+const LOGIN_PAGE: &str = "/Authorization/Login/Default";
+
+/// The name of the cookie that contains the session
+const SESSION_COOKIE: &str = ".ASPXAUTH";
+
+/// The verification token that should be part of the login form
+const VERIFICATION_TOKEN: &str = "mock-verification-token";
+
+/// The endpoints of the portal, to inject faults and inspect requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// `GET /Authorization/Login/Default`
+    LoginPage,
+
+    /// `POST /Home/Login`
+    Login,
+
+    /// `GET /Connections/List/Index`
+    ConnectionList,
+
+    /// `GET /Connections/Edit/Index/{id}`
+    ConnectionEdit,
+
+    /// The `Export*` and `GetDownload` endpoints that generate reports
+    Export,
+
+    /// `GET /Global/Download`
+    Download,
+}
+
+/// A failure of a single request, injected with [`MockPortal::fail`]
+#[derive(Debug, Clone)]
+pub enum Fault {
+    /// Responds with the status code
+    Status(StatusCode),
+
+    /// Redirects to the login page, like a session that expired
+    LoggedOut,
+
+    /// Responds with a html error page with the title
+    Html(String),
+
+    /// Responds with 202, like the portal does while the report is still being generated
+    StillGenerating,
+
+    /// Responds to an export with the error message instead of a filename
+    GenerationFailed(String),
+
+    /// Responds with 200 and an empty body
+    Empty,
+
+    /// Waits before responding normally, to exceed timeouts
+    Delay(Duration),
+}
+
+/// A connection that's listed by the portal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connection {
+    pub id: u32,
+    pub ean: String,
+    pub status: String,
+    pub meter_number: String,
+    pub label_id: u32,
+    pub classification_id: u32,
+    pub costplace_id: u32,
+}
+
+impl Connection {
+    /// Returns the connections of [`AANSLUITINGLIJST`]
+    #[must_use]
+    pub fn defaults() -> Vec<Self> {
+        [
+            (1001, "871687120000000001", "Actief", "E0001", 1, 3, 10),
+            (1002, "871687120000000002", "Actief", "E0002", 1, 4, 20),
+            (1003, "871687120000000003", "Inactief", "G0003", 2, 3, 20),
+        ]
+        .into_iter()
+        .map(
+            |(id, ean, status, meter_number, label_id, classification_id, costplace_id)| Self {
+                id,
+                ean: ean.to_owned(),
+                status: status.to_owned(),
+                meter_number: meter_number.to_owned(),
+                label_id,
+                classification_id,
+                costplace_id,
+            },
+        )
+        .collect()
+    }
+
+    /// Whether the connection matches the search terms and ids of the `PersonalFilter` json
+    fn matches(&self, filter: &serde_json::Value) -> bool {
+        let contains =
+            |value: &str, key: &str| value.contains(filter[key].as_str().unwrap_or_default());
+        let equals = |value: u32, key: &str| {
+            filter[key]
+                .as_u64()
+                .is_none_or(|id| id == 0 || id == u64::from(value))
+        };
+        contains(&self.ean, "eanSearch")
+            && contains(&self.meter_number, "meterNumber")
+            && equals(self.label_id, "labelId")
+            && equals(self.classification_id, "classificationId")
+            && equals(self.costplace_id, "costplaceId")
+    }
+}
+
+/// A request that was received by the portal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub endpoint: Endpoint,
+
+    /// The path and query of the request
+    pub path: String,
+
+    /// The decoded `request` header of an export, or the decoded `PersonalFilter` cookie of the list
+    pub payload: Option<String>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    mail: String,
+    password: String,
+    connections: Mutex<Vec<Connection>>,
+
+    /// The workbook of every export endpoint, by its path
+    reports: Mutex<HashMap<String, Vec<u8>>>,
+
+    /// The generated files that can be downloaded, by their filename
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    faults: Mutex<HashMap<Endpoint, VecDeque<Fault>>>,
+    sessions: Mutex<HashSet<String>>,
+    requests: Mutex<Vec<Request>>,
+
+    /// The number of sessions and files, to give them unique names
+    counter: AtomicU64,
+}
+
+/// A mock of the DB Energie portal that serves fixtures, to test without the real portal.
+/// Cloning is cheap, every clone shares the same state.
+#[derive(Debug, Clone)]
+pub struct MockPortal {
+    inner: Arc<Inner>,
+}
+
+/// Locks the mutex, a panic while it was locked doesn't leave the state invalid
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+impl MockPortal {
+    /// Creates a portal that accepts the credentials, with [`Connection::defaults`] and a fixture for every report
+    #[must_use]
+    pub fn new(mail: &str, password: &str) -> Self {
+        let per_meter = Report::EnergieVerbruikPerUur(Id::from(0), NaiveDate::MIN, NaiveDate::MIN);
+        let reports = Report::GLOBAL
+            .iter()
+            .chain([&per_meter])
+            .map(|report| (report.path().to_owned(), fixture(report).to_vec()))
+            .collect();
+        Self {
+            inner: Arc::new(Inner {
+                mail: mail.to_owned(),
+                password: password.to_owned(),
+                connections: Mutex::new(Connection::defaults()),
+                reports: Mutex::new(reports),
+                files: Mutex::new(HashMap::new()),
+                faults: Mutex::new(HashMap::new()),
+                sessions: Mutex::new(HashSet::new()),
+                requests: Mutex::new(Vec::new()),
+                counter: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Replaces the connections that are listed
+    #[must_use]
+    pub fn with_connections(self, connections: Vec<Connection>) -> Self {
+        *lock(&self.inner.connections) = connections;
+        self
+    }
+
+    /// Replaces the workbook that's generated for the report
+    #[must_use]
+    pub fn with_report(self, report: &Report, data: Vec<u8>) -> Self {
+        lock(&self.inner.reports).insert(report.path().to_owned(), data);
+        self
+    }
+
+    /// Makes the next request to the endpoint fail, faults of the same endpoint are used in order
+    pub fn fail(&self, endpoint: Endpoint, fault: Fault) {
+        lock(&self.inner.faults)
+            .entry(endpoint)
+            .or_default()
+            .push_back(fault);
+    }
+
+    /// Logs out every session, the next requests are redirected to the login page
+    pub fn expire_sessions(&self) {
+        lock(&self.inner.sessions).clear();
+    }
+
+    /// Returns every request that was received, in order
+    #[must_use]
+    pub fn requests(&self) -> Vec<Request> {
+        lock(&self.inner.requests).clone()
+    }
+
+    /// Returns the routes of the portal
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route(LOGIN_PAGE, get(login_page))
+            .route("/Home/Login", axum::routing::post(login))
+            .route("/Connections/List/Index", get(connection_list))
+            .route("/Connections/Edit/Index/:id", get(connection_edit))
+            .route("/Global/Download", get(download))
+            .fallback(export)
+            .with_state(self.clone())
+    }
+
+    /// Serves the portal on a free port of localhost until the returned server is dropped
+    ///
+    /// # Errors
+    /// Returns an error if no port could be bound
+    pub fn start(&self) -> Result<Server, hyper::Error> {
+        Server::start(self.router())
+    }
+
+    fn record(&self, endpoint: Endpoint, uri: &Uri, payload: Option<String>) {
+        lock(&self.inner.requests).push(Request {
+            endpoint,
+            path: uri
+                .path_and_query()
+                .map_or_else(|| uri.path().to_owned(), ToString::to_string),
+            payload,
+        });
+    }
+
+    /// Returns the response of the next fault of the endpoint, a delay is waited for
+    async fn fault(&self, endpoint: Endpoint) -> Option<Response> {
+        let fault = lock(&self.inner.faults)
+            .get_mut(&endpoint)
+            .and_then(VecDeque::pop_front);
+        let response = match fault? {
+            Fault::Delay(delay) => {
+                tokio::time::sleep(delay).await;
+                return None;
+            }
+            Fault::Status(status) => status.into_response(),
+            Fault::LoggedOut => Redirect::to(LOGIN_PAGE).into_response(),
+            Fault::Html(title) => Html(format!(
+                "<html><head><title>{title}</title></head><body><h1>{title}</h1></body></html>"
+            ))
+            .into_response(),
+            Fault::StillGenerating => StatusCode::ACCEPTED.into_response(),
+            Fault::GenerationFailed(message) => {
+                Json(json!({ "fileName": null, "errorMessage": message })).into_response()
+            }
+            Fault::Empty => StatusCode::OK.into_response(),
+        };
+        Some(response)
+    }
+
+    /// Checks whether the request contains the cookie of a logged in session
+    fn logged_in(&self, headers: &HeaderMap) -> bool {
+        let sessions = lock(&self.inner.sessions);
+        cookies(headers).any(|(name, value)| name == SESSION_COOKIE && sessions.contains(value))
+    }
+
+    fn next(&self) -> u64 {
+        self.inner.counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// The portal while it's being served, it stops when this is dropped
+#[derive(Debug)]
+pub struct Server {
+    address: SocketAddr,
+    shutdown: CancellationToken,
+}
+
+impl Server {
+    /// Serves the router on a free port of localhost until the returned server is dropped
+    ///
+    /// # Errors
+    /// Returns an error if no port could be bound
+    pub fn start(router: Router) -> Result<Self, hyper::Error> {
+        let server = axum::Server::try_bind(&SocketAddr::from(([127, 0, 0, 1], 0)))?
+            .serve(router.into_make_service());
+        let address = server.local_addr();
+        let shutdown = CancellationToken::new();
+        let server = server.with_graceful_shutdown(shutdown.clone().cancelled_owned());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                tracing::error!(%address, error = %e, "Failed to serve the mock portal");
+            }
+        });
+        Ok(Self { address, shutdown })
+    }
+
+    #[must_use]
+    pub const fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Returns the base url to log in at, see [`crate::login::CookieStore::login_at`]
+    #[must_use]
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Returns the name and value of every cookie of the request
+fn cookies(headers: &HeaderMap) -> impl Iterator<Item = (&str, &str)> {
+    headers
+        .get_all(header::COOKIE)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+}
+
+/// Redirects to the login page, like the portal does for requests without a session
+fn to_login_page() -> Response {
+    Redirect::to(LOGIN_PAGE).into_response()
+}
+
+async fn login_page(State(portal): State<MockPortal>, uri: Uri) -> Response {
+    portal.record(Endpoint::LoginPage, &uri, None);
+    if let Some(response) = portal.fault(Endpoint::LoginPage).await {
+        return response;
+    }
+    Html(format!(
+        "<html><head><title>Inloggen</title></head><body><form method=\"post\" action=\"/Home/Login\">\
+         <input name=\"__RequestVerificationToken\" type=\"hidden\" value=\"{VERIFICATION_TOKEN}\">\
+         <input name=\"user[emailAddress]\"><input name=\"user[passWord]\" type=\"password\">\
+         </form></body></html>"
+    ))
+    .into_response()
+}
+
+async fn login(
+    State(portal): State<MockPortal>,
+    uri: Uri,
+    Form(form): Form<HashMap<String, String>>,
+) -> Response {
+    portal.record(Endpoint::Login, &uri, None);
+    if let Some(response) = portal.fault(Endpoint::Login).await {
+        return response;
+    }
+    let field = |name: &str| form.get(name).map(String::as_str);
+    if field("__RequestVerificationToken") != Some(VERIFICATION_TOKEN)
+        || field("user[emailAddress]") != Some(&portal.inner.mail)
+        || field("user[passWord]") != Some(&portal.inner.password)
+    {
+        // The portal shows the login page again without a session
+        return login_page(State(portal), uri).await;
+    }
+    let session = format!("session-{}", portal.next());
+    lock(&portal.inner.sessions).insert(session.clone());
+    (
+        [(
+            header::SET_COOKIE,
+            format!("{SESSION_COOKIE}={session}; Path=/; HttpOnly"),
+        )],
+        Html("<html><head><title>Home</title></head><body></body></html>"),
+    )
+        .into_response()
+}
+
+async fn connection_list(
+    State(portal): State<MockPortal>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    // The list is filtered by the url encoded json in the cookie
+    let filter = cookies(&headers)
+        .find(|(name, _)| *name == "PersonalFilter")
+        .and_then(|(_, value)| {
+            percent_encoding::percent_decode_str(value)
+                .decode_utf8()
+                .ok()
+                .map(|value| value.into_owned())
+        });
+    portal.record(Endpoint::ConnectionList, &uri, filter.clone());
+    if let Some(response) = portal.fault(Endpoint::ConnectionList).await {
+        return response;
+    }
+    if !portal.logged_in(&headers) {
+        return to_login_page();
+    }
+    let filter = filter
+        .and_then(|filter| serde_json::from_str::<serde_json::Value>(&filter).ok())
+        .unwrap_or_default();
+    let page_size = filter["pageSize"]
+        .as_u64()
+        .and_then(|size| usize::try_from(size).ok())
+        .unwrap_or(15);
+    let page_number = filter["pageNumber"]
+        .as_u64()
+        .and_then(|number| usize::try_from(number).ok())
+        .unwrap_or(1);
+
+    let rows = lock(&portal.inner.connections)
+        .iter()
+        .filter(|connection| connection.matches(&filter))
+        .skip(page_size * page_number.saturating_sub(1))
+        .take(page_size)
+        .map(|connection| {
+            format!(
+                "<a class=\"list-row-visible\" href=\"/Connections/Edit/Index/{}\">\
+                 <div class=\"row-cell width-140\">{}</div><div class=\"row-cell width-100\">{}</div>\
+                 <div class=\"row-cell width-100\">{}</div></a>",
+                connection.id, connection.ean, connection.status, connection.meter_number
+            )
+        })
+        .collect::<String>();
+    Html(format!(
+        "<html><head><title>Aansluitingen</title></head><body><div class=\"list\">{rows}</div></body></html>"
+    ))
+    .into_response()
+}
+
+async fn connection_edit(
+    State(portal): State<MockPortal>,
+    Path(id): Path<u32>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    portal.record(Endpoint::ConnectionEdit, &uri, None);
+    if let Some(response) = portal.fault(Endpoint::ConnectionEdit).await {
+        return response;
+    }
+    if !portal.logged_in(&headers) {
+        return to_login_page();
+    }
+    let ean = lock(&portal.inner.connections)
+        .iter()
+        .find(|connection| connection.id == id)
+        .map(|connection| connection.ean.clone());
+    match ean {
+        Some(ean) => Html(format!(
+            "<html><head><title>Aansluiting</title></head><body>\
+             <input id=\"Mod_ean\" name=\"Mod.ean\" value=\" {ean} \"></body></html>"
+        ))
+        .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Generates the report of the path, it's stored as a file that can be downloaded
+async fn export(
+    State(portal): State<MockPortal>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let data = lock(&portal.inner.reports).get(uri.path()).cloned();
+    let (Method::GET, Some(data)) = (method, data) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // The parameters of the report are base64 encoded in a header
+    let payload = headers
+        .get("request")
+        .and_then(|value| {
+            base64::engine::general_purpose::STANDARD
+                .decode(value.as_bytes())
+                .ok()
+        })
+        .map(|payload| String::from_utf8_lossy(&payload).into_owned());
+    portal.record(Endpoint::Export, &uri, payload.clone());
+    if let Some(response) = portal.fault(Endpoint::Export).await {
+        return response;
+    }
+    if !portal.logged_in(&headers) {
+        return to_login_page();
+    }
+    if payload.is_none() {
+        return (StatusCode::BAD_REQUEST, "Missing request header").into_response();
+    }
+
+    let name = uri.path().rsplit('/').nth(1).unwrap_or("Report");
+    let file_name = format!("{name}_{}.xlsx", portal.next());
+    lock(&portal.inner.files).insert(file_name.clone(), data);
+    Json(json!({ "fileName": file_name })).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DownloadQuery {
+    file_name: String,
+}
+
+async fn download(
+    State(portal): State<MockPortal>,
+    uri: Uri,
+    headers: HeaderMap,
+    Query(query): Query<DownloadQuery>,
+) -> Response {
+    portal.record(Endpoint::Download, &uri, None);
+    if let Some(response) = portal.fault(Endpoint::Download).await {
+        return response;
+    }
+    if !portal.logged_in(&headers) {
+        return to_login_page();
+    }
+    let data = lock(&portal.inner.files).get(&query.file_name).cloned();
+    match data {
+        Some(data) => (
+            [(
+                header::CONTENT_TYPE,
+                "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            )],
+            data,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
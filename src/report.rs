@@ -1,14 +1,28 @@
-use std::{fmt::Display, io as std_io, str::FromStr, string::FromUtf8Error};
+use std::{
+    fmt::Display,
+    io as std_io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    string::FromUtf8Error,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
 
-use base64::Engine;
+use calamine::{Reader as _, Xlsx};
 use chrono::Datelike;
+use futures_util::StreamExt as _;
 use reqwest::{
     header::{HeaderValue, InvalidHeaderValue},
-    Method, Request, Url,
+    Method, Request, Response, Url,
 };
-use serde_json::json;
+use tokio::io::{AsyncWrite, AsyncWriteExt as _};
 
-use crate::{id::Id, login::CookieStore};
+use crate::{
+    id::Id,
+    login::CookieStore,
+    request::{AnalyzeRequest, ConsumptionRequest, DataQualityRequest, ReportRequest, UnitRequest},
+    usage,
+};
 
 /// Errors that can occur while downloading a report
 #[derive(Debug, thiserror::Error)]
@@ -23,6 +37,21 @@ pub enum Error {
     ValueNotAString,
     Io(#[from] std_io::Error),
     NotOk(reqwest::StatusCode, &'static str),
+
+    /// The portal hasn't finished generating the report yet
+    StillGenerating,
+
+    /// The portal reported that generating the report failed
+    GenerationFailed(String),
+
+    /// The report wasn't ready before the deadline passed
+    DeadlineExceeded(Duration),
+
+    /// A single request to the portal took longer than the timeout
+    TimedOut(Duration),
+
+    /// The downloaded data isn't the expected report
+    InvalidContent(InvalidContent),
 }
 
 impl Display for Error {
@@ -31,6 +60,129 @@ impl Display for Error {
     }
 }
 
+impl Error {
+    /// Returns the name of the variant, to group errors by their kind
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::InvalidUrl(_) => "InvalidUrl",
+            Self::InvalidHeaderValue(_) => "InvalidHeaderValue",
+            Self::Request(_) => "Request",
+            Self::Utf8(_) => "Utf8",
+            Self::Json(_) => "Json",
+            Self::NotAnObject => "NotAnObject",
+            Self::KeyNotFound(_) => "KeyNotFound",
+            Self::ValueNotAString => "ValueNotAString",
+            Self::Io(_) => "Io",
+            Self::NotOk(_, _) => "NotOk",
+            Self::StillGenerating => "StillGenerating",
+            Self::GenerationFailed(_) => "GenerationFailed",
+            Self::DeadlineExceeded(_) => "DeadlineExceeded",
+            Self::TimedOut(_) => "TimedOut",
+            Self::InvalidContent(_) => "InvalidContent",
+        }
+    }
+}
+
+/// Describes why downloaded data isn't a valid report
+#[derive(Debug)]
+pub enum InvalidContent {
+    /// The data is a html page, e.g. an error or the login page, with the contained title
+    Html(Option<String>),
+
+    /// The data isn't a zip archive, contains the first bytes of the data
+    NotXlsx(String),
+
+    /// The data is a zip archive, but it couldn't be read as a workbook
+    Unreadable(String),
+
+    /// The expected worksheet isn't part of the workbook
+    MissingWorksheet {
+        expected: &'static str,
+        found: Vec<String>,
+    },
+
+    /// The worksheet doesn't contain a header row
+    EmptyWorksheet(String),
+
+    /// The header row doesn't contain all expected columns
+    MissingColumns {
+        worksheet: String,
+        missing: Vec<&'static str>,
+    },
+
+    /// The header row doesn't contain a column with the ean of a meter
+    MissingMeterColumns(String),
+}
+
+impl Display for InvalidContent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// The known layout of the worksheet in a report
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    /// The name of the worksheet that contains the data, the first worksheet is used if it's `None`
+    pub worksheet: Option<&'static str>,
+
+    /// The columns that should be part of the header row
+    pub columns: &'static [&'static str],
+
+    /// Whether the header row contains a column per meter, like `871687120000000000 - Levering (kWh)`
+    pub meter_columns: bool,
+}
+
+impl Layout {
+    /// The layout of a report whose columns aren't known from a real export,
+    /// only the first worksheet has to exist so portal changes don't make the report invalid
+    const UNKNOWN: Self = Self {
+        worksheet: None,
+        columns: &[],
+        meter_columns: false,
+    };
+}
+
+/// Determines how long to wait for a report that is still being generated
+#[derive(Debug, Clone, Copy)]
+pub struct Polling {
+    /// The maximum time to wait for the report to become available
+    pub deadline: Duration,
+
+    /// The delay between two checks
+    pub interval: Duration,
+
+    /// The maximum time a single request may take
+    pub request_timeout: Duration,
+}
+
+impl Default for Polling {
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(10 * 60),
+            interval: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+/// The progress of a report that is being generated
+#[derive(Debug, Clone)]
+pub enum Progress {
+    /// The report has been requested from the portal
+    Requested,
+
+    /// The portal is still generating the report
+    Generating { attempt: u32, elapsed: Duration },
+
+    /// The report with the given filename is ready to be downloaded
+    Ready(String),
+
+    /// Part of the report has been downloaded
+    Downloading { received: u64, total: Option<u64> },
+}
+
 /// The available report types
 #[derive(Debug, Clone)]
 pub enum Report {
@@ -71,29 +223,245 @@ pub enum Report {
 }
 
 impl Report {
-    /// Returns the corresponding url for a report
+    /// The reports that cover every meter of the customers, so they don't need any parameters
+    pub const GLOBAL: [Self; 11] = [
+        Self::Aansluitinglijst,
+        Self::Belastingcluster,
+        Self::Co2,
+        Self::Datakwaliteit,
+        Self::Gebouwen,
+        Self::MeetEnInfra,
+        Self::Metadata,
+        Self::Meterstanden,
+        Self::Mj,
+        Self::Tussenmeter,
+        Self::Verbruik,
+    ];
+
+    /// Returns the report without parameters with the name
     #[must_use]
-    pub fn url(&self) -> &str {
+    pub fn global(name: &str) -> Option<Self> {
+        Self::GLOBAL
+            .into_iter()
+            .find(|report| report.name() == name)
+    }
+
+    /// Returns the name of the report type
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
         match self {
-            Self::Aansluitinglijst => "https://www.dbenergie.nl/Connections/List/ExportList",
-            Self::Belastingcluster => {
-                "https://www.dbenergie.nl/Connections/List/ExportTaxationCluster"
-            }
-            Self::Co2 | Self::Mj => "https://www.dbenergie.nl/Report/Co2/GetDownload",
-            Self::Datakwaliteit => {
-                "https://www.dbenergie.nl/Report/DataEntiretyCheck/GetDataToDownload"
-            }
-            Self::EnergieVerbruikPerUur(_, _, _) => {
-                "https://www.dbenergie.nl/Report/Analyze/GetDownload"
+            Self::Aansluitinglijst => "aansluitinglijst",
+            Self::Belastingcluster => "belastingcluster",
+            Self::Co2 => "co2",
+            Self::Datakwaliteit => "datakwaliteit",
+            Self::EnergieVerbruikPerUur(_, _, _) => "energie-verbruik-per-uur",
+            Self::Gebouwen => "gebouwen",
+            Self::MeetEnInfra => "meet-en-infra",
+            Self::Metadata => "metadata",
+            Self::Meterstanden => "meterstanden",
+            Self::Mj => "mj",
+            Self::Tussenmeter => "tussenmeter",
+            Self::Verbruik => "verbruik",
+        }
+    }
+
+    /// Returns the first and last day of the report, if it covers a date range
+    #[must_use]
+    pub const fn date_range(&self) -> Option<(chrono::NaiveDate, chrono::NaiveDate)> {
+        match self {
+            Self::EnergieVerbruikPerUur(_, start_date, end_date) => Some((*start_date, *end_date)),
+            _ => None,
+        }
+    }
+
+    /// Returns the path of the corresponding url for a report, relative to the portal
+    #[must_use]
+    pub const fn path(&self) -> &'static str {
+        match self {
+            Self::Aansluitinglijst => "/Connections/List/ExportList",
+            Self::Belastingcluster => "/Connections/List/ExportTaxationCluster",
+            Self::Co2 | Self::Mj => "/Report/Co2/GetDownload",
+            Self::Datakwaliteit => "/Report/DataEntiretyCheck/GetDataToDownload",
+            Self::EnergieVerbruikPerUur(_, _, _) => "/Report/Analyze/GetDownload",
+            Self::Gebouwen => "/Buildings/List/ExportList",
+            Self::MeetEnInfra => "/Report/MeteringServices/ExportList",
+            Self::Metadata => "/Connections/List/ExportMetaData",
+            Self::Meterstanden => "/Connections/List/ExportMeterReading",
+            Self::Tussenmeter => "/Connections/IntermediateMeter/ExportList",
+            Self::Verbruik => "/Report/Consumption/GetDownload",
+        }
+    }
+
+    /// Returns the worksheet and columns the downloaded report should contain.
+    /// Only the columns that are known from real exports are checked, other reports only have to be an xlsx file.
+    #[must_use]
+    pub const fn layout(&self) -> Layout {
+        match self {
+            Self::Aansluitinglijst => Layout {
+                worksheet: Some("Lijst_Export"),
+                columns: &["EAN code", "Status"],
+                meter_columns: false,
+            },
+
+            // The first column contains the time, followed by a column per meter, see [`crate::usage::parse`]
+            Self::EnergieVerbruikPerUur(_, _, _) => Layout {
+                worksheet: None,
+                columns: &[],
+                meter_columns: true,
+            },
+            Self::Belastingcluster
+            | Self::Co2
+            | Self::Datakwaliteit
+            | Self::Gebouwen
+            | Self::MeetEnInfra
+            | Self::Metadata
+            | Self::Meterstanden
+            | Self::Mj
+            | Self::Tussenmeter
+            | Self::Verbruik => Layout::UNKNOWN,
+        }
+    }
+
+    /// Checks whether the data is an xlsx file with the [`Report::layout`] of this report.
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidContent`] with the reason if the data isn't a valid report
+    pub fn validate(&self, data: &[u8]) -> Result<(), Error> {
+        self.validate_reader(std_io::Cursor::new(data))
+    }
+
+    /// Checks whether the file at the path is an xlsx file with the layout of this report, see [`Report::validate`]
+    ///
+    /// # Errors
+    /// Returns [`Error::InvalidContent`] with the reason if the file isn't a valid report,
+    /// or [`Error::Io`] if it couldn't be read
+    pub fn validate_file(&self, path: &Path) -> Result<(), Error> {
+        self.validate_reader(std_io::BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// Checks whether the reader contains an xlsx file with the layout of this report, see [`Report::validate`]
+    fn validate_reader(&self, mut reader: impl std_io::Read + std_io::Seek) -> Result<(), Error> {
+        // Xlsx files are zip archives
+        let mut start = Vec::with_capacity(512);
+        std_io::Read::read_to_end(&mut std_io::Read::take(&mut reader, 512), &mut start)?;
+        reader.rewind()?;
+        if !start.starts_with(b"PK\x03\x04") {
+            let text = String::from_utf8_lossy(&start);
+            if text.trim_start().starts_with('<') {
+                // The portal responds with a html page when something went wrong
+                let page = scraper::Html::parse_document(&text);
+                let title = scraper::Selector::parse("title")
+                    .ok()
+                    .and_then(|selector| page.select(&selector).next())
+                    .map(|title| title.text().collect::<String>().trim().to_owned());
+                return Err(Error::InvalidContent(InvalidContent::Html(title)));
             }
-            Self::Gebouwen => "https://www.dbenergie.nl/Buildings/List/ExportList",
-            Self::MeetEnInfra => "https://www.dbenergie.nl/Report/MeteringServices/ExportList",
-            Self::Metadata => "https://www.dbenergie.nl/Connections/List/ExportMetaData",
-            Self::Meterstanden => "https://www.dbenergie.nl/Connections/List/ExportMeterReading",
-            Self::Tussenmeter => {
-                "https://www.dbenergie.nl/Connections/IntermediateMeter/ExportList"
+            return Err(Error::InvalidContent(InvalidContent::NotXlsx(
+                text.chars().take(32).collect(),
+            )));
+        }
+
+        // Open the workbook
+        let mut workbook = Xlsx::new(reader)
+            .map_err(|e| Error::InvalidContent(InvalidContent::Unreadable(e.to_string())))?;
+
+        // Find the worksheet that should contain the data
+        let layout = self.layout();
+        let sheet_names = workbook.sheet_names();
+        let worksheet = match layout.worksheet {
+            Some(expected) => sheet_names
+                .iter()
+                .find(|name| name.as_str() == expected)
+                .ok_or_else(|| {
+                    Error::InvalidContent(InvalidContent::MissingWorksheet {
+                        expected,
+                        found: sheet_names.clone(),
+                    })
+                })?,
+            None => sheet_names.first().ok_or(Error::InvalidContent(
+                InvalidContent::MissingWorksheet {
+                    expected: "any worksheet",
+                    found: Vec::new(),
+                },
+            ))?,
+        };
+        let range = workbook
+            .worksheet_range(worksheet)
+            .map_err(|e| Error::InvalidContent(InvalidContent::Unreadable(e.to_string())))?;
+
+        // Take the header row
+        let header = range
+            .rows()
+            .next()
+            .ok_or_else(|| {
+                Error::InvalidContent(InvalidContent::EmptyWorksheet(worksheet.clone()))
+            })?
+            .iter()
+            .map(|column| column.to_string().trim().to_owned())
+            .collect::<Vec<_>>();
+
+        // Make sure every expected column is available
+        let missing = layout
+            .columns
+            .iter()
+            .filter(|column| !header.iter().any(|found| found == *column))
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(Error::InvalidContent(InvalidContent::MissingColumns {
+                worksheet: worksheet.clone(),
+                missing,
+            }));
+        }
+        if layout.meter_columns
+            && !header
+                .iter()
+                .skip(1)
+                .any(|column| usage::meter_in_header(column).is_some())
+        {
+            return Err(Error::InvalidContent(InvalidContent::MissingMeterColumns(
+                worksheet.clone(),
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the parameters of the report for the customers, as they're sent base64 encoded in the `request` header
+    #[must_use]
+    pub fn request(&self, customers: &[u32]) -> ReportRequest {
+        let now = chrono::Local::now();
+        let customer_ids = customers
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let customer_id = customers
+            .first()
+            .map(ToString::to_string)
+            .unwrap_or_default();
+
+        // The parameters depend on the report
+        match self {
+            Self::Aansluitinglijst
+            | Self::Belastingcluster
+            | Self::Gebouwen
+            | Self::MeetEnInfra
+            | Self::Metadata
+            | Self::Meterstanden
+            | Self::Tussenmeter => ReportRequest::Year(now.year()),
+            Self::Co2 => ReportRequest::Unit(UnitRequest::total(1, customer_ids, now.year())),
+            Self::Datakwaliteit => ReportRequest::DataQuality(DataQualityRequest::month(
+                customer_id,
+                now.year(),
+                now.month(),
+            )),
+            Self::Mj => ReportRequest::Unit(UnitRequest::total(2, customer_ids, now.year())),
+            Self::Verbruik => {
+                ReportRequest::Consumption(ConsumptionRequest::total(customer_ids, now.year()))
             }
-            Self::Verbruik => "https://www.dbenergie.nl/Report/Consumption/GetDownload",
+            Self::EnergieVerbruikPerUur(id, start_date, end_date) => ReportRequest::Analyze(
+                AnalyzeRequest::hourly(u32::from(*id), *start_date, *end_date),
+            ),
         }
     }
 
@@ -109,43 +477,40 @@ impl Report {
     /// - If the response body wasn't a json object
     /// - If the response body didn't contain a fileName
     /// - If the fileName isn't a string
+    /// - If the portal is still generating the report or reported that it failed
     pub async fn latest_version(&self, cookie_store: &CookieStore) -> Result<String, Error> {
-        let client = cookie_store.client();
+        self.request_latest_version(cookie_store, None).await
+    }
 
+    async fn request_latest_version(
+        &self,
+        cookie_store: &CookieStore,
+        timeout: Option<Duration>,
+    ) -> Result<String, Error> {
         // Create a get request for the report
-        let mut request = Request::new(Method::GET, Url::from_str(self.url())?);
+        let mut request = Request::new(Method::GET, Url::from_str(&cookie_store.url(self.path()))?);
+        *request.timeout_mut() = timeout;
 
-        let now = chrono::Local::now();
-
-        let base64_encoder = base64::engine::general_purpose::STANDARD;
+        // Add the report dependent parameters
+        request.headers_mut().insert(
+            "request",
+            HeaderValue::from_str(&self.request(cookie_store.customers()).encode()?)?,
+        );
 
-        // Add report dependent headers
-        match self {
-            Self::Aansluitinglijst
-            | Self::Belastingcluster
-            | Self::Gebouwen
-            | Self::MeetEnInfra
-            | Self::Metadata
-            | Self::Meterstanden
-            | Self::Tussenmeter => request
-                .headers_mut()
-                .insert("request", HeaderValue::from_str(&base64_encoder.encode(chrono::Local::now().year().to_string()))?),
-            Self::Co2 => request.headers_mut().insert("request", HeaderValue::from_str(&base64_encoder.encode(json!({"portalId":"6","unitId":1,"customerIds":"50","yearFrom":now.year() - 1,"yearTill":now.year(),"reportType":"total"}).to_string()))?),
-            Self::Datakwaliteit => request.headers_mut().insert("request", HeaderValue::from_str(&base64_encoder.encode(json!({"portalId":0,"productId":1,"customerId":"50","departmentIds":"","costsplaceId":"0","consumptionCategoryIds":"","consumptionTypeIds":"","taxationClusterId":"0","eanCode":"","year":now.year(),"month":now.month()}).to_string()))?),
-            Self::Mj => request.headers_mut().insert("request", HeaderValue::from_str(&base64_encoder.encode(json!({"portalId":"6","unitId":2,"customerIds":"50","yearFrom":now.year() - 1,"yearTill":now.year(),"reportType":"total"}).to_string()))?),
-            Self::Verbruik => request.headers_mut().insert("request", HeaderValue::from_str(&base64_encoder.encode(json!({"classificationId":0,"consumptioncategoryIds":"","consumptiontypeIds":"","costsplaceIds":"","customerIds":"50","portalCollectiveIds":"","datacheckreport":false,"departmentIds":"","eancode":"","energytaxIds":"","getODA":true,"monthFrom":1,"monthTill":12,"months":false,"portalId":"0","productId":1,"reportType":"total","yearFrom":now.year() - 1,"yearTill":now.year(),"isCollective":false}).to_string()))?),
-            Self::EnergieVerbruikPerUur(ids, start_date, end_date) => request.headers_mut().insert("request", HeaderValue::from_str(&base64_encoder.encode(json!({"meterId":[u32::from(*ids)],"IntermediateMeterId":0,"startDate":format!("{} 00:00", start_date.format("%Y-%m-%d")),"endDate":format!("{} 23:55", end_date.format("%Y-%m-%d")),"interval":"uur","chartType":"column","excel":true,"WeatherDataType":0,"productId":0}).to_string()))?),
+        // Send the request, only the response of the portal tells whether the report is still being generated
+        let response = match cookie_store.execute(request).await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() => return Err(Error::TimedOut(timeout.unwrap_or_default())),
+            Err(e) => return Err(e.into()),
         };
 
-        // Send the request
-        let response = client.execute(request).await?;
-
         // Make sure the request was successfull
-        if response.status() != reqwest::StatusCode::OK {
-            return Err(Error::NotOk(
-                response.status(),
-                "Failed to request latest version",
-            ));
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::ACCEPTED | reqwest::StatusCode::NO_CONTENT => {
+                return Err(Error::StillGenerating)
+            }
+            status => return Err(Error::NotOk(status, "Failed to request latest version")),
         }
 
         // Turn the response body (payload) into a string
@@ -155,16 +520,27 @@ impl Report {
         let body: serde_json::Value = serde_json::from_str(&body)?;
 
         // Turn json into an object
+        let body = body.as_object().ok_or(Error::NotAnObject)?;
+
         // Take the fileName property
-        // Turn the value into a string and make it an owned string
-        Ok(body
-            .as_object()
-            .ok_or(Error::NotAnObject)?
-            .get("fileName")
-            .ok_or(Error::KeyNotFound("fileName"))?
-            .as_str()
-            .ok_or(Error::ValueNotAString)?
-            .to_owned())
+        match body.get("fileName") {
+            // Turn the value into a string and make it an owned string
+            Some(serde_json::Value::String(file_name)) if !file_name.trim().is_empty() => {
+                Ok(file_name.to_owned())
+            }
+            Some(serde_json::Value::String(_) | serde_json::Value::Null) | None => {
+                // Without a filename the portal either failed or hasn't finished yet
+                match ["errorMessage", "error", "message"]
+                    .into_iter()
+                    .find_map(|key| body.get(key)?.as_str())
+                {
+                    Some(message) => Err(Error::GenerationFailed(message.to_owned())),
+                    None if body.contains_key("fileName") => Err(Error::StillGenerating),
+                    None => Err(Error::KeyNotFound("fileName")),
+                }
+            }
+            Some(_) => Err(Error::ValueNotAString),
+        }
     }
 
     /// Downloads the requested version.
@@ -178,31 +554,112 @@ impl Report {
     /// - If the file couldn't be created
     /// - If the response body couldn't be read
     /// - If the response body couldn't be written to the file
+    /// - If the file doesn't exist yet or is still empty
     pub async fn download_version(
         &self,
         cookie_store: &CookieStore,
         filename: &str,
     ) -> Result<Vec<u8>, Error> {
+        let response = Self::request_version(cookie_store, filename).await?;
+
+        // An empty file means it hasn't been written yet
+        let data = response.bytes().await?.to_vec();
+        if data.is_empty() {
+            return Err(Error::StillGenerating);
+        }
+        Ok(data)
+    }
+
+    /// Requests the file of a version and checks whether it's available
+    async fn request_version(
+        cookie_store: &CookieStore,
+        filename: &str,
+    ) -> Result<Response, Error> {
         // Create a request for the file
         let response = cookie_store
-            .client()
-            .get(format!(
-                "https://www.dbenergie.nl/Global/Download?fileName={filename}"
-            ))
-            .send()
+            .send(
+                cookie_store
+                    .client()
+                    .get(cookie_store.url(&format!("/Global/Download?fileName={filename}"))),
+            )
             .await?;
 
-        // Check whether the request was successfull
-        if response.status() != reqwest::StatusCode::OK {
-            Err(Error::NotOk(
-                response.status(),
-                "Failed to download requested version",
-            ))
-        } else {
-            Ok(response.bytes().await?.to_vec())
+        // Check whether the request was successfull, a missing file is an error instead of a file that isn't ready yet
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(response),
+            reqwest::StatusCode::ACCEPTED => Err(Error::StillGenerating),
+            status => Err(Error::NotOk(status, "Failed to download requested version")),
         }
     }
 
+    /// Downloads the requested version and streams it into the writer without buffering the whole file.
+    /// The progress is passed to the callback after every received chunk.
+    /// Returns the number of bytes that were written.
+    /// The client should contain the required cookies.
+    ///
+    /// # Errors
+    /// - If the request failed
+    /// - If the file doesn't exist yet or is still empty
+    /// - If the response body couldn't be read
+    /// - If the response body couldn't be written to the writer
+    pub async fn download_version_to(
+        &self,
+        cookie_store: &CookieStore,
+        filename: &str,
+        mut writer: impl AsyncWrite + Unpin + Send,
+        mut progress: impl FnMut(Progress) + Send,
+    ) -> Result<u64, Error> {
+        let response = Self::request_version(cookie_store, filename).await?;
+        let total = response.content_length();
+
+        // Write every chunk as soon as it's received
+        let mut received = 0;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            writer.write_all(&chunk).await?;
+            received += chunk.len() as u64;
+            progress(Progress::Downloading { received, total });
+        }
+        writer.flush().await?;
+
+        // An empty file means it hasn't been written yet
+        if received == 0 {
+            return Err(Error::StillGenerating);
+        }
+        Ok(received)
+    }
+
+    /// Downloads the requested version to the path.
+    /// The file is written to a temporary file next to the path first and renamed once it's complete,
+    /// so the path never contains a partially downloaded report.
+    /// Returns the number of bytes that were written.
+    /// The client should contain the required cookies.
+    ///
+    /// # Errors
+    /// - If the temporary file couldn't be created
+    /// - If downloading the version returns an error
+    /// - If the temporary file couldn't be renamed to the path
+    pub async fn download_version_to_path(
+        &self,
+        cookie_store: &CookieStore,
+        filename: &str,
+        path: &Path,
+        progress: impl FnMut(Progress) + Send,
+    ) -> Result<u64, Error> {
+        // Download the version to the temporary file, it's removed if the download fails or is cancelled
+        let temporary_file = TemporaryFile::new(path);
+        let mut file = tokio::fs::File::create(temporary_file.path()).await?;
+        let written = self
+            .download_version_to(cookie_store, filename, &mut file, progress)
+            .await?;
+        file.sync_all().await?;
+
+        // Move the file into place once it's complete
+        temporary_file.persist().await?;
+        Ok(written)
+    }
+
     /// Downloads the latest version of the report.
     /// The client should contain the required cookies.
     ///
@@ -220,4 +677,193 @@ impl Report {
         let response = self.download_version(cookie_store, &latest_version).await?;
         Ok((latest_version, response))
     }
+
+    /// Downloads the latest version of the report and waits for it while the portal is still generating it.
+    /// The progress is passed to the callback every time the state of the report is checked.
+    /// The client should contain the required cookies.
+    ///
+    /// # Errors
+    /// - If requesting the latest version returns an error other than [`Error::StillGenerating`]
+    /// - If downloading the version returns an error other than [`Error::StillGenerating`]
+    /// - If the request for the latest version takes longer than the request timeout
+    /// - If the report isn't available before the deadline
+    pub async fn download_latest_version_polling(
+        &self,
+        cookie_store: &CookieStore,
+        polling: &Polling,
+        mut progress: impl FnMut(Progress) + Send,
+    ) -> Result<(String, Vec<u8>), Error> {
+        let mut waiting = Waiting::new(polling);
+        let latest_version = self
+            .poll_latest_version(cookie_store, &mut waiting, &mut progress)
+            .await?;
+
+        // Download the version once the file exists
+        loop {
+            match self.download_version(cookie_store, &latest_version).await {
+                Ok(data) => return Ok((latest_version, data)),
+                Err(Error::StillGenerating) => waiting.wait(&mut progress).await?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Downloads the latest version of the report to a temporary file in the directory,
+    /// and waits for it while the portal is still generating it.
+    /// The report is streamed to the file without buffering it, the file is removed once the returned [`TemporaryFile`] is dropped.
+    /// Returns the filename on the portal, the file and its size.
+    /// The client should contain the required cookies.
+    ///
+    /// # Errors
+    /// - If the temporary file couldn't be created or written
+    /// - If any of the errors of [`Report::download_latest_version_polling`] occurs
+    pub async fn download_latest_version_polling_to(
+        &self,
+        cookie_store: &CookieStore,
+        polling: &Polling,
+        directory: &Path,
+        mut progress: impl FnMut(Progress) + Send,
+    ) -> Result<(String, TemporaryFile, u64), Error> {
+        let mut waiting = Waiting::new(polling);
+        let latest_version = self
+            .poll_latest_version(cookie_store, &mut waiting, &mut progress)
+            .await?;
+
+        // Only the name of the file on the portal is used, it never points outside of the directory
+        let file_name = Path::new(&latest_version)
+            .file_name()
+            .map_or_else(|| PathBuf::from("report.xlsx"), PathBuf::from);
+
+        // Download the version once the file exists, a partial file is removed before the next attempt
+        loop {
+            let file = TemporaryFile::new(&directory.join(&file_name));
+            let download = async {
+                let mut writer = tokio::fs::File::create(file.path()).await?;
+                self.download_version_to(cookie_store, &latest_version, &mut writer, &mut progress)
+                    .await
+            };
+            match download.await {
+                Ok(size) => return Ok((latest_version, file, size)),
+                Err(Error::StillGenerating) => waiting.wait(&mut progress).await?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Requests the latest version until the portal has a filename for it
+    async fn poll_latest_version(
+        &self,
+        cookie_store: &CookieStore,
+        waiting: &mut Waiting<'_>,
+        progress: &mut (impl FnMut(Progress) + Send),
+    ) -> Result<String, Error> {
+        progress(Progress::Requested);
+        let latest_version = loop {
+            match self
+                .request_latest_version(cookie_store, Some(waiting.polling.request_timeout))
+                .await
+            {
+                Ok(latest_version) => break latest_version,
+                Err(Error::StillGenerating) => waiting.wait(progress).await?,
+                Err(e) => return Err(e),
+            }
+        };
+        progress(Progress::Ready(latest_version.clone()));
+        Ok(latest_version)
+    }
+}
+
+/// Keeps track of the checks while waiting for a report that's being generated
+struct Waiting<'a> {
+    polling: &'a Polling,
+    start: Instant,
+    attempt: u32,
+}
+
+impl<'a> Waiting<'a> {
+    fn new(polling: &'a Polling) -> Self {
+        Self {
+            polling,
+            start: Instant::now(),
+            attempt: 0,
+        }
+    }
+
+    /// Waits before the next check, unless the deadline has passed
+    async fn wait(&mut self, progress: &mut (impl FnMut(Progress) + Send)) -> Result<(), Error> {
+        self.attempt += 1;
+        let elapsed = self.start.elapsed();
+        if elapsed >= self.polling.deadline {
+            return Err(Error::DeadlineExceeded(self.polling.deadline));
+        }
+        progress(Progress::Generating {
+            attempt: self.attempt,
+            elapsed,
+        });
+        tokio::time::sleep(self.polling.interval.min(self.polling.deadline - elapsed)).await;
+        Ok(())
+    }
+}
+
+/// Counts the temporary files of this process, so every temporary file has its own name
+static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the path of a hidden temporary file that's used while writing to the path.
+/// Every call returns a new path, so writers to the same path don't overwrite each others temporary files.
+#[must_use]
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut file_name = std::ffi::OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_default());
+    file_name.push(format!(
+        ".{}-{}.part",
+        std::process::id(),
+        TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(file_name)
+}
+
+/// The [`temporary_path`] of a file that's being written.
+/// The temporary file is removed when this is dropped before it's persisted,
+/// so writes that fail or are cancelled don't leave partial files behind.
+#[derive(Debug)]
+pub struct TemporaryFile {
+    path: PathBuf,
+    target: PathBuf,
+    persisted: bool,
+}
+
+impl TemporaryFile {
+    /// Creates the temporary file for the target path, the file itself is created by the writer
+    #[must_use]
+    pub fn new(target: &Path) -> Self {
+        Self {
+            path: temporary_path(target),
+            target: target.to_owned(),
+            persisted: false,
+        }
+    }
+
+    /// Returns the path to write to
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the temporary file to the target path
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be moved, the temporary file is removed in that case
+    pub async fn persist(mut self) -> Result<(), std::io::Error> {
+        tokio::fs::rename(&self.path, &self.target).await?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TemporaryFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
 }
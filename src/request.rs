@@ -0,0 +1,278 @@
+use std::fmt::Display;
+
+use base64::Engine;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+
+/// The characters that are encoded in cookies, the same as `encodeURIComponent` in the browser
+const COOKIE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'!')
+    .remove(b'~')
+    .remove(b'*')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')');
+
+/// Errors that can occur while decoding a payload
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Base64(#[from] base64::DecodeError),
+    Json(#[from] serde_json::Error),
+    Utf8(#[from] std::str::Utf8Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// The parameters of a report, the portal expects them as base64 encoded json in the `request` header
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ReportRequest {
+    /// The connection list is requested with `false`
+    Flag(bool),
+
+    /// The year of an exported list, like the aansluitinglijst
+    Year(i32),
+
+    /// The usage of a meter, like the energie verbruik per uur
+    Analyze(AnalyzeRequest),
+
+    /// The data quality of a month
+    DataQuality(DataQualityRequest),
+
+    /// The usage per product
+    Consumption(ConsumptionRequest),
+
+    /// The usage in a unit, like CO2 or MJ
+    Unit(UnitRequest),
+}
+
+impl ReportRequest {
+    /// Returns the name of the variant, to describe decoded payloads
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Flag(_) => "Flag",
+            Self::Year(_) => "Year",
+            Self::Analyze(_) => "Analyze",
+            Self::DataQuality(_) => "DataQuality",
+            Self::Consumption(_) => "Consumption",
+            Self::Unit(_) => "Unit",
+        }
+    }
+
+    /// Returns the value of the `request` header
+    ///
+    /// # Errors
+    /// Returns an error if the request couldn't be serialized
+    pub fn encode(&self) -> Result<String, serde_json::Error> {
+        Ok(base64::engine::general_purpose::STANDARD.encode(serde_json::to_string(self)?))
+    }
+
+    /// Decodes the value of a `request` header
+    ///
+    /// # Errors
+    /// Returns an error if the value isn't base64 or doesn't contain the json of a known request
+    pub fn decode(header: &str) -> Result<Self, Error> {
+        let json = base64::engine::general_purpose::STANDARD.decode(header.trim())?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+impl Display for ReportRequest {
+    /// Writes the request as compact json, the way it's encoded
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+/// The usage of a meter per interval, `/Report/Analyze/GetDownload`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AnalyzeRequest {
+    pub meter_id: Vec<u32>,
+    #[serde(rename = "IntermediateMeterId")]
+    pub intermediate_meter_id: u32,
+
+    /// The first moment, like `2024-01-01 00:00`
+    pub start_date: String,
+
+    /// The last moment, like `2024-01-31 23:55`
+    pub end_date: String,
+    pub interval: String,
+    pub chart_type: String,
+    pub excel: bool,
+    #[serde(rename = "WeatherDataType")]
+    pub weather_data_type: u32,
+    pub product_id: u32,
+}
+
+impl AnalyzeRequest {
+    /// Returns the request for the hourly usage of the meter, from the start of the first day until the end of the last
+    #[must_use]
+    pub fn hourly(
+        meter_id: u32,
+        start_date: chrono::NaiveDate,
+        end_date: chrono::NaiveDate,
+    ) -> Self {
+        Self {
+            meter_id: vec![meter_id],
+            intermediate_meter_id: 0,
+            start_date: format!("{} 00:00", start_date.format("%Y-%m-%d")),
+            end_date: format!("{} 23:55", end_date.format("%Y-%m-%d")),
+            interval: "uur".to_owned(),
+            chart_type: "column".to_owned(),
+            excel: true,
+            weather_data_type: 0,
+            product_id: 0,
+        }
+    }
+}
+
+/// The data quality of the connections of a customer, `/Report/DataEntiretyCheck/GetDataToDownload`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DataQualityRequest {
+    pub portal_id: u32,
+    pub product_id: u32,
+    pub customer_id: String,
+    pub department_ids: String,
+    pub costsplace_id: String,
+    pub consumption_category_ids: String,
+    pub consumption_type_ids: String,
+    pub taxation_cluster_id: String,
+    pub ean_code: String,
+    pub year: i32,
+    pub month: u32,
+}
+
+impl DataQualityRequest {
+    /// Returns the request for the data quality of every connection of the customer in the month
+    #[must_use]
+    pub fn month(customer_id: String, year: i32, month: u32) -> Self {
+        Self {
+            portal_id: 0,
+            product_id: 1,
+            customer_id,
+            department_ids: String::new(),
+            costsplace_id: "0".to_owned(),
+            consumption_category_ids: String::new(),
+            consumption_type_ids: String::new(),
+            taxation_cluster_id: "0".to_owned(),
+            ean_code: String::new(),
+            year,
+            month,
+        }
+    }
+}
+
+/// The usage per product of the customers, `/Report/Consumption/GetDownload`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ConsumptionRequest {
+    pub classification_id: u32,
+    pub consumptioncategory_ids: String,
+    pub consumptiontype_ids: String,
+    pub costsplace_ids: String,
+
+    /// The customers as a comma separated list
+    pub customer_ids: String,
+    pub portal_collective_ids: String,
+    pub datacheckreport: bool,
+    pub department_ids: String,
+    pub eancode: String,
+    pub energytax_ids: String,
+    #[serde(rename = "getODA")]
+    pub get_oda: bool,
+    pub month_from: u32,
+    pub month_till: u32,
+    pub months: bool,
+    pub portal_id: String,
+    pub product_id: u32,
+    pub report_type: String,
+    pub year_from: i32,
+    pub year_till: i32,
+    pub is_collective: bool,
+}
+
+impl ConsumptionRequest {
+    /// Returns the request for the total usage of the customers in the year and the year before it
+    #[must_use]
+    pub fn total(customer_ids: String, year: i32) -> Self {
+        Self {
+            classification_id: 0,
+            consumptioncategory_ids: String::new(),
+            consumptiontype_ids: String::new(),
+            costsplace_ids: String::new(),
+            customer_ids,
+            portal_collective_ids: String::new(),
+            datacheckreport: false,
+            department_ids: String::new(),
+            eancode: String::new(),
+            energytax_ids: String::new(),
+            get_oda: true,
+            month_from: 1,
+            month_till: 12,
+            months: false,
+            portal_id: "0".to_owned(),
+            product_id: 1,
+            report_type: "total".to_owned(),
+            year_from: year - 1,
+            year_till: year,
+            is_collective: false,
+        }
+    }
+}
+
+/// The usage of the customers in a unit, `/Report/Co2/GetDownload`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct UnitRequest {
+    pub portal_id: String,
+
+    /// The unit of the usage, 1 is CO2 and 2 is MJ
+    pub unit_id: u32,
+
+    /// The customers as a comma separated list
+    pub customer_ids: String,
+    pub year_from: i32,
+    pub year_till: i32,
+    pub report_type: String,
+}
+
+impl UnitRequest {
+    /// Returns the request for the total usage of the customers in the unit, in the year and the year before it
+    #[must_use]
+    pub fn total(unit_id: u32, customer_ids: String, year: i32) -> Self {
+        Self {
+            portal_id: "6".to_owned(),
+            unit_id,
+            customer_ids,
+            year_from: year - 1,
+            year_till: year,
+            report_type: "total".to_owned(),
+        }
+    }
+}
+
+/// Url encodes the json of a cookie, like the `PersonalFilter` cookie
+#[must_use]
+pub fn encode_cookie(json: &str) -> String {
+    percent_encoding::utf8_percent_encode(json, COOKIE).to_string()
+}
+
+/// Decodes the json of an url encoded cookie
+///
+/// # Errors
+/// Returns an error if the decoded cookie isn't utf-8 or json
+pub fn decode_cookie(cookie: &str) -> Result<serde_json::Value, Error> {
+    let json = percent_encoding::percent_decode_str(cookie.trim()).decode_utf8()?;
+    Ok(serde_json::from_str(&json)?)
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::report;
+
+/// The number of years that's searched for the next time of a schedule, every date falls on every weekday within it
+const SEARCH_YEARS: i64 = 28;
+
+/// Errors that can occur while parsing a schedule or reading the state
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Io(#[from] std::io::Error),
+    Json(#[from] serde_json::Error),
+
+    /// The cron expression is invalid for the contained reason
+    InvalidExpression(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidExpression(reason) => write!(f, "{reason}"),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+/// A field of a cron expression, the values that match are stored as bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    bits: u64,
+
+    /// Whether the field starts with `*`, like `*` or `*/2`, which matters for the day of the month and week
+    any: bool,
+}
+
+impl Field {
+    const fn contains(self, value: u32) -> bool {
+        self.bits & (1 << value) != 0
+    }
+
+    /// Parses a field like `*`, `*/15`, `1,15`, `1-5` or `mon-fri` with values between min and max
+    fn parse(field: &str, name: &str, min: u32, max: u32, names: &[&str]) -> Result<Self, Error> {
+        let invalid = || Error::InvalidExpression(format!("Invalid {name} field {field}"));
+        let value = |value: &str| -> Result<u32, Error> {
+            let index = names
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(value));
+            let value = match index {
+                Some(index) => u32::try_from(index).map_err(|_| invalid())? + min,
+                None => value.parse().map_err(|_| invalid())?,
+            };
+            if (min..=max).contains(&value) {
+                Ok(value)
+            } else {
+                Err(Error::InvalidExpression(format!(
+                    "{value} is outside of {min}-{max} in the {name} field"
+                )))
+            }
+        };
+
+        let mut bits = 0;
+        for part in field.split(',') {
+            // Split the step from the range
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+                None => (part, 1),
+            };
+            if step == 0 {
+                return Err(invalid());
+            }
+            let (start, end) = match range {
+                "*" => (min, max),
+                range => match range.split_once('-') {
+                    Some((start, end)) => (value(start)?, value(end)?),
+                    // A single value with a step runs until the maximum, like 5/15
+                    None if part.contains('/') => (value(range)?, max),
+                    None => (value(range)?, value(range)?),
+                },
+            };
+            if start > end {
+                return Err(invalid());
+            }
+            for value in (start..=end).step_by(step as usize) {
+                bits |= 1 << value;
+            }
+        }
+        Ok(Self {
+            bits,
+            any: field.starts_with('*'),
+        })
+    }
+}
+
+/// A cron expression with the fields minute, hour, day of the month, month and day of the week, in local time.
+/// The shortcuts `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` can be used as well.
+///
+/// Like in cron, a day matches if either the day of the month or the day of the week matches,
+/// unless one of them starts with `*`, like `*/2`.
+/// Expressions that never run, like `0 0 30 2 *`, are invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    expression: String,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl FromStr for Schedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expression = match s.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expression => expression,
+        };
+        let [minutes, hours, days, months, weekdays] = expression
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| {
+                Error::InvalidExpression(format!(
+                    "Expected 5 fields (minute hour day month weekday) in {s}"
+                ))
+            })?;
+        let mut weekdays = Field::parse(
+            weekdays,
+            "weekday",
+            0,
+            7,
+            &["sun", "mon", "tue", "wed", "thu", "fri", "sat"],
+        )?;
+
+        // Both 0 and 7 are sunday
+        if weekdays.contains(7) {
+            weekdays.bits |= 1;
+        }
+        let schedule = Self {
+            expression: s.trim().to_owned(),
+            minutes: Field::parse(minutes, "minute", 0, 59, &[])?,
+            hours: Field::parse(hours, "hour", 0, 23, &[])?,
+            days: Field::parse(days, "day", 1, 31, &[])?,
+            months: Field::parse(
+                months,
+                "month",
+                1,
+                12,
+                &[
+                    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov",
+                    "dec",
+                ],
+            )?,
+            weekdays,
+        };
+        if schedule.next_after(Utc::now()).is_none() {
+            return Err(Error::InvalidExpression(format!("{s} never runs")));
+        }
+        Ok(schedule)
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl Schedule {
+    /// Checks whether the schedule runs on the day
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !self.months.contains(date.month()) {
+            return false;
+        }
+        let day = self.days.contains(date.day());
+        let weekday = self
+            .weekdays
+            .contains(date.weekday().num_days_from_sunday());
+        match (self.days.any, self.weekdays.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// Returns the first time the schedule runs after the time.
+    /// Times that don't exist because of daylight saving time run an hour later,
+    /// times that exist twice run the first time.
+    #[must_use]
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.next_after_in(time, &Local)
+    }
+
+    /// Returns the first time the schedule runs after the time, with the expression in the time zone
+    fn next_after_in<Tz: TimeZone>(
+        &self,
+        time: DateTime<Utc>,
+        timezone: &Tz,
+    ) -> Option<DateTime<Utc>> {
+        let time = time.with_timezone(timezone);
+        let start = time.date_naive();
+        for date in start.iter_days().take_while(|date| {
+            date.signed_duration_since(start) < Duration::days(366 * SEARCH_YEARS)
+        }) {
+            if !self.matches_date(date) {
+                continue;
+            }
+            for hour in (0..24).filter(|hour| self.hours.contains(*hour)) {
+                for minute in (0..60).filter(|minute| self.minutes.contains(*minute)) {
+                    let Some(next) = date.and_hms_opt(hour, minute, 0).and_then(|next| {
+                        timezone.from_local_datetime(&next).earliest().or_else(|| {
+                            timezone
+                                .from_local_datetime(&(next + Duration::hours(1)))
+                                .earliest()
+                        })
+                    }) else {
+                        continue;
+                    };
+                    if next > time {
+                        return Some(next.with_timezone(&Utc));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The time of the last run of every schedule, so missed runs can be caught up after a restart
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    pub last_runs: BTreeMap<String, DateTime<Utc>>,
+
+    /// The file the state is stored in
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+impl State {
+    /// Reads the state from the file, a missing file is an empty state
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be read or isn't valid json
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut state: Self = match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        state.path = path.to_owned();
+        Ok(state)
+    }
+
+    /// Writes the state to its file, the previous state is only replaced once the new one is complete
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be written
+    pub fn store(&self) -> Result<(), Error> {
+        let temporary_path = report::temporary_path(&self.path);
+        std::fs::write(&temporary_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(temporary_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono_tz::Europe::Amsterdam;
+
+    use super::*;
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .single()
+            .expect("Invalid time")
+    }
+
+    fn next(expression: &str, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        Schedule::from_str(expression)
+            .expect("Invalid expression")
+            .next_after_in(time, &Utc)
+    }
+
+    #[test]
+    fn next_runs() {
+        for (expression, time, expected) in [
+            // Steps
+            (
+                "*/15 * * * *",
+                utc(2024, 1, 1, 10, 7),
+                utc(2024, 1, 1, 10, 15),
+            ),
+            (
+                "5/20 * * * *",
+                utc(2024, 1, 1, 10, 46),
+                utc(2024, 1, 1, 11, 5),
+            ),
+            // Ranges, with and without a step
+            (
+                "0 9-17/4 * * *",
+                utc(2024, 1, 1, 10, 0),
+                utc(2024, 1, 1, 13, 0),
+            ),
+            (
+                "0 9-17/4 * * *",
+                utc(2024, 1, 1, 17, 30),
+                utc(2024, 1, 2, 9, 0),
+            ),
+            (
+                "0 0 * * mon-fri",
+                utc(2024, 1, 6, 0, 0),
+                utc(2024, 1, 8, 0, 0),
+            ),
+            (
+                "0 0 1 jun-aug *",
+                utc(2024, 1, 1, 0, 0),
+                utc(2024, 6, 1, 0, 0),
+            ),
+            // Lists
+            (
+                "30 8 1,15 * *",
+                utc(2024, 1, 2, 0, 0),
+                utc(2024, 1, 15, 8, 30),
+            ),
+            (
+                "0 0 * * sat,1-2",
+                utc(2024, 1, 3, 0, 0),
+                utc(2024, 1, 6, 0, 0),
+            ),
+            // Both 0 and 7 are sunday
+            ("0 0 * * 7", utc(2024, 1, 1, 0, 0), utc(2024, 1, 7, 0, 0)),
+            ("0 0 * * 0", utc(2024, 1, 1, 0, 0), utc(2024, 1, 7, 0, 0)),
+            // Either the day of the month or the day of the week matches
+            ("0 0 13 * fri", utc(2024, 1, 1, 0, 0), utc(2024, 1, 5, 0, 0)),
+            ("0 0 31 2 mon", utc(2024, 1, 1, 0, 0), utc(2024, 2, 5, 0, 0)),
+            // Unless one of them starts with *, then both match
+            (
+                "0 0 */2 * mon",
+                utc(2024, 1, 1, 0, 0),
+                utc(2024, 1, 15, 0, 0),
+            ),
+            ("0 0 13 * *", utc(2024, 1, 1, 0, 0), utc(2024, 1, 13, 0, 0)),
+            ("0 0 * * fri", utc(2024, 1, 1, 0, 0), utc(2024, 1, 5, 0, 0)),
+            // Days that don't exist in every month or year
+            (
+                "0 0 31 * *",
+                utc(2024, 1, 31, 12, 0),
+                utc(2024, 3, 31, 0, 0),
+            ),
+            ("0 0 29 2 *", utc(2024, 3, 1, 0, 0), utc(2028, 2, 29, 0, 0)),
+            // Shortcuts
+            ("@hourly", utc(2024, 1, 1, 10, 0), utc(2024, 1, 1, 11, 0)),
+            ("@weekly", utc(2024, 1, 1, 0, 0), utc(2024, 1, 7, 0, 0)),
+            ("@monthly", utc(2024, 1, 15, 0, 0), utc(2024, 2, 1, 0, 0)),
+            ("@yearly", utc(2024, 1, 1, 0, 0), utc(2025, 1, 1, 0, 0)),
+        ] {
+            assert_eq!(next(expression, time), Some(expected), "{expression}");
+        }
+    }
+
+    #[test]
+    fn daylight_saving_time() {
+        let schedule = Schedule::from_str("30 2 * * *").expect("Invalid expression");
+
+        // 02:30 doesn't exist on the last sunday of march, it runs an hour later
+        assert_eq!(
+            schedule.next_after_in(utc(2024, 3, 30, 12, 0), &Amsterdam),
+            Some(utc(2024, 3, 31, 1, 30))
+        );
+
+        // 02:30 exists twice on the last sunday of october, it runs the first time
+        assert_eq!(
+            schedule.next_after_in(utc(2024, 10, 26, 12, 0), &Amsterdam),
+            Some(utc(2024, 10, 27, 0, 30))
+        );
+        assert_eq!(
+            schedule.next_after_in(utc(2024, 10, 27, 0, 30), &Amsterdam),
+            Some(utc(2024, 10, 28, 1, 30))
+        );
+
+        // The expression is in local time, so a daily run moves in utc
+        let schedule = Schedule::from_str("0 2 * * *").expect("Invalid expression");
+        assert_eq!(
+            schedule.next_after_in(utc(2024, 1, 1, 12, 0), &Amsterdam),
+            Some(utc(2024, 1, 2, 1, 0))
+        );
+        assert_eq!(
+            schedule.next_after_in(utc(2024, 7, 1, 12, 0), &Amsterdam),
+            Some(utc(2024, 7, 2, 0, 0))
+        );
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expression in [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "0 0 0 * *",
+            "0 0 * 13 *",
+            "0 0 * * 8",
+            "0 0 * * funday",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "*/x * * * *",
+            "@often",
+        ] {
+            assert!(Schedule::from_str(expression).is_err(), "{expression}");
+        }
+    }
+
+    #[test]
+    fn schedules_that_never_run_are_invalid() {
+        for expression in ["0 0 30 2 *", "0 0 31 4,6,9,11 *", "0 0 30-31 feb *"] {
+            let error = Schedule::from_str(expression).expect_err(expression);
+            assert_eq!(error.to_string(), format!("{expression} never runs"));
+        }
+    }
+}
@@ -0,0 +1,949 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{Debug, Display},
+    io as std_io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, PoisonError},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use sha2::{Digest as _, Sha256};
+use tokio::io::{AsyncRead, AsyncWriteExt as _};
+use url::Url;
+
+use crate::{
+    convert::{self, Format},
+    database::{self, Database, Download},
+    ean::Ean,
+    id::Id,
+    report::{Report, TemporaryFile},
+    usage,
+};
+
+mod manifest;
+mod s3;
+mod template;
+
+pub use manifest::{Entry, Manifest, Problem, MANIFEST_FILE_NAME};
+pub use s3::{S3Options, S3Sink};
+pub use template::Template;
+
+/// Errors that can occur while saving a report
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Io(#[from] std_io::Error),
+    Request(#[from] reqwest::Error),
+    Convert(#[from] convert::Error),
+    Usage(#[from] usage::Error),
+    Database(#[from] database::Error),
+    Join(#[from] tokio::task::JoinError),
+    Json(#[from] serde_json::Error),
+    UnsupportedOutput(String),
+    InvalidTemplate(String),
+    NotOk(reqwest::StatusCode),
+
+    /// The object storage returned an error with the contained status and message
+    S3(reqwest::StatusCode, String),
+
+    /// The response of the object storage didn't contain the value
+    InvalidS3Response(&'static str),
+
+    /// Some of the sinks of a tee failed
+    Tee(Vec<Error>),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl Error {
+    /// Returns the name of the variant, to group errors by their kind
+    #[must_use]
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "Io",
+            Self::Request(_) => "Request",
+            Self::Convert(_) => "Convert",
+            Self::Usage(_) => "Usage",
+            Self::Database(_) => "Database",
+            Self::Join(_) => "Join",
+            Self::Json(_) => "Json",
+            Self::UnsupportedOutput(_) => "UnsupportedOutput",
+            Self::InvalidTemplate(_) => "InvalidTemplate",
+            Self::NotOk(_) => "NotOk",
+            Self::S3(_, _) => "S3",
+            Self::InvalidS3Response(_) => "InvalidS3Response",
+            Self::Tee(_) => "Tee",
+        }
+    }
+}
+
+/// A reader that can also seek, like a file or a cursor
+pub trait ReadSeek: std_io::Read + std_io::Seek + Send {}
+
+impl<T: std_io::Read + std_io::Seek + Send> ReadSeek for T {}
+
+/// The data of a document, either in memory or in a temporary file
+#[derive(Debug, Clone)]
+pub enum Content {
+    /// The data in memory
+    Bytes(Vec<u8>),
+
+    /// The data in a temporary file, the file is removed once every clone of the content is dropped
+    File { file: Arc<TemporaryFile>, size: u64 },
+}
+
+impl Content {
+    /// Returns the content of a downloaded temporary file with the size
+    #[must_use]
+    pub fn file(file: TemporaryFile, size: u64) -> Self {
+        Self::File {
+            file: Arc::new(file),
+            size,
+        }
+    }
+
+    /// Returns the size of the data in bytes
+    #[must_use]
+    pub fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(data) => data.len() as u64,
+            Self::File { size, .. } => *size,
+        }
+    }
+
+    /// Returns true if there is no data
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a reader of the data, a file is read from the start
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be opened
+    pub fn reader(&self) -> Result<Box<dyn ReadSeek + '_>, std_io::Error> {
+        match self {
+            Self::Bytes(data) => Ok(Box::new(std_io::Cursor::new(data.as_slice()))),
+            Self::File { file, .. } => Ok(Box::new(std_io::BufReader::new(std::fs::File::open(
+                file.path(),
+            )?))),
+        }
+    }
+
+    /// Returns a reader of the data for async code, a file is read from the start
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be opened
+    pub async fn async_reader(&self) -> Result<Pin<Box<dyn AsyncRead + Send + '_>>, std_io::Error> {
+        match self {
+            Self::Bytes(data) => Ok(Box::pin(data.as_slice())),
+            Self::File { file, .. } => Ok(Box::pin(tokio::io::BufReader::new(
+                tokio::fs::File::open(file.path()).await?,
+            ))),
+        }
+    }
+
+    /// Returns the data in memory, a file is read completely
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be read
+    pub async fn to_bytes(&self) -> Result<Vec<u8>, std_io::Error> {
+        match self {
+            Self::Bytes(data) => Ok(data.clone()),
+            Self::File { file, .. } => tokio::fs::read(file.path()).await,
+        }
+    }
+
+    /// Returns the SHA-256 hash of the data as hex, a file is hashed without reading it into memory
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be read
+    pub fn sha256(&self) -> Result<String, std_io::Error> {
+        let mut hasher = Sha256::new();
+        std_io::copy(&mut self.reader()?, &mut hasher)?;
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+impl From<Vec<u8>> for Content {
+    fn from(data: Vec<u8>) -> Self {
+        Self::Bytes(data)
+    }
+}
+
+/// A downloaded report that should be saved
+#[derive(Debug, Clone)]
+pub struct Document {
+    /// The downloaded report
+    pub report: Report,
+
+    /// The meter the report belongs to, if it's for a single meter
+    pub ean: Option<Ean>,
+
+    /// The id of the meter the report belongs to, if it's for a single meter
+    pub id: Option<Id>,
+
+    /// The name of the file on the portal
+    pub file_name: String,
+
+    /// The xlsx file as it's returned by the portal
+    pub data: Content,
+
+    /// The time the report was downloaded
+    pub downloaded_at: DateTime<Utc>,
+}
+
+impl Document {
+    /// Returns the name the document is saved under in the format.
+    /// The name is the filename on the portal, prefixed by the ean if the report belongs to a meter.
+    /// The filename can't add directories, so the document is always saved in the output directory.
+    #[must_use]
+    pub fn output_name(&self, format: Format) -> String {
+        let file_name =
+            Path::new(&template::sanitize(&self.file_name)).with_extension(format.extension());
+        match &self.ean {
+            Some(ean) => format!("{ean}_{}", file_name.display()),
+            None => file_name.display().to_string(),
+        }
+    }
+
+    /// Returns the relative path the document is saved under in the format, with `/` as separator.
+    /// The path is created from the template if there is one, otherwise it's the [`Document::output_name`].
+    #[must_use]
+    pub fn output_path(&self, format: Format, template: Option<&Template>) -> String {
+        template.map_or_else(
+            || self.output_name(format),
+            |template| template.render(self, format),
+        )
+    }
+
+    /// Returns a key that identifies this download of the report
+    #[must_use]
+    pub fn key(&self) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            self.report.name(),
+            self.ean.as_ref().map_or("", Ean::value),
+            self.file_name
+                .chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect::<String>(),
+            self.downloaded_at.timestamp_millis()
+        )
+    }
+
+    /// Converts the data to the format, xlsx data is returned as is
+    ///
+    /// # Errors
+    /// Returns an error if the data couldn't be read or converted
+    pub fn encode(&self, format: Format) -> Result<Content, Error> {
+        match format {
+            Format::Xlsx => Ok(self.data.clone()),
+            format => Ok(Content::Bytes(convert::convert_reader(
+                &self.report,
+                self.data.reader()?,
+                format,
+            )?)),
+        }
+    }
+}
+
+/// What happened to a saved document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Saved {
+    /// The document was written or sent
+    Written,
+
+    /// The same content was saved before, so the document was skipped
+    Unchanged,
+
+    /// The sink doesn't store this kind of report, so the document was skipped
+    Skipped,
+}
+
+/// A destination for downloaded reports
+#[async_trait]
+pub trait Sink: Debug + Send + Sync {
+    /// Saves the document
+    ///
+    /// # Errors
+    /// Returns an error if the document couldn't be saved
+    async fn save(&self, document: &Document) -> Result<Saved, Error>;
+
+    /// Checks whether documents can be saved, without saving a document.
+    /// Sinks that can't be checked without side effects are assumed to be writable.
+    ///
+    /// # Errors
+    /// Returns an error if the sink isn't writable
+    async fn check(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Describes where the document would be saved, without saving it
+    fn destinations(&self, document: &Document) -> Vec<String>;
+}
+
+/// The manifest of every directory, it's loaded by the first save to the directory.
+/// Only one save at a time reads and writes the manifest of a directory.
+type Manifests = BTreeMap<PathBuf, Arc<tokio::sync::Mutex<Option<Manifest>>>>;
+static MANIFESTS: std::sync::Mutex<Manifests> = std::sync::Mutex::new(BTreeMap::new());
+
+/// Writes every report to a file in a directory
+#[derive(Debug, Clone)]
+pub struct DirectorySink {
+    pub directory: PathBuf,
+    pub format: Format,
+
+    /// The path of the files in the directory, missing directories are created
+    pub template: Option<Template>,
+
+    /// Keep a [`Manifest`] of the saved files in the directory and skip reports with unchanged content
+    pub manifest: bool,
+}
+
+impl DirectorySink {
+    /// Returns the manifest of the directory
+    fn manifest(&self) -> Arc<tokio::sync::Mutex<Option<Manifest>>> {
+        MANIFESTS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(self.directory.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Writes the data to the relative path
+    async fn write(&self, path: &str, data: &Content) -> Result<(), Error> {
+        // Create the requested directory and the directories of the template
+        let path = path
+            .split('/')
+            .fold(self.directory.clone(), |path, part| path.join(part));
+        tokio::fs::create_dir_all(path.parent().unwrap_or(&self.directory)).await?;
+
+        // Write the report to a temporary file
+        let temporary_file = TemporaryFile::new(&path);
+        let mut file = tokio::fs::File::create(temporary_file.path()).await?;
+        tokio::io::copy(&mut data.async_reader().await?, &mut file).await?;
+        file.sync_all().await?;
+
+        // Move it into place once it's complete
+        temporary_file.persist().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Sink for DirectorySink {
+    async fn save(&self, document: &Document) -> Result<Saved, Error> {
+        let data = document.encode(self.format)?;
+        let path = document.output_path(self.format, self.template.as_ref());
+        if !self.manifest {
+            self.write(&path, &data).await?;
+            return Ok(Saved::Written);
+        }
+
+        // Skip the report if the same content has been saved before
+        let manifest = self.manifest();
+        let mut loaded = manifest.lock().await;
+        let manifest = match loaded.take() {
+            Some(manifest) => manifest,
+            None => Manifest::load(&self.directory).await?,
+        };
+        let manifest = loaded.insert(manifest);
+        let entry = Entry::new(document, path, &data)?;
+        if manifest.contains(&self.directory, &entry).await {
+            return Ok(Saved::Unchanged);
+        }
+
+        // Only add the file to the manifest once it's written
+        self.write(&entry.path, &data).await?;
+        let appended = manifest.append(&self.directory, entry).await;
+        if appended.is_err() {
+            // Load the manifest again, the file doesn't contain every entry that's in memory
+            *loaded = None;
+        }
+        appended?;
+        Ok(Saved::Written)
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        // Create and remove an empty file, the directory is created if it doesn't exist yet
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let path = self.directory.join(".rapportage-check");
+        tokio::fs::write(&path, b"").await?;
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    fn destinations(&self, document: &Document) -> Vec<String> {
+        let path = document.output_path(self.format, self.template.as_ref());
+        let path = path
+            .split('/')
+            .fold(self.directory.clone(), |path, part| path.join(part));
+        vec![path.display().to_string()]
+    }
+}
+
+/// The credentials that are sent to the server
+#[derive(Clone)]
+pub enum Auth {
+    Bearer(String),
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+impl Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the credentials
+        match self {
+            Self::Bearer(_) => write!(f, "Bearer(..)"),
+            Self::Basic { username, .. } => write!(f, "Basic({username}, ..)"),
+        }
+    }
+}
+
+/// Settings for sending reports to a server
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    /// The credentials to send with every request
+    pub auth: Option<Auth>,
+
+    /// The name of the form field that contains the file
+    pub field_name: String,
+
+    /// Extra form fields that are sent with every file
+    pub fields: Vec<(String, String)>,
+
+    /// The number of times a failed upload is retried
+    pub retries: u32,
+
+    /// The delay before the first retry, it doubles for every next retry
+    pub retry_delay: Duration,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            auth: None,
+            field_name: "file".to_owned(),
+            fields: Vec::new(),
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Sends every report as a multipart form to a url.
+/// Besides the file, the form contains the ean, id, report type and date range of the report.
+#[derive(Debug, Clone)]
+pub struct HttpSink {
+    pub client: Client,
+    pub url: Url,
+    pub format: Format,
+    pub options: HttpOptions,
+}
+
+impl HttpSink {
+    /// Creates the form with the file and its metadata, a file is streamed instead of read into memory
+    async fn form(
+        &self,
+        document: &Document,
+        data: &Content,
+    ) -> Result<reqwest::multipart::Form, Error> {
+        let mut form = reqwest::multipart::Form::new()
+            .text("report", document.report.name())
+            .text("downloaded_at", document.downloaded_at.to_rfc3339());
+        if let Some(ean) = &document.ean {
+            form = form.text("ean", ean.to_string());
+        }
+        if let Some(id) = document.id {
+            form = form.text("id", id.to_string());
+        }
+        if let Some((start_date, end_date)) = document.report.date_range() {
+            form = form
+                .text("start_date", start_date.to_string())
+                .text("end_date", end_date.to_string());
+        }
+        for (name, value) in &self.options.fields {
+            form = form.text(name.clone(), value.clone());
+        }
+        let part = match data {
+            Content::Bytes(data) => reqwest::multipart::Part::bytes(data.clone()),
+            Content::File { file, size } => reqwest::multipart::Part::stream_with_length(
+                reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(
+                    tokio::fs::File::open(file.path()).await?,
+                )),
+                *size,
+            ),
+        };
+        Ok(form.part(
+            self.options.field_name.clone(),
+            part.file_name(document.output_name(self.format)),
+        ))
+    }
+
+    /// Sends the form once
+    async fn send(&self, document: &Document, data: &Content, key: &str) -> Result<(), Error> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header("Idempotency-Key", key)
+            .multipart(self.form(document, data).await?);
+        request = match &self.options.auth {
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            Some(Auth::Basic { username, password }) => {
+                request.basic_auth(username, password.as_ref())
+            }
+            None => request,
+        };
+
+        // Make sure the server accepted the file
+        let status = request.send().await?.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            Err(Error::NotOk(status))
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for HttpSink {
+    async fn save(&self, document: &Document) -> Result<Saved, Error> {
+        let data = document.encode(self.format)?;
+
+        // Every attempt uses the same key, so the server can ignore duplicates
+        let key = document.key();
+        let mut delay = self.options.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match self.send(document, &data, &key).await {
+                Ok(()) => return Ok(Saved::Written),
+
+                // Only retry errors that might be temporary
+                Err(Error::NotOk(status))
+                    if !(status.is_server_error()
+                        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || status == reqwest::StatusCode::REQUEST_TIMEOUT) =>
+                {
+                    return Err(Error::NotOk(status))
+                }
+                Err(e) if attempt >= self.options.retries => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+    }
+
+    fn destinations(&self, document: &Document) -> Vec<String> {
+        vec![format!(
+            "POST {} with {}",
+            self.url,
+            document.output_name(self.format)
+        )]
+    }
+}
+
+/// Writes every report to the standard output
+#[derive(Debug, Clone)]
+pub struct StdoutSink {
+    pub format: Format,
+}
+
+#[async_trait]
+impl Sink for StdoutSink {
+    async fn save(&self, document: &Document) -> Result<Saved, Error> {
+        let mut stdout = tokio::io::stdout();
+        let data = document.encode(self.format)?;
+        tokio::io::copy(&mut data.async_reader().await?, &mut stdout).await?;
+        stdout.flush().await?;
+        Ok(Saved::Written)
+    }
+
+    fn destinations(&self, _document: &Document) -> Vec<String> {
+        vec!["the standard output".to_owned()]
+    }
+}
+
+/// Stores the usage records of every usage report in a database and adds it to the download log.
+/// Other reports don't contain usage records, so they're skipped.
+#[derive(Debug, Clone)]
+pub struct DatabaseSink {
+    pub database: Arc<Database>,
+}
+
+#[async_trait]
+impl Sink for DatabaseSink {
+    async fn save(&self, document: &Document) -> Result<Saved, Error> {
+        if !matches!(document.report, Report::EnergieVerbruikPerUur(_, _, _)) {
+            return Ok(Saved::Skipped);
+        }
+        let database = self.database.clone();
+        let document = document.clone();
+        tokio::task::spawn_blocking(move || {
+            let records = usage::read(document.data.reader()?)?;
+            if let (Some(ean), Some(id)) = (&document.ean, document.id) {
+                database.upsert_connection(ean, id)?;
+            }
+            let records = database.upsert_usage(&records)?;
+            database.log_download(&Download {
+                report: &document.report,
+                ean: document.ean.as_ref(),
+                file_name: &document.file_name,
+                bytes: document.data.len(),
+                records,
+                downloaded_at: document.downloaded_at,
+            })?;
+            Ok(Saved::Written)
+        })
+        .await?
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        let database = self.database.clone();
+        Ok(tokio::task::spawn_blocking(move || database.check()).await??)
+    }
+
+    fn destinations(&self, document: &Document) -> Vec<String> {
+        vec![format!(
+            "the usage records of {} in the database",
+            document.report.name()
+        )]
+    }
+}
+
+/// Saves every report to all of the sinks
+#[derive(Debug, Default)]
+pub struct TeeSink {
+    pub sinks: Vec<Box<dyn Sink>>,
+}
+
+#[async_trait]
+impl Sink for TeeSink {
+    async fn save(&self, document: &Document) -> Result<Saved, Error> {
+        // Try every sink, even if an earlier one failed
+        let mut errors = Vec::new();
+        let mut saved = Saved::Skipped;
+        for sink in &self.sinks {
+            match sink.save(document).await {
+                Ok(Saved::Written) => saved = Saved::Written,
+                Ok(Saved::Unchanged) if saved == Saved::Skipped => saved = Saved::Unchanged,
+                Ok(Saved::Unchanged | Saved::Skipped) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.is_empty() {
+            Ok(saved)
+        } else {
+            Err(Error::Tee(errors))
+        }
+    }
+
+    async fn check(&self) -> Result<(), Error> {
+        let mut errors = Vec::new();
+        for sink in &self.sinks {
+            if let Err(e) = sink.check().await {
+                errors.push(e);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Tee(errors))
+        }
+    }
+
+    fn destinations(&self, document: &Document) -> Vec<String> {
+        self.sinks
+            .iter()
+            .flat_map(|sink| sink.destinations(document))
+            .collect()
+    }
+}
+
+/// Settings for the sinks that are created from outputs
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// The format of written and sent files
+    pub format: Format,
+
+    /// Settings for http(s) outputs
+    pub http: HttpOptions,
+
+    /// Settings for s3 outputs
+    pub s3: S3Options,
+
+    /// The path of saved files in directories and object storage
+    pub template: Option<Template>,
+
+    /// Keep a manifest in directories and skip unchanged reports
+    pub manifest: bool,
+}
+
+/// Creates the sink for an output:
+/// - `-` writes to the standard output
+/// - `file://` urls and paths write to a directory
+/// - `http://` and `https://` urls receive a multipart form
+/// - `sqlite://` urls store the usage records in a database
+/// - `s3://bucket/prefix` urls upload to S3-compatible object storage
+///
+/// # Errors
+/// Returns an error if the url uses an unsupported scheme or the database couldn't be opened
+pub fn from_output(
+    output: &str,
+    client: &Client,
+    options: &Options,
+) -> Result<Box<dyn Sink>, Error> {
+    let format = options.format;
+    if output == "-" {
+        return Ok(Box::new(StdoutSink { format }));
+    }
+    if let Some(database) = Database::open_url(output) {
+        return Ok(Box::new(DatabaseSink {
+            database: Arc::new(database?),
+        }));
+    }
+
+    // Anything that isn't a url is a directory path
+    let Ok(url) = Url::from_str(output) else {
+        return Ok(Box::new(DirectorySink {
+            directory: PathBuf::from(output),
+            format,
+            template: options.template.clone(),
+            manifest: options.manifest,
+        }));
+    };
+    match url.scheme() {
+        "http" | "https" => Ok(Box::new(HttpSink {
+            client: client.clone(),
+            url,
+            format,
+            options: options.http.clone(),
+        })),
+        "s3" => Ok(Box::new(S3Sink {
+            client: client.clone(),
+            bucket: url
+                .host_str()
+                .filter(|bucket| !bucket.is_empty())
+                .ok_or_else(|| Error::UnsupportedOutput(output.to_owned()))?
+                .to_owned(),
+            prefix: url.path().trim_matches('/').to_owned(),
+            format,
+            template: options.template.clone(),
+            options: options.s3.clone(),
+        })),
+        "file" => Ok(Box::new(DirectorySink {
+            directory: url
+                .to_file_path()
+                .map_err(|()| Error::UnsupportedOutput(output.to_owned()))?,
+            format,
+            template: options.template.clone(),
+            manifest: options.manifest,
+        })),
+        // Windows paths like C:\reports are parsed as a url with the drive as scheme
+        scheme if scheme.len() == 1 => Ok(Box::new(DirectorySink {
+            directory: PathBuf::from(output),
+            format,
+            template: options.template.clone(),
+            manifest: options.manifest,
+        })),
+        _ => Err(Error::UnsupportedOutput(output.to_owned())),
+    }
+}
+
+/// Creates a sink for the outputs, multiple outputs are combined in a [`TeeSink`]
+///
+/// # Errors
+/// Returns an error if a sink couldn't be created
+pub fn from_outputs(
+    outputs: &[String],
+    client: &Client,
+    options: &Options,
+) -> Result<Box<dyn Sink>, Error> {
+    let mut sinks = outputs
+        .iter()
+        .map(|output| from_output(output, client, options))
+        .collect::<Result<Vec<_>, _>>()?;
+    if sinks.len() == 1 {
+        Ok(sinks.remove(0))
+    } else {
+        Ok(Box::new(TeeSink { sinks }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(report: Report, data: &[u8]) -> Document {
+        Document {
+            report,
+            ean: Some(Ean::from("871687120000000001".to_owned())),
+            id: Some(Id::from(1001)),
+            file_name: "Analyze_1.xlsx".to_owned(),
+            data: data.to_vec().into(),
+            downloaded_at: Utc::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn database_only_stores_usage_reports() {
+        let sink = DatabaseSink {
+            database: Arc::new(Database::open(":memory:").expect("Failed to open")),
+        };
+
+        // A global report doesn't contain usage, so it isn't parsed
+        let aansluitinglijst = document(
+            Report::Aansluitinglijst,
+            include_bytes!("../tests/fixtures/aansluitinglijst.xlsx"),
+        );
+        assert_eq!(
+            sink.save(&aansluitinglijst).await.expect("Failed to save"),
+            Saved::Skipped
+        );
+
+        let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+        let usage = document(
+            Report::EnergieVerbruikPerUur(Id::from(1001), date, date),
+            include_bytes!("../tests/fixtures/reports/energie-verbruik-per-uur.xlsx"),
+        );
+        assert_eq!(
+            sink.save(&usage).await.expect("Failed to save"),
+            Saved::Written
+        );
+    }
+
+    #[tokio::test]
+    async fn tee_of_skipping_sinks_is_skipped() {
+        let database = DatabaseSink {
+            database: Arc::new(Database::open(":memory:").expect("Failed to open")),
+        };
+        let tee = TeeSink {
+            sinks: vec![Box::new(database)],
+        };
+        let aansluitinglijst = document(
+            Report::Aansluitinglijst,
+            include_bytes!("../tests/fixtures/aansluitinglijst.xlsx"),
+        );
+        assert_eq!(
+            tee.save(&aansluitinglijst).await.expect("Failed to save"),
+            Saved::Skipped
+        );
+    }
+
+    #[tokio::test]
+    async fn directory_skips_unchanged_reports_until_the_file_is_removed() {
+        let directory =
+            std::env::temp_dir().join(format!("rapportage-sink-{}", std::process::id()));
+        std::fs::remove_dir_all(&directory).ok();
+        let sink = DirectorySink {
+            directory: directory.clone(),
+            format: Format::Xlsx,
+            template: None,
+            manifest: true,
+        };
+        let aansluitinglijst = document(
+            Report::Aansluitinglijst,
+            include_bytes!("../tests/fixtures/aansluitinglijst.xlsx"),
+        );
+        assert_eq!(
+            sink.save(&aansluitinglijst).await.expect("Failed to save"),
+            Saved::Written
+        );
+        assert_eq!(
+            sink.save(&aansluitinglijst).await.expect("Failed to save"),
+            Saved::Unchanged
+        );
+
+        for destination in sink.destinations(&aansluitinglijst) {
+            std::fs::remove_file(destination).expect("Failed to remove");
+        }
+        assert_eq!(
+            sink.save(&aansluitinglijst).await.expect("Failed to save"),
+            Saved::Written
+        );
+        let manifest = Manifest::load(&directory).await.expect("Failed to load");
+        assert_eq!(manifest.entries.len(), 1);
+        std::fs::remove_dir_all(directory).ok();
+    }
+
+    #[tokio::test]
+    async fn file_names_of_the_portal_stay_in_the_directory() {
+        let mut document = document(
+            Report::Aansluitinglijst,
+            include_bytes!("../tests/fixtures/aansluitinglijst.xlsx"),
+        );
+        document.file_name = "../../etc\\Lijst_1.xlsx".to_owned();
+        assert_eq!(
+            document.output_name(Format::Csv),
+            "871687120000000001_____etc_Lijst_1.csv"
+        );
+        document.ean = None;
+        document.file_name = "/tmp/Lijst_1.xlsx".to_owned();
+        assert_eq!(document.output_name(Format::Xlsx), "_tmp_Lijst_1.xlsx");
+
+        let directory =
+            std::env::temp_dir().join(format!("rapportage-traversal-{}", std::process::id()));
+        std::fs::remove_dir_all(&directory).ok();
+        let sink = DirectorySink {
+            directory: directory.join("output"),
+            format: Format::Xlsx,
+            template: None,
+            manifest: false,
+        };
+        document.file_name = "../Lijst_1.xlsx".to_owned();
+        assert_eq!(
+            sink.save(&document).await.expect("Failed to save"),
+            Saved::Written
+        );
+        assert!(!directory.join("Lijst_1.xlsx").exists());
+        assert!(directory.join("output/__Lijst_1.xlsx").exists());
+        std::fs::remove_dir_all(directory).ok();
+    }
+
+    #[tokio::test]
+    async fn concurrent_saves_to_a_directory_are_all_in_the_manifest() {
+        let directory =
+            std::env::temp_dir().join(format!("rapportage-concurrent-{}", std::process::id()));
+        std::fs::remove_dir_all(&directory).ok();
+        let sink = Arc::new(DirectorySink {
+            directory: directory.clone(),
+            format: Format::Xlsx,
+            template: None,
+            manifest: true,
+        });
+        let saves = (0..8).map(|number| {
+            let sink = Arc::clone(&sink);
+            tokio::spawn(async move {
+                let mut document = document(
+                    Report::Aansluitinglijst,
+                    include_bytes!("../tests/fixtures/aansluitinglijst.xlsx"),
+                );
+                document.file_name = format!("Lijst_{number}.xlsx");
+                sink.save(&document).await
+            })
+        });
+        for save in futures_util::future::join_all(saves).await {
+            assert_eq!(
+                save.expect("Failed to join").expect("Failed to save"),
+                Saved::Written
+            );
+        }
+        let manifest = Manifest::load(&directory).await.expect("Failed to load");
+        assert_eq!(manifest.entries.len(), 8);
+        std::fs::remove_dir_all(directory).ok();
+    }
+}
@@ -0,0 +1,412 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tokio::io::AsyncWriteExt as _;
+
+use super::{Content, Document, Error};
+use crate::{convert, report::TemporaryFile};
+
+/// The name of the manifest file in a directory, it contains the json of an entry on every line
+pub const MANIFEST_FILE_NAME: &str = "manifest.jsonl";
+
+/// A saved file in the manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The path of the file relative to the directory, with `/` as separator
+    pub path: String,
+
+    /// The SHA-256 hash of the file
+    pub sha256: String,
+
+    /// The SHA-256 hash of the values in the report, which doesn't change when only the metadata of the workbook changes
+    pub content_sha256: String,
+
+    /// The size of the file in bytes
+    pub size: u64,
+
+    /// The type of the report
+    pub report: String,
+
+    pub ean: Option<String>,
+    pub id: Option<u32>,
+    pub start_date: Option<NaiveDate>,
+    pub end_date: Option<NaiveDate>,
+    pub saved_at: DateTime<Utc>,
+}
+
+impl Entry {
+    /// Creates the entry for a document whose data is saved at the path
+    ///
+    /// # Errors
+    /// Returns an error if the data couldn't be read
+    pub fn new(document: &Document, path: String, data: &Content) -> Result<Self, Error> {
+        let date_range = document.report.date_range();
+        Ok(Self {
+            path,
+            sha256: data.sha256()?,
+            content_sha256: content_sha256(document)?,
+            size: data.len(),
+            report: document.report.name().to_owned(),
+            ean: document.ean.as_ref().map(ToString::to_string),
+            id: document.id.map(u32::from),
+            start_date: date_range.map(|(start_date, _)| start_date),
+            end_date: date_range.map(|(_, end_date)| end_date),
+            saved_at: Utc::now(),
+        })
+    }
+
+    /// Returns the path of the file in the directory
+    #[must_use]
+    pub fn file(&self, directory: &Path) -> PathBuf {
+        self.path
+            .split('/')
+            .fold(PathBuf::from(directory), |path, part| path.join(part))
+    }
+
+    /// Checks whether the entry is for the same content of the same report at the same path
+    fn matches(&self, other: &Self) -> bool {
+        self.path == other.path
+            && self.report == other.report
+            && self.ean == other.ean
+            && self.start_date == other.start_date
+            && self.end_date == other.end_date
+            && self.content_sha256 == other.content_sha256
+    }
+}
+
+/// A problem found while verifying a directory against its manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// The file in the manifest doesn't exist
+    Missing(String),
+
+    /// The file has a different size than in the manifest
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        found: u64,
+    },
+
+    /// The file has a different hash than in the manifest
+    HashMismatch(String),
+}
+
+/// The hashes and origins of every file saved in a directory.
+/// Saving a file appends its entry to the manifest, a later entry for the same path replaces the earlier one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+
+    /// The number of lines in the file that have been replaced by a later entry
+    replaced: usize,
+
+    /// Whether the last line of the file is incomplete, because appending it was interrupted
+    incomplete: bool,
+}
+
+/// Returns the SHA-256 hash of the data as hex
+#[must_use]
+pub fn sha256(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Returns the SHA-256 hash of the values in the report.
+/// Falls back to the hash of the data if the report can't be read as a table.
+fn content_sha256(document: &Document) -> Result<String, Error> {
+    match convert::read_table(&document.report, document.data.reader()?)
+        .and_then(|table| table.to_csv())
+    {
+        Ok(csv) => Ok(sha256(&csv)),
+        Err(_) => Ok(document.data.sha256()?),
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest of the directory, a missing manifest is empty
+    ///
+    /// # Errors
+    /// Returns an error if the manifest couldn't be read or a line isn't the json of an entry
+    pub async fn load(directory: &Path) -> Result<Self, Error> {
+        let data = match tokio::fs::read(directory.join(MANIFEST_FILE_NAME)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut manifest = Self::default();
+        let mut lines = data
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .peekable();
+        while let Some(line) = lines.next() {
+            match serde_json::from_slice(line) {
+                Ok(entry) => manifest.insert(entry),
+                Err(_) if lines.peek().is_none() && !data.ends_with(b"\n") => {
+                    manifest.incomplete = true;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Writes the whole manifest to the directory, the previous manifest is only replaced once the new one is complete
+    ///
+    /// # Errors
+    /// Returns an error if the manifest couldn't be written
+    pub async fn store(&mut self, directory: &Path) -> Result<(), Error> {
+        let mut data = Vec::new();
+        for entry in &self.entries {
+            serde_json::to_writer(&mut data, entry)?;
+            data.push(b'\n');
+        }
+        let temporary_file = TemporaryFile::new(&directory.join(MANIFEST_FILE_NAME));
+        tokio::fs::write(temporary_file.path(), data).await?;
+        temporary_file.persist().await?;
+        self.replaced = 0;
+        self.incomplete = false;
+        Ok(())
+    }
+
+    /// Adds the entry and appends it to the manifest in the directory.
+    /// The whole manifest is only written again once most of its lines have been replaced, or the last line is incomplete.
+    ///
+    /// # Errors
+    /// Returns an error if the manifest couldn't be written
+    pub async fn append(&mut self, directory: &Path, entry: Entry) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.insert(entry);
+        if self.incomplete || self.replaced > self.entries.len() {
+            return self.store(directory).await;
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(directory.join(MANIFEST_FILE_NAME))
+            .await?;
+        file.write_all(&line).await?;
+        file.sync_data().await?;
+        Ok(())
+    }
+
+    /// Checks whether the same content of the same report for the same period has been saved at the same path before,
+    /// and the file is still there
+    pub async fn contains(&self, directory: &Path, entry: &Entry) -> bool {
+        let Some(existing) = self.entries.iter().find(|existing| existing.matches(entry)) else {
+            return false;
+        };
+        tokio::fs::metadata(existing.file(directory))
+            .await
+            .is_ok_and(|metadata| metadata.is_file() && metadata.len() == existing.size)
+    }
+
+    /// Adds the entry, replacing the entry of a previous file at the same path
+    pub fn insert(&mut self, entry: Entry) {
+        let count = self.entries.len();
+        self.entries.retain(|existing| existing.path != entry.path);
+        self.replaced += count - self.entries.len();
+        self.entries.push(entry);
+    }
+
+    /// Rechecks the size and hash of every file in the manifest
+    ///
+    /// # Errors
+    /// Returns an error if a file exists, but couldn't be read
+    pub async fn verify(&self, directory: &Path) -> Result<Vec<Problem>, Error> {
+        let mut problems = Vec::new();
+        for entry in &self.entries {
+            let data = match tokio::fs::read(entry.file(directory)).await {
+                Ok(data) => data,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    problems.push(Problem::Missing(entry.path.clone()));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if data.len() as u64 != entry.size {
+                problems.push(Problem::SizeMismatch {
+                    path: entry.path.clone(),
+                    expected: entry.size,
+                    found: data.len() as u64,
+                });
+            } else if sha256(&data) != entry.sha256 {
+                problems.push(Problem::HashMismatch(entry.path.clone()));
+            }
+        }
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ean::Ean, id::Id, report::Report};
+
+    const USAGE: &[u8] =
+        include_bytes!("../../tests/fixtures/reports/energie-verbruik-per-uur.xlsx");
+
+    fn directory(test: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("rapportage-manifest-{test}-{}", std::process::id()));
+        std::fs::remove_dir_all(&directory).ok();
+        std::fs::create_dir_all(&directory).expect("Failed to create the directory");
+        directory
+    }
+
+    fn usage(start: u32, end: u32) -> Document {
+        let date = |day| NaiveDate::from_ymd_opt(2024, 1, day).expect("Invalid date");
+        Document {
+            report: Report::EnergieVerbruikPerUur(Id::from(1001), date(start), date(end)),
+            ean: Some(Ean::from("871687120000000001".to_owned())),
+            id: Some(Id::from(1001)),
+            file_name: "Analyze_1.xlsx".to_owned(),
+            data: USAGE.to_vec().into(),
+            downloaded_at: Utc::now(),
+        }
+    }
+
+    /// Writes the document to the path and returns its entry
+    fn saved(directory: &Path, document: &Document, path: &str) -> Entry {
+        let entry = Entry::new(document, path.to_owned(), &document.data).expect("Failed to hash");
+        std::fs::write(entry.file(directory), USAGE).expect("Failed to write");
+        entry
+    }
+
+    #[tokio::test]
+    async fn only_the_same_report_at_the_same_path_is_contained() {
+        let directory = directory("contains");
+        let mut manifest = Manifest::default();
+        let entry = saved(&directory, &usage(1, 31), "januari.xlsx");
+        manifest.insert(entry.clone());
+        assert!(manifest.contains(&directory, &entry).await);
+
+        // The same content at a different path
+        let moved = Entry::new(
+            &usage(1, 31),
+            "februari.xlsx".to_owned(),
+            &usage(1, 31).data,
+        )
+        .expect("Failed to hash");
+        assert!(!manifest.contains(&directory, &moved).await);
+
+        // The same content for a different period
+        let period = Entry::new(&usage(1, 30), "januari.xlsx".to_owned(), &usage(1, 30).data)
+            .expect("Failed to hash");
+        assert!(!manifest.contains(&directory, &period).await);
+
+        // The file has been removed since it was saved
+        std::fs::remove_file(entry.file(&directory)).expect("Failed to remove");
+        assert!(!manifest.contains(&directory, &entry).await);
+        std::fs::remove_dir_all(directory).ok();
+    }
+
+    #[tokio::test]
+    async fn entries_are_appended_and_replaced() {
+        let directory = directory("append");
+        let mut manifest = Manifest::default();
+        let first = saved(&directory, &usage(1, 31), "januari.xlsx");
+        let second = saved(&directory, &usage(1, 30), "januari-30.xlsx");
+        manifest
+            .append(&directory, first.clone())
+            .await
+            .expect("Failed to append");
+        manifest
+            .append(&directory, second.clone())
+            .await
+            .expect("Failed to append");
+        let lines = || {
+            std::fs::read_to_string(directory.join(MANIFEST_FILE_NAME))
+                .expect("Failed to read")
+                .lines()
+                .count()
+        };
+        assert_eq!(lines(), 2);
+
+        // Replacing an entry appends it, until most of the lines have been replaced
+        let mut replaced = first.clone();
+        replaced.saved_at = Utc::now();
+        manifest
+            .append(&directory, replaced.clone())
+            .await
+            .expect("Failed to append");
+        assert_eq!(lines(), 3);
+        let loaded = Manifest::load(&directory).await.expect("Failed to load");
+        assert_eq!(loaded.entries, [second.clone(), replaced.clone()]);
+
+        manifest
+            .append(&directory, first.clone())
+            .await
+            .expect("Failed to append");
+        manifest
+            .append(&directory, replaced.clone())
+            .await
+            .expect("Failed to append");
+        assert_eq!(lines(), 2);
+        let loaded = Manifest::load(&directory).await.expect("Failed to load");
+        assert_eq!(loaded.entries, [second, replaced]);
+        std::fs::remove_dir_all(directory).ok();
+    }
+
+    #[tokio::test]
+    async fn incomplete_last_line_is_ignored_and_replaced() {
+        let directory = directory("incomplete");
+        let entry = saved(&directory, &usage(1, 31), "januari.xlsx");
+        let mut data = serde_json::to_vec(&entry).expect("Failed to serialize");
+        data.extend_from_slice(b"\n{\"path\":\"febr");
+        std::fs::write(directory.join(MANIFEST_FILE_NAME), data).expect("Failed to write");
+
+        let mut manifest = Manifest::load(&directory).await.expect("Failed to load");
+        assert_eq!(manifest.entries, std::slice::from_ref(&entry));
+
+        // The next entry isn't appended to the incomplete line
+        let next = saved(&directory, &usage(1, 30), "januari-30.xlsx");
+        manifest
+            .append(&directory, next.clone())
+            .await
+            .expect("Failed to append");
+        let loaded = Manifest::load(&directory).await.expect("Failed to load");
+        assert_eq!(loaded.entries, [entry, next]);
+
+        // Any other invalid line is an error
+        std::fs::write(directory.join(MANIFEST_FILE_NAME), b"{}\n").expect("Failed to write");
+        assert!(Manifest::load(&directory).await.is_err());
+        std::fs::remove_dir_all(directory).ok();
+    }
+
+    #[tokio::test]
+    async fn verify_finds_missing_and_changed_files() {
+        let directory = directory("verify");
+        let mut manifest = Manifest::default();
+        let missing = saved(&directory, &usage(1, 31), "missing.xlsx");
+        let resized = saved(&directory, &usage(1, 31), "resized.xlsx");
+        let changed = saved(&directory, &usage(1, 31), "changed.xlsx");
+        let unchanged = saved(&directory, &usage(1, 31), "unchanged.xlsx");
+        for entry in [&missing, &resized, &changed, &unchanged] {
+            manifest.insert(entry.clone());
+        }
+
+        std::fs::remove_file(missing.file(&directory)).expect("Failed to remove");
+        std::fs::write(resized.file(&directory), b"xlsx").expect("Failed to write");
+        let mut data = std::fs::read(changed.file(&directory)).expect("Failed to read");
+        data[0] ^= 1;
+        std::fs::write(changed.file(&directory), data).expect("Failed to write");
+
+        let problems = manifest.verify(&directory).await.expect("Failed to verify");
+        assert_eq!(
+            problems,
+            [
+                Problem::Missing("missing.xlsx".to_owned()),
+                Problem::SizeMismatch {
+                    path: "resized.xlsx".to_owned(),
+                    expected: resized.size,
+                    found: 4,
+                },
+                Problem::HashMismatch("changed.xlsx".to_owned()),
+            ]
+        );
+        std::fs::remove_dir_all(directory).ok();
+    }
+}
@@ -0,0 +1,266 @@
+use std::{fmt::Display, path::Path, str::FromStr};
+
+use chrono::Local;
+
+use super::{Document, Error};
+use crate::convert::Format;
+
+/// The placeholders that can be used in a template
+pub const PLACEHOLDERS: [&str; 12] = [
+    "report",
+    "ean",
+    "id",
+    "start",
+    "end",
+    "year",
+    "month",
+    "day",
+    "downloaded",
+    "filename",
+    "ext",
+    "format",
+];
+
+/// A template for the relative path of saved reports, like `{report}/{year}/{month}/{ean}_{start}_{end}.{ext}`.
+///
+/// The available placeholders are:
+/// - `{report}`: the type of the report
+/// - `{ean}` and `{id}`: the meter of the report
+/// - `{start}` and `{end}`: the first and last day of the report
+/// - `{year}`, `{month}` and `{day}`: the local date of the download
+/// - `{downloaded}`: the local time of the download
+/// - `{filename}`: the name of the file on the portal, without extension
+/// - `{ext}` or `{format}`: the extension of the output format
+///
+/// Placeholders without a value, like the ean of a report for all meters, are left empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(String);
+
+impl FromStr for Template {
+    type Err = Error;
+
+    /// Parses the template and makes sure every placeholder is known
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some((_, after)) = rest.split_once('{') {
+            let (placeholder, after) = after
+                .split_once('}')
+                .ok_or_else(|| Error::InvalidTemplate(format!("Unclosed placeholder in {s}")))?;
+            if !PLACEHOLDERS.contains(&placeholder) {
+                return Err(Error::InvalidTemplate(format!(
+                    "Unknown placeholder {{{placeholder}}}, expected one of {}",
+                    PLACEHOLDERS.join(", ")
+                )));
+            }
+            rest = after;
+        }
+        if s.trim_matches('/').is_empty() {
+            return Err(Error::InvalidTemplate("Empty template".to_owned()));
+        }
+        Ok(Self(s.to_owned()))
+    }
+}
+
+impl Display for Template {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Makes sure a value can't add directories to the path
+pub(super) fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .replace("..", "_")
+}
+
+impl Template {
+    /// Returns the value of a placeholder for the document
+    fn value(placeholder: &str, document: &Document, format: Format) -> String {
+        let downloaded_at = document.downloaded_at.with_timezone(&Local);
+        let date_range = document.report.date_range();
+        match placeholder {
+            "report" => document.report.name().to_owned(),
+            "ean" => document
+                .ean
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+            "id" => document.id.map(|id| id.to_string()).unwrap_or_default(),
+            "start" => date_range
+                .map(|(start, _)| start.to_string())
+                .unwrap_or_default(),
+            "end" => date_range
+                .map(|(_, end)| end.to_string())
+                .unwrap_or_default(),
+            "year" => downloaded_at.format("%Y").to_string(),
+            "month" => downloaded_at.format("%m").to_string(),
+            "day" => downloaded_at.format("%d").to_string(),
+            "downloaded" => downloaded_at.format("%Y%m%dT%H%M%S").to_string(),
+            "filename" => Path::new(&document.file_name)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            "ext" | "format" => format.extension().to_owned(),
+            _ => String::new(),
+        }
+    }
+
+    /// Fills in the placeholders for the document.
+    /// Returns a relative path with `/` as separator.
+    #[must_use]
+    pub fn render(&self, document: &Document, format: Format) -> String {
+        let mut path = String::new();
+        let mut rest = self.0.as_str();
+        while let Some((before, after)) = rest.split_once('{') {
+            path.push_str(before);
+            let (placeholder, after) = after.split_once('}').unwrap_or((after, ""));
+            path.push_str(&sanitize(&Self::value(placeholder, document, format)));
+            rest = after;
+        }
+        path.push_str(rest);
+
+        // Remove empty directories, which are caused by empty placeholders
+        path.split(['/', '\\'])
+            .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone as _, Utc};
+
+    use super::*;
+    use crate::{ean::Ean, id::Id, report::Report};
+
+    fn document(report: Report, ean: Option<&str>, file_name: &str) -> Document {
+        Document {
+            report,
+            ean: ean.map(|ean| Ean::from(ean.to_owned())),
+            id: ean.map(|_| Id::from(1001)),
+            file_name: file_name.to_owned(),
+            data: Vec::new().into(),
+            downloaded_at: Utc
+                .with_ymd_and_hms(2024, 2, 1, 12, 0, 0)
+                .single()
+                .expect("Invalid time"),
+        }
+    }
+
+    fn usage() -> Document {
+        let start = NaiveDate::from_ymd_opt(2024, 1, 1).expect("Invalid date");
+        let end = NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+        document(
+            Report::EnergieVerbruikPerUur(Id::from(1001), start, end),
+            Some("871687120000000001"),
+            "Analyze_1.xlsx",
+        )
+    }
+
+    fn error(template: &str) -> String {
+        match Template::from_str(template) {
+            Err(Error::InvalidTemplate(message)) => message,
+            result => panic!("Expected an invalid template for {template}, found {result:?}"),
+        }
+    }
+
+    #[test]
+    fn templates_with_known_placeholders_are_valid() {
+        for template in [
+            "{report}/{year}/{month}/{ean}_{start}_{end}.{ext}",
+            "{filename}.{format}",
+            "rapporten/{downloaded}_{id}.xlsx",
+            "zonder-placeholders.xlsx",
+        ] {
+            let parsed = Template::from_str(template).expect("Invalid template");
+            assert_eq!(parsed.to_string(), template);
+        }
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        assert!(error("{report}/{meter}.{ext}").starts_with("Unknown placeholder {meter}"));
+        assert!(error("{}.xlsx").starts_with("Unknown placeholder {}"));
+        assert!(error("{Report}.xlsx").starts_with("Unknown placeholder {Report}"));
+    }
+
+    #[test]
+    fn unclosed_placeholders_are_rejected() {
+        assert_eq!(
+            error("{report}/{ean.xlsx"),
+            "Unclosed placeholder in {report}/{ean.xlsx"
+        );
+        assert!(error("{report").starts_with("Unclosed placeholder"));
+    }
+
+    #[test]
+    fn empty_templates_are_rejected() {
+        assert_eq!(error(""), "Empty template");
+        assert_eq!(error("///"), "Empty template");
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let template = Template::from_str("{report}/{ean}_{id}_{start}_{end}/{filename}.{ext}")
+            .expect("Invalid template");
+        assert_eq!(
+            template.render(&usage(), Format::Parquet),
+            "energie-verbruik-per-uur/871687120000000001_1001_2024-01-01_2024-01-31/Analyze_1.parquet"
+        );
+
+        // The date of the download is local
+        let local = usage().downloaded_at.with_timezone(&Local);
+        let template = Template::from_str("{year}/{month}/{day}/{downloaded}.{format}")
+            .expect("Invalid template");
+        assert_eq!(
+            template.render(&usage(), Format::Csv),
+            format!(
+                "{}/{}.csv",
+                local.format("%Y/%m/%d"),
+                local.format("%Y%m%dT%H%M%S")
+            )
+        );
+    }
+
+    #[test]
+    fn empty_placeholders_leave_no_empty_directories() {
+        let template = Template::from_str("{report}/{ean}/{start}/{filename}.{ext}")
+            .expect("Invalid template");
+        let document = document(Report::Co2, None, "Co2_12.xlsx");
+        assert_eq!(template.render(&document, Format::Xlsx), "co2/Co2_12.xlsx");
+
+        let template = Template::from_str("/{ean}//{report}/").expect("Invalid template");
+        assert_eq!(template.render(&document, Format::Xlsx), "co2");
+    }
+
+    #[test]
+    fn values_cant_add_directories() {
+        assert_eq!(sanitize("../a/b\\c:d"), "__a_b_c_d");
+        assert_eq!(sanitize("..."), "_.");
+
+        // The name of the file on the portal is the only value the portal controls
+        let template = Template::from_str("{report}/{filename}.{ext}").expect("Invalid template");
+        let document = document(Report::Co2, None, "..\\..\\Co2:1.xlsx");
+        assert_eq!(
+            template.render(&document, Format::Xlsx),
+            "co2/____Co2_1.xlsx"
+        );
+    }
+
+    #[test]
+    fn relative_directories_in_the_template_are_removed() {
+        let template =
+            Template::from_str("../{report}/./..\\{ean}.{ext}").expect("Invalid template");
+        assert_eq!(
+            template.render(&usage(), Format::Jsonl),
+            "energie-verbruik-per-uur/871687120000000001.jsonl"
+        );
+    }
+}
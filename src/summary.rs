@@ -0,0 +1,454 @@
+use std::{fmt::Display, path::Path, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    ean::Ean,
+    id::Id,
+    report::{self, Report},
+    sink,
+};
+
+/// Errors that can occur while storing a summary
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Io(#[from] std::io::Error),
+    Json(#[from] serde_json::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// What happened to a requested report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Written,
+    Unchanged,
+
+    /// The report was downloaded, but none of the outputs stores it
+    Skipped,
+    Failed,
+
+    /// The download was aborted because of a shutdown
+    Cancelled,
+}
+
+impl Outcome {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Written => "written",
+            Self::Unchanged => "unchanged",
+            Self::Skipped => "skipped",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// The number of reports per outcome
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Counts {
+    pub written: usize,
+    pub unchanged: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+}
+
+impl Counts {
+    pub fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Written => self.written += 1,
+            Outcome::Unchanged => self.unchanged += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Failed => self.failed += 1,
+            Outcome::Cancelled => self.cancelled += 1,
+        }
+    }
+}
+
+impl std::ops::AddAssign for Counts {
+    fn add_assign(&mut self, other: Self) {
+        self.written += other.written;
+        self.unchanged += other.unchanged;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.cancelled += other.cancelled;
+    }
+}
+
+impl Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Saved reports: {}, unchanged: {}, skipped: {}, failed: {}, cancelled: {}",
+            self.written, self.unchanged, self.skipped, self.failed, self.cancelled
+        )
+    }
+}
+
+/// The step of a run that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Login,
+    ReadEans,
+    ResolveId,
+    Download,
+    Save,
+}
+
+impl Stage {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::ReadEans => "read_eans",
+            Self::ResolveId => "resolve_id",
+            Self::Download => "download",
+            Self::Save => "save",
+        }
+    }
+}
+
+/// Why a step of a run failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Failure {
+    pub stage: Stage,
+
+    /// The variant of the error, like `NotOk` or `InvalidContent`
+    pub kind: String,
+
+    /// The complete error
+    pub message: String,
+}
+
+impl Failure {
+    #[must_use]
+    pub fn new(stage: Stage, kind: &str, error: &impl Display) -> Self {
+        Self {
+            stage,
+            kind: kind.to_owned(),
+            message: error.to_string(),
+        }
+    }
+
+    /// Returns the failure of a download
+    #[must_use]
+    pub fn download(error: &report::Error) -> Self {
+        Self::new(Stage::Download, error.kind(), error)
+    }
+
+    /// Returns the failure of saving a report
+    #[must_use]
+    pub fn save(error: &sink::Error) -> Self {
+        Self::new(Stage::Save, error.kind(), error)
+    }
+}
+
+/// What happened to a single report
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportSummary {
+    pub report: String,
+    pub ean: Option<String>,
+    pub id: Option<u32>,
+    pub outcome: Outcome,
+
+    /// The number of times the report was requested
+    pub attempts: u32,
+
+    /// The size of the downloaded report
+    pub bytes: u64,
+    pub duration_secs: f64,
+
+    /// Why the report failed, if it did
+    pub failure: Option<Failure>,
+}
+
+impl ReportSummary {
+    /// Creates the summary of a report that hasn't been requested yet
+    #[must_use]
+    pub fn new(report: &Report, ean: Option<&Ean>, id: Option<Id>) -> Self {
+        Self {
+            report: report.name().to_owned(),
+            ean: ean.map(ToString::to_string),
+            id: id.map(u32::from),
+            outcome: Outcome::Failed,
+            attempts: 0,
+            bytes: 0,
+            duration_secs: 0.0,
+            failure: None,
+        }
+    }
+
+    /// Creates the summary of a report of a meter whose id couldn't be retrieved, so the report wasn't requested
+    #[must_use]
+    pub fn unresolved(report: &str, ean: &Ean, failure: Failure) -> Self {
+        Self {
+            report: report.to_owned(),
+            ean: Some(ean.to_string()),
+            id: None,
+            outcome: Outcome::Failed,
+            attempts: 0,
+            bytes: 0,
+            duration_secs: 0.0,
+            failure: Some(failure),
+        }
+    }
+}
+
+/// What happened while downloading the reports of an account
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AccountSummary {
+    pub mail: String,
+
+    /// The eans that passed the filters
+    pub eans: usize,
+
+    /// The number of eans whose id was retrieved
+    pub ids_resolved: usize,
+
+    /// The eans of the connections that didn't pass the filters
+    pub skipped_meters: Vec<String>,
+    pub reports: Vec<ReportSummary>,
+
+    /// The failures that stopped the account before its reports were requested
+    pub failures: Vec<Failure>,
+
+    /// Whether the shutdown stopped the account before its reports were requested
+    pub cancelled: bool,
+    pub duration_secs: f64,
+}
+
+impl AccountSummary {
+    #[must_use]
+    pub fn new(mail: &str) -> Self {
+        Self {
+            mail: mail.to_owned(),
+            ..Self::default()
+        }
+    }
+
+    /// Returns the number of reports per outcome.
+    /// A failure or shutdown before the reports were requested counts as a failed or cancelled report.
+    #[must_use]
+    pub fn counts(&self) -> Counts {
+        let mut counts = Counts {
+            failed: self.failures.len(),
+            cancelled: usize::from(self.cancelled),
+            ..Counts::default()
+        };
+        for report in &self.reports {
+            counts.add(report.outcome);
+        }
+        counts
+    }
+}
+
+/// The result of a whole run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Every report was saved
+    Ok,
+
+    /// Some reports were saved and some failed
+    Partial,
+
+    /// Nothing was saved
+    Failed,
+
+    /// The run was interrupted by a shutdown
+    Cancelled,
+}
+
+impl Status {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Partial => "partial",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    /// Returns the exit code of a run with this status.
+    /// A cancelled run exits with the code of the signal instead, this returns the code of SIGINT.
+    #[must_use]
+    pub const fn exit_code(self) -> i32 {
+        match self {
+            Self::Ok => 0,
+            Self::Failed => 1,
+            Self::Partial => 3,
+            Self::Cancelled => 130,
+        }
+    }
+}
+
+/// A machine readable description of a run, with every account and report
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub status: Status,
+    pub counts: Counts,
+
+    /// The size of all downloaded reports
+    pub bytes: u64,
+    pub accounts: Vec<AccountSummary>,
+}
+
+impl Summary {
+    /// Creates the summary of a run that took the duration and determines its status
+    #[must_use]
+    pub fn new(
+        started_at: DateTime<Utc>,
+        duration: Duration,
+        accounts: Vec<AccountSummary>,
+    ) -> Self {
+        let mut counts = Counts::default();
+        for account in &accounts {
+            counts += account.counts();
+        }
+        let status = if counts.cancelled > 0 {
+            Status::Cancelled
+        } else if counts.failed == 0 {
+            Status::Ok
+        } else if counts.written + counts.unchanged + counts.skipped > 0 {
+            Status::Partial
+        } else {
+            Status::Failed
+        };
+        Self {
+            started_at,
+            finished_at: Utc::now(),
+            duration_secs: duration.as_secs_f64(),
+            status,
+            counts,
+            bytes: accounts
+                .iter()
+                .flat_map(|account| &account.reports)
+                .map(|report| report.bytes)
+                .sum(),
+            accounts,
+        }
+    }
+
+    /// Writes the summary as json, the previous summary is only replaced once the new one is complete
+    ///
+    /// # Errors
+    /// Returns an error if the file couldn't be written
+    pub fn store(&self, path: &Path) -> Result<(), Error> {
+        let temporary_path = report::temporary_path(path);
+        std::fs::write(&temporary_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(temporary_path, path)?;
+        Ok(())
+    }
+}
+
+/// Formats a number of bytes with a binary unit
+fn bytes(bytes: u64) -> String {
+    #[allow(clippy::cast_precision_loss)]
+    let mut value = bytes as f64;
+    for unit in ["B", "KiB", "MiB"] {
+        if value < 1024.0 {
+            return format!("{value:.0} {unit}");
+        }
+        value /= 1024.0;
+    }
+    format!("{value:.1} GiB")
+}
+
+impl Display for Summary {
+    /// Writes the summary as a table with a row per report, followed by the failures and totals
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for account in &self.accounts {
+            writeln!(
+                f,
+                "{}: {} eans, {} ids resolved, {} skipped meters, {:.1} s",
+                account.mail,
+                account.eans,
+                account.ids_resolved,
+                account.skipped_meters.len(),
+                account.duration_secs
+            )?;
+            for failure in &account.failures {
+                writeln!(
+                    f,
+                    "  {} failed ({}): {}",
+                    failure.stage.name(),
+                    failure.kind,
+                    failure.message
+                )?;
+            }
+            if account.reports.is_empty() {
+                continue;
+            }
+            writeln!(
+                f,
+                "  {:<26} {:<18} {:<10} {:>8} {:>10} {:>9}  Error",
+                "Report", "EAN", "Outcome", "Attempts", "Size", "Duration"
+            )?;
+            for report in &account.reports {
+                writeln!(
+                    f,
+                    "  {:<26} {:<18} {:<10} {:>8} {:>10} {:>7.1} s  {}",
+                    report.report,
+                    report.ean.as_deref().unwrap_or("-"),
+                    report.outcome.name(),
+                    report.attempts,
+                    bytes(report.bytes),
+                    report.duration_secs,
+                    report.failure.as_ref().map_or(String::new(), |failure| {
+                        format!("{} ({})", failure.stage.name(), failure.kind)
+                    })
+                )?;
+            }
+        }
+        write!(
+            f,
+            "Status: {}. {}, downloaded: {}, duration: {:.1} s",
+            self.status.name(),
+            self.counts,
+            bytes(self.bytes),
+            self.duration_secs
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_reports_are_counted_separately() {
+        let mut account = AccountSummary::new("test@example.com");
+        for outcome in [Outcome::Written, Outcome::Skipped, Outcome::Skipped] {
+            let mut report = ReportSummary::new(&Report::Aansluitinglijst, None, None);
+            report.outcome = outcome;
+            account.reports.push(report);
+        }
+        let summary = Summary::new(Utc::now(), Duration::ZERO, vec![account]);
+        assert_eq!(
+            summary.counts,
+            Counts {
+                written: 1,
+                skipped: 2,
+                ..Counts::default()
+            }
+        );
+        assert_eq!(summary.status, Status::Ok);
+        assert_eq!(
+            summary.counts.to_string(),
+            "Saved reports: 1, unchanged: 0, skipped: 2, failed: 0, cancelled: 0"
+        );
+    }
+}
@@ -0,0 +1,413 @@
+use std::{
+    fmt::Display,
+    io::{Cursor, Read, Seek},
+};
+
+use calamine::{DataType, Reader as _, Xlsx};
+use chrono::{DateTime, LocalResult, NaiveDateTime, TimeZone as _};
+use chrono_tz::{Europe::Amsterdam, Tz};
+
+use crate::{convert::parse_decimal, ean::Ean};
+
+/// Errors that can occur while parsing a usage report
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    Xlsx(#[from] calamine::XlsxError),
+    MissingWorksheet,
+    EmptyWorksheet,
+    NoMeterColumns,
+    InvalidTimestamp { row: usize, value: String },
+    NonexistentTime { row: usize, time: NaiveDateTime },
+    InvalidValue { row: usize, column: usize },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// The unit of a measured value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unit {
+    Kwh,
+    M3,
+    Mj,
+    Other(String),
+}
+
+impl From<&str> for Unit {
+    fn from(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "kwh" => Self::Kwh,
+            "m3" | "m³" => Self::M3,
+            "mj" => Self::Mj,
+            _ => Self::Other(value.trim().to_owned()),
+        }
+    }
+}
+
+impl Display for Unit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Kwh => write!(f, "kWh"),
+            Self::M3 => write!(f, "m3"),
+            Self::Mj => write!(f, "MJ"),
+            Self::Other(unit) => write!(f, "{unit}"),
+        }
+    }
+}
+
+/// The direction of the energy a meter column measures
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Energy taken from the grid, like `Levering`, also used when the header doesn't name a direction
+    Consumption,
+
+    /// Energy returned to the grid, like `Teruglevering`
+    FeedIn,
+
+    /// Another channel that's named in the header
+    Other(String),
+}
+
+impl From<&str> for Direction {
+    fn from(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "" | "levering" | "afname" | "verbruik" => Self::Consumption,
+            "teruglevering" | "invoeding" | "opwek" => Self::FeedIn,
+            _ => Self::Other(value.trim().to_owned()),
+        }
+    }
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Consumption => write!(f, "consumption"),
+            Self::FeedIn => write!(f, "feed_in"),
+            Self::Other(direction) => write!(f, "{direction}"),
+        }
+    }
+}
+
+/// A single measurement of a meter
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRecord {
+    /// The meter that measured the value
+    pub meter: Ean,
+
+    /// The start of the measured interval
+    pub timestamp: DateTime<Tz>,
+
+    /// The measured value
+    pub value: f64,
+
+    /// The unit of the value
+    pub unit: Unit,
+
+    /// Whether the value was taken from or returned to the grid, a meter can have a column for both
+    pub direction: Direction,
+}
+
+/// A column of the worksheet that contains the values of a meter
+struct MeterColumn {
+    index: usize,
+    meter: Ean,
+    unit: Unit,
+    direction: Direction,
+}
+
+/// Returns the meter of a column header like `871687120000000000 - Levering (kWh)`
+#[must_use]
+pub fn meter_in_header(header: &str) -> Option<Ean> {
+    // The ean code consists of 18 digits
+    header
+        .split(|c: char| !c.is_ascii_digit())
+        .find(|part| part.len() == 18)
+        .map(|digits| Ean::from(digits.to_owned()))
+}
+
+impl MeterColumn {
+    /// Reads the meter, direction and unit from a header like `871687120000000000 - Levering (kWh)`
+    fn from_header(index: usize, header: &str) -> Option<Self> {
+        let meter = meter_in_header(header)?;
+
+        // The unit is placed between the last parentheses
+        let (rest, unit) = match header.rsplit_once('(') {
+            Some((rest, unit)) => (
+                rest,
+                unit.split_once(')')
+                    .map_or(Unit::Kwh, |(unit, _)| Unit::from(unit)),
+            ),
+            None => (header, Unit::Kwh),
+        };
+
+        // The direction follows the ean code, separated by a dash
+        let direction = rest
+            .split_once(meter.value())
+            .map_or("", |(_, direction)| direction)
+            .trim()
+            .trim_start_matches('-');
+
+        Some(Self {
+            index,
+            meter,
+            unit,
+            direction: Direction::from(direction),
+        })
+    }
+}
+
+/// Reads the local time at the start of the interval
+fn parse_naive_timestamp(cell: &DataType) -> Option<NaiveDateTime> {
+    if let DataType::String(value) = cell {
+        return [
+            "%d-%m-%Y %H:%M",
+            "%Y-%m-%d %H:%M",
+            "%d-%m-%Y %H:%M:%S",
+            "%Y-%m-%d %H:%M:%S",
+        ]
+        .into_iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value.trim(), format).ok());
+    }
+
+    // Excel stores times as fractions of days, so round to whole minutes
+    let time = cell.as_datetime()?;
+    let seconds = (time.and_utc().timestamp() + 30).div_euclid(60) * 60;
+    DateTime::from_timestamp(seconds, 0).map(|time| time.naive_utc())
+}
+
+/// Converts the local time to a time in Europe/Amsterdam.
+/// The hour that occurs twice when the clock is turned back is resolved by the previous timestamp,
+/// the first occurrence is the earliest time and the second occurrence the latest.
+fn resolve_local_time(
+    time: NaiveDateTime,
+    previous: Option<DateTime<Tz>>,
+    row: usize,
+) -> Result<DateTime<Tz>, Error> {
+    match Amsterdam.from_local_datetime(&time) {
+        LocalResult::Single(time) => Ok(time),
+        LocalResult::Ambiguous(earliest, latest) => match previous {
+            Some(previous) if previous >= earliest => Ok(latest),
+            _ => Ok(earliest),
+        },
+        LocalResult::None => Err(Error::NonexistentTime { row, time }),
+    }
+}
+
+/// Parses the xlsx export of [`crate::report::Report::EnergieVerbruikPerUur`] into usage records.
+/// The first column contains the local time at the start of every interval and every column with an ean code in the header contains the values of that meter.
+/// Times are interpreted in Europe/Amsterdam, so days with a daylight saving time transition contain 23 or 25 hours.
+///
+/// # Errors
+/// - If the data isn't a valid workbook
+/// - If the workbook doesn't contain a worksheet or header row
+/// - If the header row doesn't contain a meter column
+/// - If a timestamp couldn't be parsed or doesn't exist in Europe/Amsterdam
+/// - If a value isn't a number
+pub fn parse(bytes: &[u8]) -> Result<Vec<UsageRecord>, Error> {
+    read(Cursor::new(bytes))
+}
+
+/// Reads the usage records from the xlsx export in the reader, see [`parse`]
+///
+/// # Errors
+/// Returns the same errors as [`parse`]
+pub fn read(reader: impl Read + Seek) -> Result<Vec<UsageRecord>, Error> {
+    // Open the first worksheet
+    let mut workbook = Xlsx::new(reader)?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or(Error::MissingWorksheet)??;
+    let mut rows = range.rows();
+
+    // Find the meters in the header row
+    let meters = rows
+        .next()
+        .ok_or(Error::EmptyWorksheet)?
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter_map(|(index, header)| MeterColumn::from_header(index, &header.to_string()))
+        .collect::<Vec<_>>();
+    if meters.is_empty() {
+        return Err(Error::NoMeterColumns);
+    }
+
+    let mut records = Vec::new();
+    let mut previous = None;
+    for (row_index, row) in rows.enumerate() {
+        // The header is the first row, so the data starts at the second row
+        let row_number = row_index + 2;
+
+        // Skip empty rows
+        let Some(cell) = row.first().filter(|cell| !cell.is_empty()) else {
+            continue;
+        };
+        let Some(time) = parse_naive_timestamp(cell) else {
+            // A trailing row with totals doesn't contain a time
+            if cell.to_string().trim().eq_ignore_ascii_case("totaal") {
+                continue;
+            }
+            return Err(Error::InvalidTimestamp {
+                row: row_number,
+                value: cell.to_string(),
+            });
+        };
+        let timestamp = resolve_local_time(time, previous, row_number)?;
+        previous = Some(timestamp);
+
+        // Take the value of every meter
+        for meter in &meters {
+            let Some(value) = row.get(meter.index).filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            let value = value
+                .as_f64()
+                .or_else(|| parse_decimal(&value.to_string()))
+                .ok_or(Error::InvalidValue {
+                    row: row_number,
+                    column: meter.index,
+                })?;
+            records.push(UsageRecord {
+                meter: meter.meter.clone(),
+                timestamp,
+                value,
+                unit: meter.unit.clone(),
+                direction: meter.direction.clone(),
+            });
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Offset as _};
+
+    use super::*;
+
+    const SUMMER_TIME: &[u8] = include_bytes!("../tests/fixtures/usage/summer-time.xlsx");
+    const WINTER_TIME: &[u8] = include_bytes!("../tests/fixtures/usage/winter-time.xlsx");
+    const NO_TIME: &[u8] = include_bytes!("../tests/fixtures/usage/no-time.xlsx");
+    const FEED_IN: &[u8] = include_bytes!("../tests/fixtures/usage/feed-in.xlsx");
+
+    /// Returns the records of the first meter
+    fn first_meter(records: &[UsageRecord]) -> Vec<&UsageRecord> {
+        records
+            .iter()
+            .filter(|record| record.meter.value() == "871687120000000001")
+            .collect()
+    }
+
+    /// Asserts that every record starts an hour after the previous one
+    fn assert_hourly(records: &[&UsageRecord]) {
+        for pair in records.windows(2) {
+            assert_eq!(
+                pair[1].timestamp - pair[0].timestamp,
+                Duration::hours(1),
+                "{} is not an hour after {}",
+                pair[1].timestamp,
+                pair[0].timestamp
+            );
+        }
+    }
+
+    #[test]
+    fn day_that_switches_to_summer_time_has_23_hours() {
+        let records = parse(SUMMER_TIME).expect("Failed to parse");
+        assert_eq!(records.len(), 2 * 23);
+        let meter = first_meter(&records);
+        assert_eq!(meter.len(), 23);
+        assert_hourly(&meter);
+
+        // The clock jumps from 02:00 to 03:00
+        assert_eq!(meter[1].timestamp.to_rfc3339(), "2024-03-31T01:00:00+01:00");
+        assert_eq!(meter[2].timestamp.to_rfc3339(), "2024-03-31T03:00:00+02:00");
+        assert_eq!(meter[0].unit, Unit::Kwh);
+        assert!(records
+            .iter()
+            .any(|record| record.meter.value() == "871687120000000002" && record.unit == Unit::M3));
+    }
+
+    #[test]
+    fn day_that_switches_to_winter_time_has_25_hours() {
+        let records = parse(WINTER_TIME).expect("Failed to parse");
+        let meter = first_meter(&records);
+
+        // The trailing row with totals is skipped
+        assert_eq!(meter.len(), 25);
+        assert_hourly(&meter);
+
+        // The ambiguous hour occurs twice, first in summer time and then in winter time
+        assert_eq!(meter[2].timestamp.to_rfc3339(), "2024-10-27T02:00:00+02:00");
+        assert_eq!(meter[3].timestamp.to_rfc3339(), "2024-10-27T02:00:00+01:00");
+        assert_eq!(
+            meter[2].timestamp.offset().fix().local_minus_utc(),
+            2 * 3600
+        );
+        assert_eq!(meter[3].timestamp.offset().fix().local_minus_utc(), 3600);
+    }
+
+    #[test]
+    fn first_column_without_times_is_invalid() {
+        let error = parse(NO_TIME).expect_err("The first column doesn't contain times");
+        assert!(
+            matches!(&error, Error::InvalidTimestamp { row: 2, value } if value == "Kantoor"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn nonexistent_time_is_invalid() {
+        let time = NaiveDateTime::parse_from_str("2024-03-31 02:00", "%Y-%m-%d %H:%M")
+            .expect("Invalid time");
+        assert!(matches!(
+            resolve_local_time(time, None, 4),
+            Err(Error::NonexistentTime { row: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn headers_contain_the_meter_and_unit() {
+        let column = MeterColumn::from_header(3, "871687120000000001 - Teruglevering (kWh)")
+            .expect("No meter in the header");
+        assert_eq!(column.meter.value(), "871687120000000001");
+        assert_eq!(column.unit, Unit::Kwh);
+        assert_eq!(column.direction, Direction::FeedIn);
+        let column = MeterColumn::from_header(2, "871687120000000001 - Levering (kWh)")
+            .expect("No meter in the header");
+        assert_eq!(column.direction, Direction::Consumption);
+        let column = MeterColumn::from_header(1, "Gas 871687120000000002 (m³)")
+            .expect("No meter in the header");
+        assert_eq!(column.unit, Unit::M3);
+        assert_eq!(column.direction, Direction::Consumption);
+        let column = MeterColumn::from_header(1, "871687120000000003 - Blindvermogen (kvarh)")
+            .expect("No meter in the header");
+        assert_eq!(
+            column.direction,
+            Direction::Other("Blindvermogen".to_owned())
+        );
+        assert!(MeterColumn::from_header(1, "Datum").is_none());
+        assert!(meter_in_header("12345").is_none());
+    }
+
+    #[test]
+    fn consumption_and_feed_in_of_a_meter_are_separate_records() {
+        let records = parse(FEED_IN).expect("Failed to parse");
+        assert_eq!(records.len(), 2 * 3);
+        let noon = records
+            .iter()
+            .filter(|record| record.timestamp.to_rfc3339() == "2024-06-01T12:00:00+02:00")
+            .map(|record| (record.meter.value(), record.direction.clone(), record.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            noon,
+            [
+                ("871687120000000001", Direction::Consumption, 0.0),
+                ("871687120000000001", Direction::FeedIn, 3.75),
+            ]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use rapportage_downloader::{
+    api::{self, Api, Options},
+    config::Filters,
+    convert::Format,
+    login::CookieStore,
+    metrics::Metrics,
+    mock::{self, Endpoint, Fault, MockPortal, Server},
+    report::Polling,
+    sink::TeeSink,
+};
+use tokio_util::sync::CancellationToken;
+
+const MAIL: &str = "test@example.com";
+const PASSWORD: &str = "test";
+const TOKEN: &str = "geheim";
+
+/// Starts the portal and serves the api of a session at it, with the token if it's set
+async fn serve(portal: &MockPortal, token: Option<&str>) -> (Server, Server) {
+    let portal_server = portal.start().expect("Failed to start the mock portal");
+    let cookie_store =
+        CookieStore::login_at(&portal_server.url(), MAIL.to_owned(), PASSWORD.to_owned())
+            .await
+            .expect("Failed to log in");
+    let options = Options {
+        filters: Filters::default(),
+        polling: Polling {
+            deadline: Duration::from_secs(5),
+            interval: Duration::from_millis(10),
+            request_timeout: Duration::from_millis(500),
+        },
+        format: Format::Xlsx,
+        concurrency: 2,
+        token: token.map(ToOwned::to_owned),
+    };
+    let api = Api::new(
+        cookie_store,
+        options,
+        Arc::new(TeeSink { sinks: Vec::new() }),
+        Metrics::new().expect("Failed to create the metrics"),
+        CancellationToken::new(),
+    );
+    let api_server = Server::start(api::router(api)).expect("Failed to start the api");
+    (portal_server, api_server)
+}
+
+/// Returns the number of requests the portal received at the endpoint
+fn count(portal: &MockPortal, endpoint: Endpoint) -> usize {
+    portal
+        .requests()
+        .iter()
+        .filter(|request| request.endpoint == endpoint)
+        .count()
+}
+
+#[tokio::test]
+async fn unknown_report_is_not_found() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_portal_server, api_server) = serve(&portal, None).await;
+    let response = reqwest::Client::new()
+        .post(format!("{}/reports/onbekend/download", api_server.url()))
+        .send()
+        .await
+        .expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // A known report with an invalid body is still a bad request
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/reports/aansluitinglijst/download",
+            api_server.url()
+        ))
+        .body(r#"{"days": 7}"#)
+        .send()
+        .await
+        .expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    // A date range that can't exist is rejected before it's computed
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/reports/energie-verbruik-per-uur/download",
+            api_server.url()
+        ))
+        .body(r#"{"id": 1001, "days": 4000000000}"#)
+        .send()
+        .await
+        .expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn requests_need_the_token() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_portal_server, api_server) = serve(&portal, Some(TOKEN)).await;
+    let client = reqwest::Client::new();
+    let url = format!("{}/reports", api_server.url());
+
+    let response = client.get(&url).send().await.expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    for token in ["geheim2", "gehein", ""] {
+        let response = client
+            .get(&url)
+            .bearer_auth(token)
+            .send()
+            .await
+            .expect("Api unreachable");
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{token}");
+    }
+
+    let response = client
+        .get(&url)
+        .bearer_auth(TOKEN)
+        .send()
+        .await
+        .expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn only_expired_sessions_log_in_again() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_portal_server, api_server) = serve(&portal, None).await;
+    let url = format!("{}/meters", api_server.url());
+
+    // An error of the portal isn't solved by logging in again
+    portal.fail(
+        Endpoint::Export,
+        Fault::Status(StatusCode::INTERNAL_SERVER_ERROR),
+    );
+    let response = reqwest::get(&url).await.expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(count(&portal, Endpoint::Login), 1);
+
+    // An expired session is
+    portal.expire_sessions();
+    let response = reqwest::get(&url).await.expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(count(&portal, Endpoint::Login), 2);
+}
+
+#[tokio::test]
+async fn concurrent_requests_retrieve_an_id_once() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_portal_server, api_server) = serve(&portal, None).await;
+    let url = format!(
+        "{}/meters/871687120000000001/usage?from=2024-01-01&to=2024-01-31",
+        api_server.url()
+    );
+    let (first, second) = tokio::join!(reqwest::get(&url), reqwest::get(&url));
+    for response in [first, second] {
+        assert_eq!(response.expect("Api unreachable").status(), StatusCode::OK);
+    }
+    assert_eq!(count(&portal, Endpoint::ConnectionEdit), 1);
+}
+
+#[tokio::test]
+async fn finished_jobs_stream_their_file() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_portal_server, api_server) = serve(&portal, None).await;
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!(
+            "{}/reports/aansluitinglijst/download",
+            api_server.url()
+        ))
+        .send()
+        .await
+        .expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let location = response.headers()[reqwest::header::LOCATION]
+        .to_str()
+        .expect("Invalid location")
+        .to_owned();
+
+    // Wait until the job is done
+    let mut status = serde_json::Value::Null;
+    for _ in 0..100 {
+        let body = client
+            .get(format!("{}{location}", api_server.url()))
+            .send()
+            .await
+            .expect("Api unreachable")
+            .text()
+            .await
+            .expect("Invalid body");
+        status = serde_json::from_str(&body).expect("Invalid status");
+        if status["state"] != "queued" && status["state"] != "running" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(status["state"], "done", "{status}");
+    assert_eq!(status["bytes"], mock::AANSLUITINGLIJST.len());
+
+    let response = client
+        .get(format!("{}{location}/file", api_server.url()))
+        .send()
+        .await
+        .expect("Api unreachable");
+    assert_eq!(response.status(), StatusCode::OK);
+    let data = response.bytes().await.expect("Invalid body");
+    assert_eq!(data.as_ref(), mock::AANSLUITINGLIJST);
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use rapportage_downloader::{
+    health::Health,
+    login::CookieStore,
+    mock::{Endpoint, MockPortal},
+    sink::TeeSink,
+};
+
+const MAIL: &str = "test@example.com";
+const PASSWORD: &str = "test";
+
+/// Returns the result of the session check
+async fn session(health: &Health) -> (bool, String) {
+    let readiness = health.readiness().await;
+    let check = readiness
+        .checks
+        .into_iter()
+        .find(|check| check.name == "session")
+        .expect("The session isn't checked");
+    (check.ok, check.message)
+}
+
+/// Returns the number of times the portal received the login form
+fn logins(portal: &MockPortal) -> usize {
+    portal
+        .requests()
+        .iter()
+        .filter(|request| request.endpoint == Endpoint::Login)
+        .count()
+}
+
+#[tokio::test]
+async fn session_is_only_ready_once_it_logged_in() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let server = portal.start().expect("Failed to start the mock portal");
+    let health = Health::new(Arc::new(TeeSink { sinks: Vec::new() }), None);
+    assert_eq!(
+        session(&health).await,
+        (false, "Not logged in yet".to_owned())
+    );
+
+    // The login form is accepted, but the password is wrong
+    let wrong = CookieStore::login_at(&server.url(), MAIL.to_owned(), "wrong".to_owned())
+        .await
+        .expect("Failed to send the login form");
+    health.set_session(wrong);
+    assert_eq!(
+        session(&health).await,
+        (false, "Logging in didn't work".to_owned())
+    );
+
+    let cookie_store = CookieStore::login_at(&server.url(), MAIL.to_owned(), PASSWORD.to_owned())
+        .await
+        .expect("Failed to log in");
+    health.set_session(cookie_store.clone());
+    assert_eq!(session(&health).await, (true, "Logged in".to_owned()));
+
+    // The probe doesn't log in again, a new session that isn't logged in isn't ready
+    portal.expire_sessions();
+    health.set_session(cookie_store);
+    assert_eq!(
+        session(&health).await,
+        (false, "Logging in didn't work".to_owned())
+    );
+    assert_eq!(logins(&portal), 2);
+}
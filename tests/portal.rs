@@ -0,0 +1,414 @@
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use rapportage_downloader::{
+    connections::{Connection, Connections, OrderDirection, PersonalFilter},
+    ean::{self, Ean},
+    id::Id,
+    login::CookieStore,
+    mock::{self, Endpoint, Fault, MockPortal, Server},
+    report::{self, InvalidContent, Polling, Report},
+};
+
+const MAIL: &str = "test@example.com";
+const PASSWORD: &str = "test";
+
+/// Starts the portal and logs in to it
+async fn login(portal: &MockPortal) -> (Server, CookieStore) {
+    let server = portal.start().expect("Failed to start the mock portal");
+    let cookie_store = CookieStore::login_at(&server.url(), MAIL.to_owned(), PASSWORD.to_owned())
+        .await
+        .expect("Failed to log in");
+    (server, cookie_store)
+}
+
+/// Polls quickly, so waiting for a report doesn't slow the tests down
+fn polling() -> Polling {
+    Polling {
+        deadline: Duration::from_secs(5),
+        interval: Duration::from_millis(10),
+        request_timeout: Duration::from_millis(500),
+    }
+}
+
+#[tokio::test]
+async fn login_with_the_wrong_password_has_no_session() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let server = portal.start().expect("Failed to start the mock portal");
+    let cookie_store = CookieStore::login_at(&server.url(), MAIL.to_owned(), "wrong".to_owned())
+        .await
+        .expect("Failed to send the login form");
+    assert!(!cookie_store
+        .is_logged_in()
+        .await
+        .expect("Portal unreachable"));
+}
+
+#[tokio::test]
+async fn expired_session_logs_in_again() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    assert!(cookie_store
+        .is_logged_in()
+        .await
+        .expect("Portal unreachable"));
+
+    portal.expire_sessions();
+    assert!(!cookie_store
+        .is_logged_in()
+        .await
+        .expect("Portal unreachable"));
+    cookie_store
+        .redo_login()
+        .await
+        .expect("Failed to log in again");
+    assert!(cookie_store
+        .is_logged_in()
+        .await
+        .expect("Portal unreachable"));
+}
+
+#[tokio::test]
+async fn id_and_ean_resolve_each_other() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+
+    let ean = Ean::from("871687120000000002".to_owned());
+    let id = Id::from_ean(&cookie_store, &ean)
+        .await
+        .expect("No id found");
+    assert_eq!(u32::from(id), 1002);
+    let found = Ean::from_id(&cookie_store, id).await.expect("No ean found");
+    assert_eq!(found, ean);
+
+    // The ean is searched through the filter cookie
+    let list = portal
+        .requests()
+        .into_iter()
+        .find(|request| request.endpoint == Endpoint::ConnectionList)
+        .expect("The list wasn't requested");
+    assert!(list
+        .payload
+        .is_some_and(|filter| filter.contains("\"eanSearch\":\"871687120000000002\"")));
+}
+
+#[tokio::test]
+async fn search_returns_typed_rows_per_page() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    let connections = Connections::new(&cookie_store);
+
+    let filter = PersonalFilter::new(&[50]).with_page(2, 1);
+    let first = connections.search(&filter).await.expect("Failed to search");
+    assert_eq!(first.len(), 2);
+    assert_eq!(u32::from(first[0].id), 1001);
+    assert_eq!(first[0].ean.value(), "871687120000000001");
+    assert_eq!(first[0].cells, ["Actief", "E0001"]);
+
+    let second = connections
+        .search(&filter.with_page(2, 2))
+        .await
+        .expect("Failed to search");
+    assert_eq!(second.len(), 1);
+    assert_eq!(u32::from(second[0].id), 1003);
+    assert_eq!(second[0].cells, ["Inactief", "G0003"]);
+
+    // The filter is sent as json in the cookie
+    let filter = portal
+        .requests()
+        .into_iter()
+        .filter(|request| request.endpoint == Endpoint::ConnectionList)
+        .find_map(|request| request.payload)
+        .expect("The list wasn't requested");
+    let filter: PersonalFilter = serde_json::from_str(&filter).expect("Invalid filter");
+    assert_eq!(filter.customer_id, [50]);
+    assert_eq!(filter.page_size, 2);
+    assert_eq!(filter.order_direction, OrderDirection::Asc);
+}
+
+#[tokio::test]
+async fn search_by_meter_number_label_and_costplace() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    let connections = Connections::new(&cookie_store);
+    let ids = |found: Vec<Connection>| {
+        found
+            .into_iter()
+            .map(|connection| u32::from(connection.id))
+            .collect::<Vec<_>>()
+    };
+
+    let filter = PersonalFilter::new(&[50]).with_meter_number("E000");
+    let found = connections
+        .search_all(&filter)
+        .await
+        .expect("Failed to search");
+    assert_eq!(ids(found), [1001, 1002]);
+
+    let filter = PersonalFilter::new(&[50]).with_label(1).with_costplace(20);
+    let found = connections
+        .search_all(&filter)
+        .await
+        .expect("Failed to search");
+    assert_eq!(found[0].ean.value(), "871687120000000002");
+    assert_eq!(ids(found), [1002]);
+
+    let filter = PersonalFilter::new(&[50])
+        .with_classification(3)
+        .with_page(1, 1);
+    let found = connections
+        .search_all(&filter)
+        .await
+        .expect("Failed to search");
+    assert_eq!(ids(found), [1001, 1003]);
+}
+
+#[tokio::test]
+async fn unknown_ean_has_no_id() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+
+    let ean = Ean::from("871687120000000009".to_owned());
+    assert!(Id::from_ean(&cookie_store, &ean).await.is_err());
+}
+
+#[tokio::test]
+async fn aansluitinglijst_contains_the_connections() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+
+    let (_, data) = Report::Aansluitinglijst
+        .download_latest_version(&cookie_store)
+        .await
+        .expect("Failed to download");
+    Report::Aansluitinglijst
+        .validate(&data)
+        .expect("Invalid report");
+    let connections =
+        ean::read_connections(std::io::Cursor::new(data)).expect("Failed to read the connections");
+    assert_eq!(connections.len(), 3);
+    assert_eq!(connections[2].ean.value(), "871687120000000003");
+    assert_eq!(connections[2].status.as_deref(), Some("Inactief"));
+}
+
+#[tokio::test]
+async fn polling_waits_while_the_report_is_generated() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    portal.fail(Endpoint::Export, Fault::StillGenerating);
+    portal.fail(Endpoint::Export, Fault::StillGenerating);
+    portal.fail(Endpoint::Download, Fault::StillGenerating);
+    portal.fail(Endpoint::Download, Fault::Empty);
+
+    let (_, data) = Report::Co2
+        .download_latest_version_polling(&cookie_store, &polling(), |_| {})
+        .await
+        .expect("Failed to download");
+    assert_eq!(data, mock::fixture(&Report::Co2));
+}
+
+#[tokio::test]
+async fn timeout_is_an_error_instead_of_a_new_request() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    portal.fail(Endpoint::Export, Fault::Delay(Duration::from_secs(1)));
+
+    let error = Report::Co2
+        .download_latest_version_polling(&cookie_store, &polling(), |_| {})
+        .await
+        .expect_err("The request should time out");
+    assert!(matches!(error, report::Error::TimedOut(_)));
+    let exports = portal
+        .requests()
+        .into_iter()
+        .filter(|request| request.endpoint == Endpoint::Export)
+        .count();
+    assert_eq!(exports, 1);
+}
+
+#[tokio::test]
+async fn missing_file_is_an_error_instead_of_still_generating() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    portal.fail(Endpoint::Download, Fault::Status(StatusCode::NOT_FOUND));
+
+    let started = std::time::Instant::now();
+    let error = Report::Co2
+        .download_latest_version_polling(&cookie_store, &polling(), |_| {})
+        .await
+        .expect_err("The download should fail");
+    assert!(matches!(
+        error,
+        report::Error::NotOk(reqwest::StatusCode::NOT_FOUND, _)
+    ));
+    assert!(started.elapsed() < polling().deadline);
+}
+
+#[tokio::test]
+async fn failed_generation_is_reported() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    portal.fail(
+        Endpoint::Export,
+        Fault::GenerationFailed("Geen data".to_owned()),
+    );
+
+    let error = Report::Verbruik
+        .latest_version(&cookie_store)
+        .await
+        .expect_err("The generation should fail");
+    assert!(matches!(error, report::Error::GenerationFailed(message) if message == "Geen data"));
+}
+
+#[tokio::test]
+async fn server_errors_are_reported() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    portal.fail(
+        Endpoint::Export,
+        Fault::Status(StatusCode::INTERNAL_SERVER_ERROR),
+    );
+
+    let error = Report::Gebouwen
+        .latest_version(&cookie_store)
+        .await
+        .expect_err("The request should fail");
+    assert!(matches!(
+        error,
+        report::Error::NotOk(reqwest::StatusCode::INTERNAL_SERVER_ERROR, _)
+    ));
+}
+
+#[tokio::test]
+async fn html_instead_of_a_report_is_invalid() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    portal.fail(Endpoint::Download, Fault::Html("Fout".to_owned()));
+
+    let (_, data) = Report::Aansluitinglijst
+        .download_latest_version(&cookie_store)
+        .await
+        .expect("Failed to download");
+    let error = Report::Aansluitinglijst
+        .validate(&data)
+        .expect_err("Html isn't a report");
+    assert!(matches!(
+        error,
+        report::Error::InvalidContent(InvalidContent::Html(Some(title))) if title == "Fout"
+    ));
+}
+
+#[test]
+fn every_report_has_a_layout_that_matches_its_fixture() {
+    let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+    let reports = Report::GLOBAL
+        .into_iter()
+        .chain([Report::EnergieVerbruikPerUur(Id::from(1001), date, date)]);
+    for report in reports {
+        report
+            .validate(mock::fixture(&report))
+            .unwrap_or_else(|e| panic!("{} doesn't match its layout: {e}", report.name()));
+    }
+}
+
+#[test]
+fn mismatched_header_is_invalid() {
+    // The columns of reports that aren't known from real exports aren't checked
+    Report::Verbruik
+        .validate(mock::fixture(&Report::Co2))
+        .expect("Any workbook is a verbruik report");
+    let error = Report::Aansluitinglijst
+        .validate(mock::fixture(&Report::Co2))
+        .expect_err("The co2 report isn't an aansluitinglijst");
+    assert!(matches!(
+        error,
+        report::Error::InvalidContent(InvalidContent::MissingWorksheet { .. })
+    ));
+
+    let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+    let error = Report::EnergieVerbruikPerUur(Id::from(1001), date, date)
+        .validate(mock::fixture(&Report::Verbruik))
+        .expect_err("The verbruik report doesn't contain meter columns");
+    assert!(matches!(
+        error,
+        report::Error::InvalidContent(InvalidContent::MissingMeterColumns(worksheet)) if worksheet == "Rapport"
+    ));
+
+    let error = Report::Aansluitinglijst
+        .validate(mock::fixture(&Report::Metadata))
+        .expect_err("The metadata isn't an aansluitinglijst");
+    assert!(matches!(
+        error,
+        report::Error::InvalidContent(InvalidContent::MissingWorksheet {
+            expected: "Lijst_Export",
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn per_meter_report_sends_the_meter_and_dates() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+
+    let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+    Report::EnergieVerbruikPerUur(Id::from(1001), date, date)
+        .download_latest_version(&cookie_store)
+        .await
+        .expect("Failed to download");
+    let payload = portal
+        .requests()
+        .into_iter()
+        .find(|request| request.endpoint == Endpoint::Export)
+        .and_then(|request| request.payload)
+        .expect("The report wasn't requested");
+    let payload: serde_json::Value = serde_json::from_str(&payload).expect("Invalid payload");
+    assert_eq!(payload["meterId"], serde_json::json!([1001]));
+    assert_eq!(payload["startDate"], "2024-01-31 00:00");
+    assert_eq!(payload["endDate"], "2024-01-31 23:55");
+}
+
+#[tokio::test]
+async fn per_meter_report_is_streamed_to_a_temporary_file() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let (_server, cookie_store) = login(&portal).await;
+    portal.fail(Endpoint::Download, Fault::Empty);
+    let directory =
+        std::env::temp_dir().join(format!("rapportage-streamed-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).expect("Failed to create the directory");
+
+    let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+    let report = Report::EnergieVerbruikPerUur(Id::from(1001), date, date);
+    let (_, file, size) = report
+        .download_latest_version_polling_to(&cookie_store, &polling(), &directory, |_| {})
+        .await
+        .expect("Failed to download");
+    assert_eq!(size, mock::fixture(&report).len() as u64);
+    let path = file.path().to_owned();
+    assert_eq!(
+        std::fs::read(&path).expect("The report wasn't written"),
+        mock::fixture(&report)
+    );
+
+    // The temporary file is removed once it's no longer used
+    drop(file);
+    assert!(!path.exists());
+    std::fs::remove_dir(&directory).expect("Temporary files were left behind");
+}
+
+#[test]
+fn temporary_paths_are_unique() {
+    let path = std::path::Path::new("reports").join("report.xlsx");
+    let first = report::temporary_path(&path);
+    let second = report::temporary_path(&path);
+    assert_ne!(first, second);
+    for temporary_path in [first, second] {
+        assert_eq!(temporary_path.parent(), path.parent());
+        let name = temporary_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .expect("Invalid file name");
+        assert!(name.starts_with(".report.xlsx."), "{name}");
+        assert!(name.ends_with(".part"), "{name}");
+    }
+}
@@ -0,0 +1,219 @@
+use std::path::{Path, PathBuf};
+
+use rapportage_downloader::{
+    ean::Ean,
+    fixtures::{Recorder, Replay},
+    id::Id,
+    login::CookieStore,
+    mock::MockPortal,
+    report::Report,
+};
+
+const MAIL: &str = "test@example.com";
+const PASSWORD: &str = "test";
+
+/// The fixtures that were recorded from the mock portal, not from the real portal.
+/// Re-record them with `cargo run --example mock_portal` and
+/// `--portal-url http://127.0.0.1:<port> --record tests/fixtures/portal`
+fn recorded() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/portal")
+}
+
+/// Returns an empty directory for the fixtures of a test
+fn directory(test: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("rapportage-{test}-{}", std::process::id()));
+    std::fs::remove_dir_all(&directory).ok();
+    directory
+}
+
+#[tokio::test]
+async fn recorded_fixtures_resolve_ids_and_reports() {
+    let replay = Replay::load(&recorded()).expect("Failed to load the fixtures");
+    assert!(!replay.is_empty());
+    let server = replay.start().expect("Failed to start the replay");
+    let cookie_store = CookieStore::login_at(&server.url(), MAIL.to_owned(), PASSWORD.to_owned())
+        .await
+        .expect("Failed to log in");
+
+    // In the order of the recording, the search for an id sets the filter of the connection list
+    let (_, data) = Report::Aansluitinglijst
+        .download_latest_version(&cookie_store)
+        .await
+        .expect("Failed to download");
+    Report::Aansluitinglijst
+        .validate(&data)
+        .expect("Invalid report");
+
+    let ean = Ean::from("871687120000000001".to_owned());
+    let id = Id::from_ean(&cookie_store, &ean)
+        .await
+        .expect("No id found");
+    assert_eq!(u32::from(id), 1001);
+
+    let start = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).expect("Invalid date");
+    let end = chrono::NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+    let file_name = Report::EnergieVerbruikPerUur(id, start, end)
+        .latest_version(&cookie_store)
+        .await
+        .expect("No version found");
+    assert!(file_name.ends_with(".xlsx"));
+}
+
+#[tokio::test]
+async fn requests_only_match_the_recorded_payload() {
+    let replay = Replay::load(&recorded()).expect("Failed to load the fixtures");
+    let server = replay.start().expect("Failed to start the replay");
+    let cookie_store = CookieStore::login_at(&server.url(), MAIL.to_owned(), PASSWORD.to_owned())
+        .await
+        .expect("Failed to log in");
+    let ean = Ean::from("871687120000000001".to_owned());
+    let id = Id::from_ean(&cookie_store, &ean)
+        .await
+        .expect("No id found");
+
+    // Another period than the recorded one isn't answered with the recorded report
+    let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).expect("Invalid date");
+    assert!(Report::EnergieVerbruikPerUur(id, date, date)
+        .latest_version(&cookie_store)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn years_are_replayed_relative_to_the_recording() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let server = portal.start().expect("Failed to start the mock portal");
+    let directory = directory("years");
+    let recorder = Recorder::new(directory.clone());
+    let cookie_store = CookieStore::login_with(
+        &server.url(),
+        MAIL.to_owned(),
+        PASSWORD.to_owned(),
+        Some(recorder.clone()),
+    )
+    .await
+    .expect("Failed to log in");
+    Report::Aansluitinglijst
+        .download_latest_version(&cookie_store)
+        .await
+        .expect("Failed to download");
+    recorder.save().expect("Failed to save");
+
+    // Moves the recording of the export a year back, with or without the year of the recording
+    let shift = |recording_year: bool| {
+        for entry in std::fs::read_dir(&directory).expect("Failed to read the fixtures") {
+            let path = entry.expect("Failed to read a fixture").path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json")
+                || path.to_string_lossy().contains(".body.")
+            {
+                continue;
+            }
+            let content = std::fs::read(&path).expect("Failed to read a fixture");
+            let mut exchange: serde_json::Value =
+                serde_json::from_slice(&content).expect("Invalid fixture");
+            let Some(year) = exchange["payload"]
+                .as_str()
+                .and_then(|payload| payload.parse::<i32>().ok())
+            else {
+                continue;
+            };
+            exchange["payload"] = (year - 1).to_string().into();
+            if recording_year {
+                exchange["year"] = exchange["year"].as_i64().map(|year| year - 1).into();
+            }
+            std::fs::write(&path, exchange.to_string()).expect("Failed to write a fixture");
+        }
+    };
+    let download = || async {
+        let replay = Replay::load(&directory).expect("Failed to load the fixtures");
+        let server = replay.start().expect("Failed to start the replay");
+        let cookie_store =
+            CookieStore::login_at(&server.url(), MAIL.to_owned(), PASSWORD.to_owned())
+                .await
+                .expect("Failed to log in");
+        Report::Aansluitinglijst
+            .download_latest_version(&cookie_store)
+            .await
+    };
+
+    shift(true);
+    assert!(download().await.is_ok());
+    shift(false);
+    assert!(download().await.is_err());
+    std::fs::remove_dir_all(directory).ok();
+}
+
+#[tokio::test]
+async fn recording_leaves_out_credentials() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let server = portal.start().expect("Failed to start the mock portal");
+    let directory = directory("credentials");
+    let recorder = Recorder::new(directory.clone());
+    let cookie_store = CookieStore::login_with(
+        &server.url(),
+        MAIL.to_owned(),
+        PASSWORD.to_owned(),
+        Some(recorder.clone()),
+    )
+    .await
+    .expect("Failed to log in");
+    let ean = Ean::from("871687120000000002".to_owned());
+    let id = Id::from_ean(&cookie_store, &ean)
+        .await
+        .expect("No id found");
+    assert_eq!(recorder.save().expect("Failed to save"), 3);
+
+    for entry in std::fs::read_dir(&directory).expect("Failed to read the fixtures") {
+        let content = std::fs::read(entry.expect("Failed to read a fixture").path())
+            .expect("Failed to read a fixture");
+        let content = String::from_utf8_lossy(&content);
+        for secret in [MAIL, PASSWORD, "mock-verification-token", "session-"] {
+            assert!(
+                !content.contains(secret),
+                "{secret} is part of the fixtures"
+            );
+        }
+    }
+
+    // The same requests are answered the same way
+    let replay = Replay::load(&directory).expect("Failed to load the fixtures");
+    let server = replay.start().expect("Failed to start the replay");
+    let cookie_store = CookieStore::login_at(&server.url(), MAIL.to_owned(), PASSWORD.to_owned())
+        .await
+        .expect("Failed to log in");
+    assert_eq!(Id::from_ean(&cookie_store, &ean).await.ok(), Some(id));
+    std::fs::remove_dir_all(directory).ok();
+}
+
+#[tokio::test]
+async fn redirects_are_replayed() {
+    let portal = MockPortal::new(MAIL, PASSWORD);
+    let server = portal.start().expect("Failed to start the mock portal");
+    let directory = directory("redirects");
+    let recorder = Recorder::new(directory.clone());
+    let cookie_store = CookieStore::login_with(
+        &server.url(),
+        MAIL.to_owned(),
+        PASSWORD.to_owned(),
+        Some(recorder.clone()),
+    )
+    .await
+    .expect("Failed to log in");
+    portal.expire_sessions();
+    assert!(!cookie_store
+        .is_logged_in()
+        .await
+        .expect("Portal unreachable"));
+    recorder.save().expect("Failed to save");
+
+    let replay = Replay::load(&directory).expect("Failed to load the fixtures");
+    let server = replay.start().expect("Failed to start the replay");
+    let cookie_store = CookieStore::login_at(&server.url(), MAIL.to_owned(), PASSWORD.to_owned())
+        .await
+        .expect("Failed to log in");
+    assert!(!cookie_store
+        .is_logged_in()
+        .await
+        .expect("Replay unreachable"));
+    std::fs::remove_dir_all(directory).ok();
+}
@@ -0,0 +1,53 @@
+use rapportage_downloader::{
+    id::Id,
+    report::Report,
+    request::{self, ReportRequest},
+};
+
+#[test]
+fn report_requests_survive_encoding() {
+    let date = chrono::NaiveDate::from_ymd_opt(2024, 1, 31).expect("Invalid date");
+    let reports = Report::GLOBAL
+        .into_iter()
+        .chain([Report::EnergieVerbruikPerUur(Id::from(1001), date, date)]);
+    for report in reports {
+        let request = report.request(&[50, 51]);
+        let header = request.encode().expect("Failed to encode");
+        let decoded = ReportRequest::decode(&header).expect("Failed to decode");
+        assert_eq!(decoded, request, "{}", report.name());
+    }
+}
+
+#[test]
+fn recorded_payloads_decode() {
+    let decoded = ReportRequest::decode(
+        "eyJJbnRlcm1lZGlhdGVNZXRlcklkIjowLCJXZWF0aGVyRGF0YVR5cGUiOjAsImNoYXJ0VHlwZSI6ImNvbHVtbiIsImVuZERhdGUiOiIyMDI0LTAxLTMxIDIzOjU1IiwiZXhjZWwiOnRydWUsImludGVydmFsIjoidXVyIiwibWV0ZXJJZCI6WzEwMDFdLCJwcm9kdWN0SWQiOjAsInN0YXJ0RGF0ZSI6IjIwMjQtMDEtMDEgMDA6MDAifQ==",
+    )
+        .expect("Failed to decode");
+    let ReportRequest::Analyze(analyze) = decoded else {
+        panic!("Expected an analyze request, found {decoded:?}");
+    };
+    assert_eq!(analyze.meter_id, [1001]);
+    assert_eq!(analyze.start_date, "2024-01-01 00:00");
+
+    assert!(matches!(
+        ReportRequest::decode("ZmFsc2U="),
+        Ok(ReportRequest::Flag(false))
+    ));
+    assert!(matches!(
+        ReportRequest::decode("MjAyNg=="),
+        Ok(ReportRequest::Year(2026))
+    ));
+}
+
+#[test]
+fn cookies_are_url_encoded_json() {
+    let json = r#"{"customerId":[50,51],"eanSearch":"871687120000000001"}"#;
+    let cookie = request::encode_cookie(json);
+    assert_eq!(
+        cookie,
+        "%7B%22customerId%22%3A%5B50%2C51%5D%2C%22eanSearch%22%3A%22871687120000000001%22%7D"
+    );
+    let decoded = request::decode_cookie(&cookie).expect("Failed to decode");
+    assert_eq!(decoded["eanSearch"], "871687120000000001");
+}
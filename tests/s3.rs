@@ -0,0 +1,285 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Router,
+};
+use base64::Engine as _;
+use rapportage_downloader::{
+    mock::Server,
+    report::{Report, TemporaryFile},
+    sink::{self, Content, Document, S3Options, S3Sink, Saved, Sink},
+};
+use sha2::{Digest as _, Sha256};
+
+const MIB: usize = 1024 * 1024;
+
+/// A request that was received by the stub
+#[derive(Debug, Clone)]
+struct Received {
+    method: Method,
+    uri: String,
+    body: Bytes,
+    headers: HeaderMap,
+}
+
+/// Object storage that accepts every upload, except for the failures that are set up
+#[derive(Debug, Default)]
+struct Stub {
+    received: Mutex<Vec<Received>>,
+
+    /// The part that's answered with an internal server error
+    failing_part: Option<u32>,
+
+    /// Complete the multipart upload with an error in the body of a successful response
+    failing_complete: bool,
+}
+
+impl Stub {
+    fn start(self) -> (Server, Arc<Self>) {
+        let stub = Arc::new(self);
+        // Parts are larger than the default limit of the body
+        let router = Router::new()
+            .fallback(answer)
+            .layer(DefaultBodyLimit::disable())
+            .with_state(stub.clone());
+        (
+            Server::start(router).expect("Failed to start the stub"),
+            stub,
+        )
+    }
+
+    fn received(&self) -> Vec<Received> {
+        self.received.lock().expect("Poisoned").clone()
+    }
+}
+
+async fn answer(
+    State(stub): State<Arc<Stub>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    stub.received.lock().expect("Poisoned").push(Received {
+        method: method.clone(),
+        uri: uri.to_string(),
+        body: body.clone(),
+        headers,
+    });
+    let query = uri.query().unwrap_or_default();
+    match method {
+        Method::POST if query == "uploads=" => {
+            "<InitiateMultipartUploadResult><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>"
+                .into_response()
+        }
+        Method::PUT if query.starts_with("partNumber=") => {
+            let part = query
+                .trim_start_matches("partNumber=")
+                .split('&')
+                .next()
+                .and_then(|part| part.parse::<u32>().ok());
+            if part == stub.failing_part {
+                return (StatusCode::INTERNAL_SERVER_ERROR, "<Error><Code>InternalError</Code></Error>")
+                    .into_response();
+            }
+            ([("etag", format!("\"etag-{}\"", part.unwrap_or_default()))], "").into_response()
+        }
+        Method::POST if stub.failing_complete => {
+            "<Error><Code>InternalError</Code><Message>We encountered an internal error.</Message></Error>"
+                .into_response()
+        }
+        Method::POST => "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>".into_response(),
+        Method::DELETE => StatusCode::NO_CONTENT.into_response(),
+        _ => StatusCode::OK.into_response(),
+    }
+}
+
+fn sink(server: &Server) -> S3Sink {
+    S3Sink {
+        client: reqwest::Client::new(),
+        bucket: "rapporten".to_owned(),
+        prefix: "db-energie".to_owned(),
+        format: sink::Options::default().format,
+        template: None,
+        options: S3Options {
+            endpoint: server.url(),
+            region: "eu-west-1".to_owned(),
+            access_key: "access".to_owned(),
+            secret_key: "secret".to_owned(),
+            path_style: true,
+            part_size: 5 * MIB,
+            ..S3Options::default()
+        },
+    }
+}
+
+fn document(data: Content) -> Document {
+    Document {
+        report: Report::Co2,
+        ean: None,
+        id: None,
+        file_name: "Co2.xlsx".to_owned(),
+        data,
+        downloaded_at: chrono::Utc::now(),
+    }
+}
+
+/// Returns data of the size that differs per mebibyte, so parts in the wrong order are noticed
+fn data(size: usize) -> Vec<u8> {
+    (0..size).map(|index| (index / MIB) as u8).collect()
+}
+
+fn sha256_base64(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(Sha256::digest(data))
+}
+
+#[tokio::test]
+async fn small_files_are_put_in_one_request() {
+    let (server, stub) = Stub::default().start();
+    let data = data(1024);
+    let saved = sink(&server)
+        .save(&document(data.clone().into()))
+        .await
+        .expect("Failed to upload");
+    assert_eq!(saved, Saved::Written);
+
+    let received = stub.received();
+    assert_eq!(received.len(), 1);
+    let put = &received[0];
+    assert_eq!(put.method, Method::PUT);
+    assert_eq!(put.uri, "/rapporten/db-energie/Co2.xlsx");
+    assert_eq!(put.body, data);
+    assert_eq!(
+        put.headers["x-amz-checksum-sha256"],
+        sha256_base64(&data).as_str()
+    );
+    let authorization = put.headers["authorization"]
+        .to_str()
+        .expect("Invalid authorization");
+    assert!(
+        authorization.starts_with("AWS4-HMAC-SHA256 Credential=access/"),
+        "{authorization}"
+    );
+    assert!(authorization.contains("/eu-west-1/s3/aws4_request"));
+}
+
+#[tokio::test]
+async fn large_files_are_uploaded_in_parts() {
+    let (server, stub) = Stub::default().start();
+
+    // The file is read one part at a time
+    let data = data(11 * MIB);
+    let file = TemporaryFile::new(&std::env::temp_dir().join("rapportage-s3.xlsx"));
+    std::fs::write(file.path(), &data).expect("Failed to write the file");
+    sink(&server)
+        .save(&document(Content::file(file, data.len() as u64)))
+        .await
+        .expect("Failed to upload");
+
+    let received = stub.received();
+    let summary = received
+        .iter()
+        .map(|request| {
+            (
+                request.method.clone(),
+                request.uri.clone(),
+                request.body.len(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let key = "/rapporten/db-energie/Co2.xlsx";
+    assert_eq!(
+        summary,
+        [
+            (Method::POST, format!("{key}?uploads="), 0),
+            (
+                Method::PUT,
+                format!("{key}?partNumber=1&uploadId=upload-1"),
+                5 * MIB
+            ),
+            (
+                Method::PUT,
+                format!("{key}?partNumber=2&uploadId=upload-1"),
+                5 * MIB
+            ),
+            (
+                Method::PUT,
+                format!("{key}?partNumber=3&uploadId=upload-1"),
+                MIB
+            ),
+            (
+                Method::POST,
+                format!("{key}?uploadId=upload-1"),
+                received[4].body.len()
+            ),
+        ]
+    );
+
+    // The parts contain the file in order, each with its own checksum
+    let parts = received[1..4]
+        .iter()
+        .flat_map(|request| request.body.to_vec())
+        .collect::<Vec<_>>();
+    assert_eq!(parts, data);
+    for part in &received[1..4] {
+        assert_eq!(
+            part.headers["x-amz-checksum-sha256"],
+            sha256_base64(&part.body).as_str()
+        );
+    }
+    let complete = String::from_utf8_lossy(&received[4].body);
+    assert_eq!(complete.matches("<Part>").count(), 3);
+    assert!(complete.contains("<PartNumber>3</PartNumber><ETag>\"etag-3\"</ETag>"));
+}
+
+#[tokio::test]
+async fn failed_part_aborts_the_upload() {
+    let (server, stub) = Stub {
+        failing_part: Some(2),
+        ..Stub::default()
+    }
+    .start();
+    let error = sink(&server)
+        .save(&document(data(11 * MIB).into()))
+        .await
+        .expect_err("The second part failed");
+    assert!(
+        matches!(&error, sink::Error::S3(StatusCode::INTERNAL_SERVER_ERROR, body) if body.contains("InternalError")),
+        "{error}"
+    );
+
+    // The uploaded parts are removed and the upload isn't completed
+    let methods = stub
+        .received()
+        .into_iter()
+        .map(|request| request.method)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        methods,
+        [Method::POST, Method::PUT, Method::PUT, Method::DELETE]
+    );
+    assert!(stub.received()[3].uri.ends_with("?uploadId=upload-1"));
+}
+
+#[tokio::test]
+async fn error_in_the_body_of_completing_is_an_error() {
+    let (server, stub) = Stub {
+        failing_complete: true,
+        ..Stub::default()
+    }
+    .start();
+    let error = sink(&server)
+        .save(&document(data(6 * MIB).into()))
+        .await
+        .expect_err("Completing the upload failed");
+    assert!(
+        matches!(&error, sink::Error::S3(_, code) if code == "InternalError"),
+        "{error}"
+    );
+    let last = stub.received().pop().expect("No requests");
+    assert_eq!(last.method, Method::DELETE);
+}